@@ -113,7 +113,7 @@
     // Ping interval - only when connected
     state.intervals.ping = setInterval(() => {
       if (state.ws && state.ws.readyState === 1) {
-        OWP.actions.send('ping', { client_ts: utils.nowMs() });
+        OWP.actions.sendTimeSync();
       }
     }, PING_MS);
 
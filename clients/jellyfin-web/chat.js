@@ -60,7 +60,10 @@
      */
     receive(msg) {
       console.log('[OpenWatchParty] Chat.receive called with:', msg);
+      // Already seen (e.g. replayed in chat_history after a reconnect)
+      if (this.hasMessage(msg.payload?.id)) return;
       const message = {
+        id: msg.payload?.id,
         clientId: msg.client,
         username: msg.payload?.username || 'Anonymous',
         text: msg.payload?.text || '',
@@ -89,6 +92,34 @@
       this.renderMessage(message);
     },
 
+    /**
+     * Merge the server's recent history (sent on join/resume) without
+     * duplicating messages we already have.
+     */
+    loadHistory(entries) {
+      if (!Array.isArray(entries)) return;
+      entries.forEach(entry => {
+        if (this.hasMessage(entry.id)) return;
+        this.messages.push({
+          id: entry.id,
+          clientId: entry.client_id,
+          username: entry.username || 'Anonymous',
+          text: entry.text || '',
+          timestamp: entry.ts || Date.now(),
+          isOwn: entry.client_id === OWP.state.clientId
+        });
+      });
+      this.messages.sort((a, b) => (a.id || 0) - (b.id || 0));
+      if (this.messages.length > MAX_MESSAGES) {
+        this.messages.splice(0, this.messages.length - MAX_MESSAGES);
+      }
+      this.renderAllMessages();
+    },
+
+    hasMessage(id) {
+      return typeof id === 'number' && this.messages.some(m => m.id === id);
+    },
+
     /**
      * Check if chat panel is visible
      */
@@ -146,7 +146,7 @@
 
     const onEvent = (action) => {
       const actions = OWP.actions;
-      if (!state.isHost || !actions || !actions.send || !utils.shouldSend()) return;
+      if (!utils.canControl() || !actions || !actions.send || !utils.shouldSend()) return;
       // Don't send while syncing to server command (prevents feedback loop)
       if (state.isSyncing) return;
       // Don't block play/pause on video ready - these are critical user actions
@@ -177,7 +177,7 @@
       // For play/pause/seek, send immediate state_update (bypass normal throttle/ready checks)
       // This ensures CLIENT gets the play_state change ASAP
       // For seek: CLIENT needs to know if HOST is playing so it can resume after seeking
-      if (action === 'play' || action === 'pause' || action === 'seek') {
+      if (state.isHost && (action === 'play' || action === 'pause' || action === 'seek')) {
         actions.send('state_update', { position: video.currentTime, play_state: video.paused ? 'paused' : 'playing' });
         state.lastStateSentAt = utils.nowMs();
       }
@@ -78,8 +78,12 @@
     ws: null,
     roomId: '',
     clientId: '',
+    resumeToken: '',
     name: '',
     isHost: false,
+    controlMode: 'host_only',  // host_only | everyone | allow_list
+    controllers: [],
+    queue: [],                 // Upcoming media IDs in the room's watch queue
     followHost: true,
     suppressUntil: 0,
     rooms: [],
@@ -94,6 +98,7 @@
     logBufferMax: 100,
     serverOffsetMs: 0,
     timeSyncSamples: [],         // Circular buffer of { rtt, offset, ts } for hybrid time sync
+    lastTimeSync: null,          // { client_send_ts, client_recv_ts } of the last completed exchange
     lastSeekSentAt: 0,
     lastStateSentAt: 0,
     lastSentPosition: 0,
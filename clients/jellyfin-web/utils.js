@@ -7,6 +7,10 @@
 
   const nowMs = () => Date.now();
   const shouldSend = () => nowMs() > state.suppressUntil;
+  // Whether this client may send playback commands under the room's control mode
+  const canControl = () => state.isHost
+    || state.controlMode === 'everyone'
+    || (state.controlMode === 'allow_list' && state.controllers.includes(state.clientId));
   const suppress = (ms = SUPPRESS_MS) => { state.suppressUntil = nowMs() + ms; };
   const getVideo = () => document.querySelector('video');
   const isVideoReady = () => {
@@ -228,6 +232,7 @@
   OWP.utils = {
     nowMs,
     shouldSend,
+    canControl,
     suppress,
     getVideo,
     isVideoReady,
@@ -19,6 +19,46 @@
     state.ws.send(JSON.stringify(message));
   };
 
+  // NTP-style sync: report when the previous reply arrived so the server can
+  // complete that exchange and track our RTT/offset.
+  const sendTimeSync = () => {
+    const payload = { client_send_ts: utils.nowMs() };
+    if (state.lastTimeSync) {
+      payload.prev_client_send_ts = state.lastTimeSync.client_send_ts;
+      payload.prev_client_recv_ts = state.lastTimeSync.client_recv_ts;
+    }
+    send('time_sync', payload);
+  };
+
+  // Feed one clock sample into the lowest-RTT window and EMA offset
+  const addClockSample = (rtt, sampleOffset, now) => {
+    const latEl = document.querySelector('.owp-latency');
+    if (latEl) latEl.textContent = `${Math.round(rtt)} ms`;
+
+    // Add sample to circular buffer
+    state.timeSyncSamples.push({ rtt, offset: sampleOffset, ts: now });
+    if (state.timeSyncSamples.length > TIME_SYNC_MAX_SAMPLES) {
+      state.timeSyncSamples.shift();
+    }
+
+    // Select sample with lowest RTT (most accurate)
+    const bestSample = state.timeSyncSamples.reduce((best, s) =>
+      s.rtt < best.rtt ? s : best
+    );
+
+    // Apply EMA on best sample offset
+    const prevOffset = state.serverOffsetMs;
+    state.serverOffsetMs = state.hasTimeSync
+      ? state.serverOffsetMs * (1 - TIME_SYNC_EMA_ALPHA) + bestSample.offset * TIME_SYNC_EMA_ALPHA
+      : bestSample.offset;
+    state.hasTimeSync = true;
+
+    // Log clock sync periodically (every ~10 pings to reduce noise)
+    if (Math.random() < 0.1) {
+      utils.log('CLOCK', { rtt, best_rtt: bestSample.rtt, server_offset: state.serverOffsetMs, delta: state.serverOffsetMs - prevOffset, samples: state.timeSyncSamples.length });
+    }
+  };
+
   const createRoom = () => {
     const v = utils.getVideo();
     const mediaId = utils.getCurrentItemId();
@@ -169,20 +209,25 @@
 
     if (refreshInMs > 0) {
       console.log('[OpenWatchParty] Token refresh scheduled in', Math.round(refreshInMs / 1000), 's');
-      state.tokenRefreshTimer = setTimeout(async () => {
-        console.log('[OpenWatchParty] Refreshing auth token...');
-        state.authToken = null;  // Clear old token to force refresh
-        const newToken = await fetchAuthToken();
-        if (newToken && state.ws && state.ws.readyState === WebSocket.OPEN) {
-          // Re-authenticate with new token
-          state.ws.send(JSON.stringify({
-            type: 'auth',
-            payload: { token: newToken, user_name: state.userName, user_id: state.userId },
-            ts: utils.nowMs()
-          }));
-          console.log('[OpenWatchParty] Token refreshed and re-authenticated');
-        }
-      }, refreshInMs);
+      state.tokenRefreshTimer = setTimeout(refreshToken, refreshInMs);
+    }
+  };
+
+  /**
+   * Fetch a fresh token and hand it to the server with `reauth`
+   * (keeps the room, unlike a new `auth`)
+   */
+  const refreshToken = async () => {
+    console.log('[OpenWatchParty] Refreshing auth token...');
+    state.authToken = null;  // Clear old token to force refresh
+    const newToken = await fetchAuthToken();
+    if (newToken && state.ws && state.ws.readyState === WebSocket.OPEN) {
+      state.ws.send(JSON.stringify({
+        type: 'reauth',
+        payload: { token: newToken },
+        ts: utils.nowMs()
+      }));
+      console.log('[OpenWatchParty] Token refreshed and re-authenticated');
     }
   };
 
@@ -210,11 +255,10 @@
       state.autoReconnect = wasAutoReconnect;
     }
 
-    // Reuse existing token if we have one (avoid rate limiting on reconnects)
-    let token = state.authToken;
-    if (!token) {
-      token = await fetchAuthToken();
-    }
+    // Tokens are single-use: the server rejects one already presented on an
+    // earlier connection, so every (re)connect needs a fresh one
+    state.authToken = null;
+    const token = await fetchAuthToken();
 
     // Connect without token in URL (security: avoid token in logs/history)
     const wsUrl = DEFAULT_WS_URL;
@@ -248,8 +292,9 @@
       if (Object.keys(authPayload).length > 0) {
         state.ws.send(JSON.stringify({ type: 'auth', payload: authPayload, ts: utils.nowMs() }));
       }
-      // Send immediate ping for faster clock sync (fixes 5.6)
-      state.ws.send(JSON.stringify({ type: 'ping', payload: { client_ts: utils.nowMs() }, ts: utils.nowMs() }));
+      // Send immediate time sync for faster clock sync (fixes 5.6)
+      state.lastTimeSync = null;
+      sendTimeSync();
       ui.render();
     };
     state.ws.onerror = (err) => {
@@ -260,6 +305,7 @@
       console.log('[OpenWatchParty] WebSocket closed:', e.code, e.reason);
       state.isConnecting = false;
       state.timeSyncSamples = [];  // Reset time sync samples on disconnect
+      state.lastTimeSync = null;
       ui.render();
       // Only auto-reconnect if flag is set and not already connecting
       if (state.autoReconnect && !state.isConnecting) {
@@ -298,11 +344,30 @@
 
       case 'client_hello':
         if (msg.payload && msg.payload.client_id) {
+          // Try to reclaim the previous session (room + host role) after a reconnect
+          const previousToken = state.resumeToken;
           state.clientId = msg.payload.client_id;
+          state.resumeToken = msg.payload.resume_token || '';
+          if (previousToken && state.ws && state.ws.readyState === WebSocket.OPEN) {
+            state.ws.send(JSON.stringify({ type: 'resume', payload: { token: previousToken }, ts: utils.nowMs() }));
+          }
           ui.render();
         }
         break;
 
+      case 'token_expiring':
+        // Server-side reminder, in case the scheduled refresh did not run (e.g. a suspended tab)
+        refreshToken();
+        break;
+
+      case 'session_resumed':
+        if (msg.payload && msg.payload.client_id) {
+          state.clientId = msg.payload.client_id;
+          state.resumeToken = msg.payload.resume_token || '';
+          console.log('[OpenWatchParty] Session resumed as', state.clientId);
+        }
+        break;
+
       case 'room_state':
         state.inRoom = true;
         state.roomId = msg.room;
@@ -312,6 +377,9 @@
           state.clientId = msg.client;
         }
         state.isHost = (msg.payload.host_id === state.clientId);
+        state.controlMode = msg.payload.control_mode || 'host_only';
+        state.controllers = msg.payload.controllers || [];
+        state.queue = msg.payload.queue || [];
         if (!state.hasTimeSync && typeof msg.server_ts === 'number') {
           state.serverOffsetMs = msg.server_ts - utils.nowMs();
           state.hasTimeSync = true;
@@ -397,6 +465,79 @@
         }
         break;
 
+      case 'host_changed':
+        if (msg.payload?.host_id) {
+          const wasHost = state.isHost;
+          state.isHost = (msg.payload.host_id === state.clientId);
+          if (state.isHost && !wasHost) {
+            ui.showToast('You are now the host');
+          } else if (!state.isHost) {
+            ui.showToast('The host changed');
+          }
+          ui.render();
+        }
+        break;
+
+      case 'control_mode_changed':
+        if (msg.payload) {
+          state.controlMode = msg.payload.control_mode || 'host_only';
+          state.controllers = msg.payload.controllers || [];
+          ui.showToast(utils.canControl() ? 'You can control playback' : 'Only the host controls playback');
+          ui.render();
+        }
+        break;
+
+      case 'queue_updated':
+        if (msg.payload) {
+          state.queue = msg.payload.queue || [];
+          ui.render();
+        }
+        break;
+
+      case 'media_changed':
+        if (msg.payload && msg.payload.media_id) {
+          state.queue = msg.payload.queue || [];
+          // Everyone loads the next item and reports ready again
+          state.readyRoomId = '';
+          if (OWP.playback && OWP.playback.ensurePlayback) {
+            OWP.playback.ensurePlayback(msg.payload.media_id);
+            if (OWP.playback.watchReady) OWP.playback.watchReady();
+          }
+          ui.showToast('Up next');
+          ui.render();
+        }
+        break;
+
+      case 'kicked':
+      case 'banned':
+        state.inRoom = false; state.roomId = '';
+        ui.showToast(msg.type === 'banned' ? 'You were banned from the room' : 'You were removed from the room');
+        ui.render();
+        break;
+
+      case 'disconnected':
+        // Removed by a server administrator: don't reconnect automatically
+        state.autoReconnect = false;
+        state.inRoom = false; state.roomId = '';
+        ui.showToast(msg.payload?.reason || 'Disconnected by the server');
+        ui.render();
+        break;
+
+      case 'announcement':
+        if (msg.payload?.text) ui.showToast(msg.payload.text);
+        break;
+
+      case 'mute_status':
+        ui.showToast(msg.payload?.muted ? 'The host muted you' : 'The host unmuted you');
+        break;
+
+      case 'participant_moderated': {
+        const who = msg.payload?.user_name || 'A participant';
+        const labels = { kick: 'was removed', ban: 'was banned', mute: 'was muted', unmute: 'was unmuted' };
+        if (labels[msg.payload?.action]) ui.showToast(`${who} ${labels[msg.payload.action]}`);
+        break;
+      }
+
       case 'room_closed':
         state.inRoom = false; state.roomId = '';
         const reason = msg.payload?.reason || 'The room was closed';
@@ -405,7 +546,9 @@
         break;
 
       case 'player_event':
-        if (state.isHost || !video) return;
+        // The server never echoes our own commands, so anything here comes from
+        // another controller (always the host in host_only mode)
+        if (!video) return;
         utils.startSyncing();
 
         if (msg.payload && typeof msg.payload.position === 'number') {
@@ -550,33 +693,23 @@
         if (msg.payload && msg.payload.client_ts) {
           const now = utils.nowMs();
           const rtt = now - msg.payload.client_ts;
-          const latEl = document.querySelector('.owp-latency');
-          if (latEl) latEl.textContent = `${rtt} ms`;
           if (typeof msg.server_ts === 'number' && rtt > 0) {
-            const sampleOffset = msg.server_ts + (rtt / 2) - now;
-
-            // Add sample to circular buffer
-            state.timeSyncSamples.push({ rtt, offset: sampleOffset, ts: now });
-            if (state.timeSyncSamples.length > TIME_SYNC_MAX_SAMPLES) {
-              state.timeSyncSamples.shift();
-            }
+            addClockSample(rtt, msg.server_ts + (rtt / 2) - now, now);
+          }
+        }
+        break;
 
-            // Select sample with lowest RTT (most accurate)
-            const bestSample = state.timeSyncSamples.reduce((best, s) =>
-              s.rtt < best.rtt ? s : best
-            );
-
-            // Apply EMA on best sample offset
-            const prevOffset = state.serverOffsetMs;
-            state.serverOffsetMs = state.hasTimeSync
-              ? state.serverOffsetMs * (1 - TIME_SYNC_EMA_ALPHA) + bestSample.offset * TIME_SYNC_EMA_ALPHA
-              : bestSample.offset;
-            state.hasTimeSync = true;
-
-            // Log clock sync periodically (every ~10 pings to reduce noise)
-            if (Math.random() < 0.1) {
-              utils.log('CLOCK', { rtt, best_rtt: bestSample.rtt, server_offset: state.serverOffsetMs, delta: state.serverOffsetMs - prevOffset, samples: state.timeSyncSamples.length });
-            }
+      case 'time_sync':
+        if (msg.payload && typeof msg.payload.client_send_ts === 'number') {
+          const t0 = msg.payload.client_send_ts;
+          const t1 = msg.payload.server_recv_ts;
+          const t2 = msg.payload.server_send_ts;
+          const t3 = utils.nowMs();
+          state.lastTimeSync = { client_send_ts: t0, client_recv_ts: t3 };
+          if (typeof t1 === 'number' && typeof t2 === 'number') {
+            // Exclude server processing time from RTT
+            const rtt = (t3 - t0) - (t2 - t1);
+            if (rtt >= 0) addClockSample(rtt, ((t1 - t0) + (t2 - t3)) / 2, t3);
           }
         }
         break;
@@ -587,11 +720,18 @@
           OWP.chat.receive(msg);
         }
         break;
+
+      case 'chat_history':
+        if (OWP.chat && msg.payload) {
+          OWP.chat.loadHistory(msg.payload.messages);
+        }
+        break;
     }
   };
 
   OWP.actions = {
     send,
+    sendTimeSync,
     createRoom,
     joinRoom,
     leaveRoom,
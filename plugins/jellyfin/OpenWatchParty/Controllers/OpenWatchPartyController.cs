@@ -208,7 +208,9 @@ public class OpenWatchPartyController : ControllerBase
             });
         }
 
-        var token = GenerateJwtToken(userId, userName, config);
+        // Jellyfin administrators may moderate and control any watch party
+        var isAdmin = User.IsInRole("Administrator");
+        var token = GenerateJwtToken(userId, userName, isAdmin, config);
         _logger.LogDebug("Generated token for user {UserName} ({UserId})", userName, userId);
 
         return Ok(new {
@@ -238,12 +240,12 @@ public class OpenWatchPartyController : ControllerBase
         return _cachedSigningCredentials;
     }
 
-    private static string GenerateJwtToken(string userId, string userName, PluginConfiguration config)
+    private static string GenerateJwtToken(string userId, string userName, bool isAdmin, PluginConfiguration config)
     {
         // P-CS02 fix: Use cached signing credentials instead of creating new ones each time
         var credentials = GetSigningCredentials(config.JwtSecret);
 
-        var claims = new[]
+        var claims = new List<Claim>
         {
             new Claim(JwtRegisteredClaimNames.Sub, userId),
             new Claim(JwtRegisteredClaimNames.Name, userName),
@@ -252,6 +254,10 @@ public class OpenWatchPartyController : ControllerBase
             new Claim(JwtRegisteredClaimNames.Iat, DateTimeOffset.UtcNow.ToUnixTimeSeconds().ToString(), ClaimValueTypes.Integer64),
             new Claim(JwtRegisteredClaimNames.Jti, Guid.NewGuid().ToString()),
         };
+        if (isAdmin)
+        {
+            claims.Add(new Claim("roles", "admin"));
+        }
 
         var token = new JwtSecurityToken(
             issuer: config.JwtIssuer,
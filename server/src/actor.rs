@@ -0,0 +1,247 @@
+use crate::messaging::RoomListItem;
+use crate::types::{ClientMap, Clients, Room, RoomVisibility};
+use dashmap::DashMap;
+use log::info;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot, watch};
+
+// Commands queued per room before callers wait for the room to catch up
+const ROOM_COMMAND_BUFFER: usize = 256;
+
+// Runs on the room's task; the returned reply is sent once the room's
+// summary has been updated, so callers never see a stale one
+type Reply = Box<dyn FnOnce() + Send>;
+type Command = Box<dyn FnOnce(&mut Room, &ClientMap) -> Reply + Send>;
+
+/// What other tasks can read about a room without asking it: enough for
+/// `room_list`, metrics and the cluster directory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoomSummary {
+    pub host_id: String,
+    pub visibility: RoomVisibility,
+    /// Playback state is raw, as of `position_ts`; readers extrapolate
+    pub listing: RoomListItem,
+    pub position_ts: u64,
+}
+
+impl RoomSummary {
+    pub fn new(room: &Room) -> Self {
+        Self {
+            host_id: room.host_id.clone(),
+            visibility: room.visibility,
+            listing: RoomListItem::new(room, room.state.clone()),
+            position_ts: room.position_ts,
+        }
+    }
+}
+
+/// Mailbox of a room's task. Each room is owned by its own task, which runs
+/// the commands sent here one at a time, so rooms never wait on each other.
+#[derive(Clone)]
+pub struct RoomHandle {
+    commands: mpsc::Sender<Command>,
+    summary: watch::Receiver<RoomSummary>,
+}
+
+impl RoomHandle {
+    /// Runs `f` on the room's task and returns its result, or None if the
+    /// room closed in the meantime. `f` must not block: the room handles
+    /// nothing else while it runs.
+    pub async fn call<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Room, &ClientMap) -> R + Send + 'static,
+    ) -> Option<R> {
+        let (tx, rx) = oneshot::channel();
+        let command: Command = Box::new(move |room, clients| {
+            let result = f(room, clients);
+            Box::new(move || {
+                let _ = tx.send(result);
+            })
+        });
+        self.commands.send(command).await.ok()?;
+        rx.await.ok()
+    }
+
+    pub fn summary(&self) -> RoomSummary {
+        self.summary.borrow().clone()
+    }
+}
+
+/// The running rooms by ID. Handles are cloned out, so no map entry is held
+/// while talking to a room.
+#[derive(Default)]
+pub struct RoomRegistry {
+    rooms: DashMap<String, RoomHandle>,
+}
+
+impl RoomRegistry {
+    /// Starts the task owning `room` and registers it. The room closes (and
+    /// unregisters itself) once its last participant is gone.
+    pub fn spawn(self: &Arc<Self>, room: Room, clients: &Clients) -> RoomHandle {
+        let (commands, receiver) = mpsc::channel(ROOM_COMMAND_BUFFER);
+        let (summary_tx, summary) = watch::channel(RoomSummary::new(&room));
+        let handle = RoomHandle { commands, summary };
+        self.rooms.insert(room.room_id.clone(), handle.clone());
+        tokio::spawn(run_room(
+            room,
+            receiver,
+            summary_tx,
+            clients.clone(),
+            self.clone(),
+        ));
+        handle
+    }
+
+    pub fn get(&self, room_id: &str) -> Option<RoomHandle> {
+        self.rooms.get(room_id).map(|handle| handle.clone())
+    }
+
+    /// `RoomHandle::call` on the room `room_id`. None if there is no such room.
+    pub async fn call<R: Send + 'static>(
+        &self,
+        room_id: &str,
+        f: impl FnOnce(&mut Room, &ClientMap) -> R + Send + 'static,
+    ) -> Option<R> {
+        self.get(room_id)?.call(f).await
+    }
+
+    pub fn contains(&self, room_id: &str) -> bool {
+        self.rooms.contains_key(room_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.rooms.len()
+    }
+
+    pub fn handles(&self) -> Vec<RoomHandle> {
+        self.rooms
+            .iter()
+            .map(|entry| entry.value().clone())
+            .collect()
+    }
+
+    pub fn summaries(&self) -> Vec<RoomSummary> {
+        self.rooms
+            .iter()
+            .map(|entry| entry.value().summary())
+            .collect()
+    }
+
+    /// ID of the room hosted by `client_id`, if any.
+    pub fn hosted_by(&self, client_id: &str) -> Option<String> {
+        self.rooms.iter().find_map(|entry| {
+            let summary = entry.value().summary.borrow();
+            (summary.host_id == client_id).then(|| entry.key().clone())
+        })
+    }
+
+    /// Unregisters the room; it keeps running until its handles are dropped
+    /// or it empties.
+    pub fn remove(&self, room_id: &str) -> Option<RoomHandle> {
+        self.rooms.remove(room_id).map(|(_, handle)| handle)
+    }
+}
+
+async fn run_room(
+    mut room: Room,
+    mut commands: mpsc::Receiver<Command>,
+    summary: watch::Sender<RoomSummary>,
+    clients: Clients,
+    registry: Arc<RoomRegistry>,
+) {
+    while let Some(command) = commands.recv().await {
+        let reply = command(&mut room, &clients);
+        if room.clients.is_empty() {
+            if registry.remove(&room.room_id).is_some() {
+                info!("Closing empty room {}", room.room_id);
+            }
+            reply();
+            break;
+        }
+        summary.send_if_modified(|current| {
+            let updated = RoomSummary::new(&room);
+            let modified = *current != updated;
+            *current = updated;
+            modified
+        });
+        reply();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{ControlMode, HostTransferPolicy, PlaybackState};
+    use std::collections::HashSet;
+    use std::time::{Duration, Instant};
+
+    fn room(room_id: &str, clients: &[&str]) -> Room {
+        Room {
+            room_id: room_id.to_string(),
+            name: "Test".to_string(),
+            host_id: clients[0].to_string(),
+            media_id: None,
+            queue: Vec::new(),
+            clients: clients.iter().map(|id| id.to_string()).collect(),
+            ready_clients: HashSet::new(),
+            pending_play: None,
+            state: PlaybackState {
+                position: 0.0,
+                play_state: "paused".to_string(),
+            },
+            host_policy: HostTransferPolicy::default(),
+            successor_id: None,
+            control_mode: ControlMode::default(),
+            controllers: HashSet::new(),
+            visibility: RoomVisibility::Public,
+            password_hash: None,
+            invite_codes: HashSet::new(),
+            banned_users: HashSet::new(),
+            muted: HashSet::new(),
+            chat_history: Default::default(),
+            position_ts: 0,
+            last_state_ts: 0,
+            last_command_ts: 0,
+            last_command_by: None,
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_busy_room_does_not_block_other_rooms() {
+        let clients: Clients = Default::default();
+        let rooms: Arc<RoomRegistry> = Default::default();
+        let busy = rooms.spawn(room("busy", &["a"]), &clients);
+        let quiet = rooms.spawn(room("quiet", &["b"]), &clients);
+
+        // Keep the busy room occupied with a slow command
+        let slow = tokio::spawn(async move {
+            busy.call(|_, _| std::thread::sleep(Duration::from_millis(500)))
+                .await
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let started = Instant::now();
+        let count = quiet
+            .call(|room, _| {
+                room.clients.push("c".to_string());
+                room.clients.len()
+            })
+            .await;
+        assert_eq!(count, Some(2));
+        assert!(started.elapsed() < Duration::from_millis(250));
+        assert_eq!(rooms.get("quiet").unwrap().summary().listing.count, 2);
+        slow.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_room_closes_when_empty() {
+        let clients: Clients = Default::default();
+        let rooms: Arc<RoomRegistry> = Default::default();
+        let handle = rooms.spawn(room("room", &["a"]), &clients);
+        assert_eq!(rooms.hosted_by("a").as_deref(), Some("room"));
+
+        handle.call(|room, _| room.clients.clear()).await.unwrap();
+        assert!(!rooms.contains("room"));
+        assert_eq!(handle.call(|room, _| room.clients.len()).await, None);
+    }
+}
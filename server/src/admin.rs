@@ -0,0 +1,601 @@
+use crate::auth::bearer_matches;
+use crate::cluster::Cluster;
+use crate::messaging::send_to_client;
+use crate::room::{close_room, expected_state, handle_disconnect};
+use crate::tokens::{Revocation, TokenRegistry};
+use crate::types::{Clients, Rooms, ServerMessage, WsMessage};
+use crate::utils::now_ms;
+use log::{info, warn};
+use std::sync::Arc;
+use warp::http::StatusCode;
+use warp::reply::{json, with_status, Json, WithStatus};
+use warp::Filter;
+
+// Max length of an admin announcement (matches the chat limit)
+const MAX_ANNOUNCEMENT_LENGTH: usize = 500;
+// Max accepted request body for admin POSTs
+const MAX_ADMIN_BODY_BYTES: u64 = 4 * 1024;
+
+type AdminReply = WithStatus<Json>;
+
+fn reply(status: StatusCode, body: serde_json::Value) -> AdminReply {
+    with_status(json(&body), status)
+}
+
+fn error(status: StatusCode, message: &str) -> AdminReply {
+    reply(status, serde_json::json!({ "error": message }))
+}
+
+/// Builds the `/admin` routes. Every request must carry
+/// `Authorization: Bearer <ADMIN_TOKEN>`; without a configured token the
+/// whole API answers 404 so it is not discoverable.
+pub fn routes(
+    clients: Clients,
+    rooms: Rooms,
+    tokens: Arc<TokenRegistry>,
+    cluster: Arc<Cluster>,
+    admin_token: Option<Arc<String>>,
+) -> impl Filter<Extract = (AdminReply,), Error = warp::Rejection> + Clone {
+    let state = warp::any().map(move || (clients.clone(), rooms.clone()));
+    let tokens = warp::any().map(move || tokens.clone());
+    let cluster = warp::any().map(move || cluster.clone());
+    let authorized = warp::header::optional::<String>("authorization").and_then(
+        move |authorization: Option<String>| {
+            let admin_token = admin_token.clone();
+            async move {
+                match admin_token.as_deref() {
+                    None => Err(warp::reject::not_found()),
+                    Some(token) if bearer_matches(authorization.as_deref(), token) => Ok(()),
+                    Some(_) => {
+                        warn!("Rejected admin API request with invalid token");
+                        Err(warp::reject::custom(Unauthorized))
+                    }
+                }
+            }
+        },
+    );
+    let admin = warp::path("admin").and(authorized).untuple_one().and(state);
+
+    let list_rooms = admin
+        .clone()
+        .and(warp::path!("rooms"))
+        .and(warp::get())
+        .then(|(_clients, rooms): (Clients, Rooms)| list_rooms(rooms));
+    let delete_room = admin
+        .clone()
+        .and(warp::path!("rooms" / String))
+        .and(warp::delete())
+        .and(cluster.clone())
+        .then(
+            |(clients, rooms): (Clients, Rooms), room_id: String, cluster: Arc<Cluster>| {
+                delete_room(clients, rooms, cluster, room_id)
+            },
+        );
+    let list_clients = admin
+        .clone()
+        .and(warp::path!("clients"))
+        .and(warp::get())
+        .then(|(clients, rooms): (Clients, Rooms)| list_clients(clients, rooms));
+    let disconnect = admin
+        .clone()
+        .and(warp::path!("clients" / String))
+        .and(warp::delete())
+        .and(cluster.clone())
+        .then(
+            |(clients, rooms): (Clients, Rooms), client_id: String, cluster: Arc<Cluster>| {
+                disconnect_client(clients, rooms, cluster, client_id)
+            },
+        );
+    let list_revocations = admin
+        .clone()
+        .and(warp::path!("revocations"))
+        .and(warp::get())
+        .and(tokens.clone())
+        .then(|_state: (Clients, Rooms), tokens: Arc<TokenRegistry>| list_revocations(tokens));
+    let revoke = admin
+        .clone()
+        .and(warp::path!("revocations"))
+        .and(warp::post())
+        .and(tokens.clone())
+        .and(cluster.clone())
+        .and(warp::body::content_length_limit(MAX_ADMIN_BODY_BYTES))
+        .and(warp::body::json())
+        .then(
+            |(clients, rooms): (Clients, Rooms),
+             tokens: Arc<TokenRegistry>,
+             cluster: Arc<Cluster>,
+             body: Revocation| { revoke(clients, rooms, tokens, cluster, body) },
+        );
+    let unrevoke = admin
+        .clone()
+        .and(warp::path!("revocations" / String / String))
+        .and(warp::delete())
+        .and(tokens)
+        .and(cluster.clone())
+        .then(
+            |_state: (Clients, Rooms),
+             kind: String,
+             value: String,
+             tokens: Arc<TokenRegistry>,
+             cluster: Arc<Cluster>| { unrevoke(tokens, cluster, kind, value) },
+        );
+    let announce = admin
+        .and(warp::path!("announce"))
+        .and(warp::post())
+        .and(cluster)
+        .and(warp::body::content_length_limit(MAX_ADMIN_BODY_BYTES))
+        .and(warp::body::json())
+        .then(
+            |(clients, _rooms): (Clients, Rooms),
+             cluster: Arc<Cluster>,
+             body: serde_json::Value| { announce(clients, cluster, body) },
+        );
+
+    list_rooms
+        .or(delete_room)
+        .unify()
+        .or(list_clients)
+        .unify()
+        .or(disconnect)
+        .unify()
+        .or(list_revocations)
+        .unify()
+        .or(revoke)
+        .unify()
+        .or(unrevoke)
+        .unify()
+        .or(announce)
+        .unify()
+        .recover(handle_rejection)
+        .unify()
+}
+
+async fn list_rooms(rooms: Rooms) -> AdminReply {
+    let now = now_ms();
+    let mut list = Vec::new();
+    for handle in rooms.handles() {
+        let entry = handle.call(move |room, clients| {
+            let participants: Vec<serde_json::Value> = room
+                .clients
+                .iter()
+                .map(|id| {
+                    let client = clients.get(id);
+                    serde_json::json!({
+                        "client_id": id,
+                        "user_name": client.as_ref().map(|c| c.user_name.clone()),
+                        "connected": client.is_some_and(|c| c.disconnected_at.is_none()),
+                        "ready": room.ready_clients.contains(id)
+                    })
+                })
+                .collect();
+            serde_json::json!({
+                "id": room.room_id,
+                "name": room.name,
+                "host_id": room.host_id,
+                "media_id": room.media_id,
+                "queue": room.queue,
+                "state": expected_state(room, now),
+                "participants": participants,
+                "ready_clients": room.ready_clients,
+                "visibility": room.visibility,
+                "control_mode": room.control_mode,
+                "has_password": room.password_hash.is_some()
+            })
+        });
+        // Rooms closing meanwhile are left out
+        if let Some(entry) = entry.await {
+            list.push(entry);
+        }
+    }
+    reply(StatusCode::OK, serde_json::json!({ "rooms": list }))
+}
+
+async fn list_clients(clients: Clients, rooms: Rooms) -> AdminReply {
+    let now = now_ms();
+    let list: Vec<serde_json::Value> = clients
+        .iter()
+        .map(|client| {
+            let id = client.key();
+            let is_host = client
+                .room_id
+                .as_ref()
+                .and_then(|room_id| rooms.get(room_id))
+                .is_some_and(|handle| handle.summary().host_id == *id);
+            serde_json::json!({
+                "client_id": id,
+                "user_id": client.user_id,
+                "user_name": client.user_name,
+                "authenticated": client.authenticated,
+                "room_id": client.room_id,
+                "is_host": is_host,
+                "connected": client.disconnected_at.is_none(),
+                "idle_ms": now.saturating_sub(client.last_seen),
+                "rtt_ms": client.clock.rtt_ms(),
+                "routed_to": client.owner_node,
+                "relayed_from": client.origin_node
+            })
+        })
+        .collect();
+    reply(StatusCode::OK, serde_json::json!({ "clients": list }))
+}
+
+async fn delete_room(
+    clients: Clients,
+    rooms: Rooms,
+    cluster: Arc<Cluster>,
+    room_id: String,
+) -> AdminReply {
+    if !rooms.contains(&room_id) {
+        return error(StatusCode::NOT_FOUND, "Room not found");
+    }
+    info!("Admin closing room {}", room_id);
+    close_room(
+        &room_id,
+        "Closed by an administrator",
+        &clients,
+        &rooms,
+        cluster.remote_rooms(),
+    )
+    .await;
+    reply(StatusCode::OK, serde_json::json!({ "closed": room_id }))
+}
+
+async fn disconnect_client(
+    clients: Clients,
+    rooms: Rooms,
+    cluster: Arc<Cluster>,
+    client_id: String,
+) -> AdminReply {
+    info!("Admin disconnecting client {}", client_id);
+    if !disconnect(
+        &client_id,
+        "Disconnected by an administrator",
+        &clients,
+        &rooms,
+        &cluster,
+    )
+    .await
+    {
+        return error(StatusCode::NOT_FOUND, "Client not found");
+    }
+    reply(
+        StatusCode::OK,
+        serde_json::json!({ "disconnected": client_id }),
+    )
+}
+
+/// Tells the client why, drops its session outright (no resume) and closes
+/// the socket. Returns false if there is no such client. The owner of the
+/// client's room, if on another node, drops it too; for a relayed client the
+/// close reaches the node holding its socket, which ends the session there.
+async fn disconnect(
+    client_id: &str,
+    reason: &str,
+    clients: &Clients,
+    rooms: &Rooms,
+    cluster: &Cluster,
+) -> bool {
+    let Some((room_id, sender)) = clients
+        .get(client_id)
+        .map(|client| (client.room_id.clone(), client.sender.clone()))
+    else {
+        return false;
+    };
+    send_to_client(
+        client_id,
+        clients,
+        &WsMessage {
+            body: ServerMessage::Disconnected {
+                reason: reason.to_string(),
+            },
+            id: None,
+            room: room_id,
+            client: Some(client_id.to_string()),
+            ts: now_ms(),
+            server_ts: Some(now_ms()),
+        },
+    );
+    cluster.client_dropped(client_id, clients).await;
+    handle_disconnect(client_id, clients, rooms, cluster.remote_rooms()).await;
+    let _ = sender.try_send(Ok(warp::ws::Message::close()));
+    true
+}
+
+async fn list_revocations(tokens: Arc<TokenRegistry>) -> AdminReply {
+    reply(
+        StatusCode::OK,
+        serde_json::json!({ "revocations": tokens.revocations(now_ms()) }),
+    )
+}
+
+/// Revokes a token (`{"jti": ...}`) or all of a user's tokens (`{"sub": ...}`)
+/// and disconnects the clients using them.
+async fn revoke(
+    clients: Clients,
+    rooms: Rooms,
+    tokens: Arc<TokenRegistry>,
+    cluster: Arc<Cluster>,
+    revocation: Revocation,
+) -> AdminReply {
+    let (Revocation::Jti(value) | Revocation::Sub(value)) = &revocation;
+    if value.is_empty() {
+        return error(StatusCode::BAD_REQUEST, "jti or sub must not be empty");
+    }
+    let expires_at = match tokens.revoke(revocation.clone(), now_ms()) {
+        Ok(expires_at) => expires_at,
+        Err(e) => return error(StatusCode::CONFLICT, &e),
+    };
+    cluster.revoked(&revocation, expires_at).await;
+    let matching = disconnect_revoked(&revocation, &clients, &rooms, &cluster).await;
+    reply(
+        StatusCode::OK,
+        serde_json::json!({ "revoked": revocation, "disconnected": matching }),
+    )
+}
+
+/// Disconnects this node's clients holding a revoked token; returns their IDs.
+/// Relayed clients are disconnected by the node holding their socket, which
+/// hears of the revocation through the cluster.
+pub async fn disconnect_revoked(
+    revocation: &Revocation,
+    clients: &Clients,
+    rooms: &Rooms,
+    cluster: &Cluster,
+) -> Vec<String> {
+    let matching: Vec<String> = clients
+        .iter()
+        .filter(|c| c.origin_node.is_none())
+        .filter(|c| match revocation {
+            Revocation::Jti(jti) => c.token_jti.as_ref() == Some(jti),
+            Revocation::Sub(sub) => c.user_id == *sub,
+        })
+        .map(|c| c.key().clone())
+        .collect();
+    info!(
+        "Revoked {:?}, disconnecting {} clients",
+        revocation,
+        matching.len()
+    );
+    for client_id in &matching {
+        disconnect(
+            client_id,
+            "Token revoked by an administrator",
+            clients,
+            rooms,
+            cluster,
+        )
+        .await;
+    }
+    matching
+}
+
+async fn unrevoke(
+    tokens: Arc<TokenRegistry>,
+    cluster: Arc<Cluster>,
+    kind: String,
+    value: String,
+) -> AdminReply {
+    let revocation = match kind.as_str() {
+        "jti" => Revocation::Jti(value),
+        "sub" => Revocation::Sub(value),
+        _ => return error(StatusCode::NOT_FOUND, "Unknown revocation kind"),
+    };
+    if !tokens.unrevoke(&revocation) {
+        return error(StatusCode::NOT_FOUND, "Not revoked");
+    }
+    cluster.unrevoked(&revocation).await;
+    info!("Admin lifted revocation {:?}", revocation);
+    reply(StatusCode::OK, serde_json::json!({ "lifted": revocation }))
+}
+
+async fn announce(clients: Clients, cluster: Arc<Cluster>, body: serde_json::Value) -> AdminReply {
+    let text = body
+        .get("text")
+        .and_then(|v| v.as_str())
+        .map(str::trim)
+        .unwrap_or("");
+    if text.is_empty() || text.chars().count() > MAX_ANNOUNCEMENT_LENGTH {
+        return error(StatusCode::BAD_REQUEST, "text must be 1-500 characters");
+    }
+    cluster.announced(text).await;
+    let recipients = announce_locally(text, &clients);
+    reply(
+        StatusCode::OK,
+        serde_json::json!({ "recipients": recipients }),
+    )
+}
+
+/// Sends an announcement to the sockets this node holds; returns how many.
+/// Relayed clients get it from the node holding their socket.
+pub fn announce_locally(text: &str, clients: &Clients) -> usize {
+    let msg = WsMessage {
+        body: ServerMessage::Announcement {
+            text: text.to_string(),
+        },
+        id: None,
+        room: None,
+        client: None,
+        ts: now_ms(),
+        server_ts: Some(now_ms()),
+    };
+    let recipients: Vec<String> = clients
+        .iter()
+        .filter(|c| c.origin_node.is_none() && c.disconnected_at.is_none())
+        .map(|c| c.key().clone())
+        .collect();
+    for id in &recipients {
+        send_to_client(id, clients, &msg);
+    }
+    info!("Announcement sent to {} clients", recipients.len());
+    recipients.len()
+}
+
+#[derive(Debug)]
+struct Unauthorized;
+impl warp::reject::Reject for Unauthorized {}
+
+async fn handle_rejection(rejection: warp::Rejection) -> Result<AdminReply, warp::Rejection> {
+    if rejection.find::<Unauthorized>().is_some() {
+        Ok(error(StatusCode::UNAUTHORIZED, "Invalid admin token"))
+    } else if rejection
+        .find::<warp::filters::body::BodyDeserializeError>()
+        .is_some()
+    {
+        Ok(error(StatusCode::BAD_REQUEST, "Invalid JSON body"))
+    } else {
+        Err(rejection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ws::tests::test_client;
+
+    fn test_cluster() -> Arc<Cluster> {
+        Arc::new(Cluster::new(
+            "test".to_string(),
+            Arc::new(crate::backend::MemoryBackend::default()),
+        ))
+    }
+
+    fn api(
+        token: Option<&str>,
+    ) -> impl Filter<Extract = (AdminReply,), Error = warp::Rejection> + Clone {
+        let clients: Clients = Default::default();
+        let rooms: Rooms = Default::default();
+        routes(
+            clients,
+            rooms,
+            Default::default(),
+            test_cluster(),
+            token.map(|t| Arc::new(t.to_string())),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_admin_requires_token() {
+        let res = warp::test::request()
+            .path("/admin/rooms")
+            .header("authorization", "Bearer wrong")
+            .reply(&api(Some("s3cret")))
+            .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let res = warp::test::request()
+            .path("/admin/rooms")
+            .reply(&api(Some("s3cret")))
+            .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_admin_disabled_without_token() {
+        let res = warp::test::request()
+            .path("/admin/rooms")
+            .header("authorization", "Bearer anything")
+            .reply(&api(None))
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_admin_routes() {
+        let api = api(Some("s3cret"));
+        let res = warp::test::request()
+            .path("/admin/rooms")
+            .header("authorization", "Bearer s3cret")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.body().as_ref(), br#"{"rooms":[]}"#);
+
+        let res = warp::test::request()
+            .method("DELETE")
+            .path("/admin/rooms/missing")
+            .header("authorization", "Bearer s3cret")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/admin/announce")
+            .header("authorization", "Bearer s3cret")
+            .json(&serde_json::json!({ "text": "  " }))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/admin/announce")
+            .header("authorization", "Bearer s3cret")
+            .json(&serde_json::json!({ "text": "Restarting in 5 minutes" }))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.body().as_ref(), br#"{"recipients":0}"#);
+    }
+
+    #[tokio::test]
+    async fn test_revocation_disconnects_clients() {
+        let clients: Clients = Default::default();
+        let tokens: Arc<TokenRegistry> = Default::default();
+        let (client, mut rx) = test_client("user-1");
+        clients.insert(
+            "alice".to_string(),
+            crate::types::Client {
+                token_jti: Some("t1".to_string()),
+                ..client
+            },
+        );
+        let api = routes(
+            clients.clone(),
+            Default::default(),
+            tokens.clone(),
+            test_cluster(),
+            Some(Arc::new("s3cret".to_string())),
+        );
+        let request = |method: &str, path: &str| {
+            warp::test::request()
+                .method(method)
+                .path(path)
+                .header("authorization", "Bearer s3cret")
+        };
+
+        let res = request("POST", "/admin/revocations")
+            .json(&serde_json::json!({ "sub": "user-2" }))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(clients.contains_key("alice"));
+
+        let res = request("POST", "/admin/revocations")
+            .json(&serde_json::json!({ "jti": "t1" }))
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.body().as_ref(),
+            br#"{"disconnected":["alice"],"revoked":{"jti":"t1"}}"#
+        );
+        assert!(!clients.contains_key("alice"));
+        let notice = rx.try_recv().unwrap().unwrap();
+        assert!(notice.to_str().unwrap().contains("disconnected"));
+        assert!(rx.try_recv().unwrap().unwrap().is_close());
+
+        let res = request("GET", "/admin/revocations").reply(&api).await;
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["revocations"].as_array().unwrap().len(), 2);
+
+        let res = request("DELETE", "/admin/revocations/sub/user-2")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = request("DELETE", "/admin/revocations/sub/user-2")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(tokens.revocations(now_ms()).len(), 1);
+    }
+}
@@ -1,6 +1,18 @@
-use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
-use serde::{Deserialize, Serialize};
+use crate::jwks::{KeySource, PublicKeys};
+use crate::tokens::{TokenRegistry, UsedToken};
+use crate::utils::now_ms;
+use argon2::password_hash::{
+    rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+};
+use argon2::Argon2;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize};
 use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Tolerance for clock skew when checking `exp`, in seconds
+pub const TOKEN_LEEWAY_SECS: u64 = 60;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
@@ -10,6 +22,94 @@ pub struct Claims {
     pub iss: String,  // Issuer (should be "Jellyfin")
     pub exp: usize,   // Expiration time
     pub iat: usize,   // Issued at
+    #[serde(default)]
+    pub jti: Option<String>, // Token ID: single-use, and what admins revoke
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub roles: Vec<String>, // "admin" grants every permission
+    #[serde(default, deserialize_with = "optional_one_or_many")]
+    pub permissions: Option<Vec<String>>, // Absent: the regular user's permissions
+}
+
+/// What a client may do, from its token's `roles` and `permissions` claims.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct Permissions {
+    pub can_create_room: bool,
+    pub can_control: bool,
+    pub can_chat: bool,
+    /// Server admin: moderates and controls any room, and isn't kept out by
+    /// passwords, invites or bans
+    pub admin: bool,
+}
+
+impl Default for Permissions {
+    /// A regular user: anyone without a `permissions` claim (or with
+    /// authentication disabled)
+    fn default() -> Self {
+        Self {
+            can_create_room: true,
+            can_control: true,
+            can_chat: true,
+            admin: false,
+        }
+    }
+}
+
+impl Permissions {
+    pub const ADMIN: Self = Self {
+        can_create_room: true,
+        can_control: true,
+        can_chat: true,
+        admin: true,
+    };
+
+    /// Tokens without a `permissions` claim get the defaults; with one, only
+    /// what it lists. Unknown names are ignored.
+    pub fn from_claims(claims: &Claims) -> Self {
+        let granted = |name: &str| {
+            claims
+                .permissions
+                .as_ref()
+                .is_none_or(|list| list.iter().any(|p| p == name))
+        };
+        let admin = claims.roles.iter().any(|r| r == "admin")
+            || claims.permissions.iter().flatten().any(|p| p == "admin");
+        if admin {
+            return Self::ADMIN;
+        }
+        Self {
+            can_create_room: granted("can_create_room"),
+            can_control: granted("can_control"),
+            can_chat: granted("can_chat"),
+            admin: false,
+        }
+    }
+}
+
+// Issuers write single-valued list claims as a plain string
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl From<OneOrMany> for Vec<String> {
+    fn from(value: OneOrMany) -> Self {
+        match value {
+            OneOrMany::One(s) => vec![s],
+            OneOrMany::Many(list) => list,
+        }
+    }
+}
+
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    OneOrMany::deserialize(deserializer).map(Vec::from)
+}
+
+fn optional_one_or_many<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<String>>, D::Error> {
+    Option::<OneOrMany>::deserialize(deserializer).map(|v| v.map(Vec::from))
 }
 
 // Minimum recommended entropy in bits for secure JWT secrets
@@ -41,20 +141,82 @@ fn calculate_entropy(s: &str) -> f64 {
     entropy * len
 }
 
+/// Hash a room password with Argon2id (PHC string, salt included).
+/// This is deliberately slow: call it from `spawn_blocking`.
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|h| h.to_string())
+        .map_err(|e| format!("Failed to hash password: {}", e))
+}
+
+/// Check a room password against a stored Argon2 PHC hash.
+/// This is deliberately slow: call it from `spawn_blocking`.
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Checks an `Authorization` header against a static bearer token
+/// (admin API, metrics). Compared in constant time.
+pub fn bearer_matches(authorization: Option<&str>, token: &str) -> bool {
+    let Some(provided) = authorization.and_then(|h| h.strip_prefix("Bearer ")) else {
+        return false;
+    };
+    provided.len() == token.len()
+        && provided
+            .bytes()
+            .zip(token.bytes())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
 #[derive(Clone)]
 pub struct JwtConfig {
-    pub secret: String,
+    pub secret: String, // HS256 shared secret, unused with `public_keys`
     pub audience: String,
     pub issuer: String,
     pub enabled: bool,
+    /// RS256/ES256 verification keys; when set, HS256 tokens are rejected
+    pub public_keys: Option<Arc<PublicKeys>>,
+    /// Used `jti`s and admin revocations (shared with the admin API)
+    pub tokens: Arc<TokenRegistry>,
+}
+
+/// Public key source from `JWT_PUBLIC_KEY_FILE` (PEM) or `JWT_JWKS` (file or URL)
+fn key_source_from_env() -> Option<KeySource> {
+    let var = |name: &str| {
+        std::env::var(name)
+            .ok()
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+    };
+    var("JWT_PUBLIC_KEY_FILE")
+        .map(KeySource::Pem)
+        .or_else(|| var("JWT_JWKS").map(KeySource::Jwks))
 }
 
 impl JwtConfig {
+    /// With public keys configured the keys still have to be loaded:
+    /// `public_keys.refresh()` before accepting connections.
     pub fn from_env() -> Self {
-        let secret = std::env::var("JWT_SECRET").unwrap_or_default();
-        let enabled = !secret.is_empty();
+        let mut secret = std::env::var("JWT_SECRET").unwrap_or_default();
+        let public_keys = key_source_from_env().map(|source| Arc::new(PublicKeys::new(source)));
+        let enabled = !secret.is_empty() || public_keys.is_some();
 
-        if !enabled {
+        if public_keys.is_some() {
+            if !secret.is_empty() {
+                log::warn!(
+                    "JWT_SECRET is ignored when public keys are configured; remove it so the server holds no signing secret"
+                );
+                secret.clear();
+            }
+        } else if !enabled {
             log::warn!("=======================================================");
             log::warn!("SECURITY WARNING: JWT_SECRET not set!");
             log::warn!("Authentication is DISABLED - anyone can join rooms.");
@@ -86,6 +248,8 @@ impl JwtConfig {
                 .unwrap_or_else(|_| "OpenWatchParty".to_string()),
             issuer: std::env::var("JWT_ISSUER").unwrap_or_else(|_| "Jellyfin".to_string()),
             enabled,
+            public_keys,
+            tokens: Default::default(),
         }
     }
 
@@ -99,29 +263,92 @@ impl JwtConfig {
                 iss: self.issuer.clone(),
                 exp: 0,
                 iat: 0,
+                jti: None,
+                roles: Vec::new(),
+                permissions: None,
             });
         }
 
-        let mut validation = Validation::new(Algorithm::HS256);
-        validation.set_audience(&[&self.audience]);
-        validation.set_issuer(&[&self.issuer]);
-        validation.validate_exp = true; // Enforce expiration check
-        validation.leeway = 60; // 60 seconds tolerance for clock skew
+        if let Some(keys) = &self.public_keys {
+            return self.validate_signed(token, keys);
+        }
 
         match decode::<Claims>(
             token,
             &DecodingKey::from_secret(self.secret.as_bytes()),
-            &validation,
+            &self.validation(Algorithm::HS256),
         ) {
             Ok(token_data) => Ok(token_data.claims),
             Err(e) => Err(format!("Invalid token: {}", e)),
         }
     }
+
+    /// The per-connection checks for claims from `validate_token`: the token
+    /// must not be revoked or already used by another connection than
+    /// `client_id`. Records the token's `jti` as used by `client_id`.
+    pub fn admit(&self, claims: &Claims, client_id: &str) -> Result<(), String> {
+        if self.enabled {
+            self.tokens.admit(claims, client_id, now_ms())?;
+        }
+        Ok(())
+    }
+
+    /// Expiry of a validated token in ms, or None when authentication is
+    /// disabled (the anonymous identity never expires).
+    pub fn expires_at(&self, claims: &Claims) -> Option<u64> {
+        self.enabled.then(|| claims.exp as u64 * 1000)
+    }
+
+    /// The `jti` use `authenticate` recorded, for sharing with other nodes.
+    pub fn used_token(&self, claims: &Claims, client_id: &str) -> Option<UsedToken> {
+        UsedToken::new(claims, client_id).filter(|_| self.enabled)
+    }
+
+    /// RS256/ES256: the key is picked by the header's `kid` and must be of
+    /// the header's algorithm, so a token can't choose how it is checked.
+    fn validate_signed(&self, token: &str, keys: &PublicKeys) -> Result<Claims, String> {
+        let header = decode_header(token).map_err(|e| format!("Invalid token: {}", e))?;
+        if !matches!(header.alg, Algorithm::RS256 | Algorithm::ES256) {
+            return Err(format!("Invalid token: {:?} is not accepted", header.alg));
+        }
+        let validation = self.validation(header.alg);
+        let mut result = Err("Invalid token: no matching key".to_string());
+        for key in keys.candidates(header.kid.as_deref(), header.alg) {
+            result = decode::<Claims>(token, &key.key, &validation)
+                .map(|token_data| token_data.claims)
+                .map_err(|e| format!("Invalid token: {}", e));
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    }
+
+    fn validation(&self, algorithm: Algorithm) -> Validation {
+        let mut validation = Validation::new(algorithm);
+        validation.set_audience(&[&self.audience]);
+        validation.set_issuer(&[&self.issuer]);
+        validation.validate_exp = true; // Enforce expiration check
+        validation.leeway = TOKEN_LEEWAY_SECS;
+        validation
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::jwks::tests::{test_jwks, EC_A_PRIVATE, EC_A_PUBLIC, EC_B_PRIVATE};
+    use crate::jwks::{parse_jwks, parse_pem, VerifyingKey};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+
+    #[test]
+    fn test_bearer_matches() {
+        assert!(bearer_matches(Some("Bearer s3cret"), "s3cret"));
+        assert!(!bearer_matches(Some("Bearer s3cre"), "s3cret"));
+        assert!(!bearer_matches(Some("Bearer s3creT"), "s3cret"));
+        assert!(!bearer_matches(Some("s3cret"), "s3cret")); // Missing scheme
+        assert!(!bearer_matches(None, "s3cret"));
+    }
 
     #[test]
     fn test_entropy_empty_string() {
@@ -182,6 +409,19 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_password_hash_roundtrip() {
+        let hash = hash_password("popcorn").unwrap();
+        assert!(
+            !hash.contains("popcorn"),
+            "Hash must not contain the plaintext"
+        );
+        assert!(hash.starts_with("$argon2"));
+        assert!(verify_password("popcorn", &hash));
+        assert!(!verify_password("Popcorn", &hash));
+        assert!(!verify_password("popcorn", "not-a-phc-string"));
+    }
+
     #[test]
     fn test_jwt_config_disabled() {
         // When no secret is set, auth should be disabled
@@ -200,6 +440,8 @@ mod tests {
             audience: "test".to_string(),
             issuer: "test".to_string(),
             enabled: false,
+            public_keys: None,
+            tokens: Default::default(),
         };
 
         // When disabled, should return anonymous claims
@@ -216,9 +458,127 @@ mod tests {
             audience: "test".to_string(),
             issuer: "test".to_string(),
             enabled: true,
+            public_keys: None,
+            tokens: Default::default(),
         };
 
         let result = config.validate_token("invalid-token");
         assert!(result.is_err(), "Should fail for invalid token");
     }
+
+    #[test]
+    fn test_permissions_from_claims() {
+        let claims = |json: serde_json::Value| -> Claims {
+            let mut base = serde_json::json!({
+                "sub": "user-1", "name": "Alice", "aud": "test", "iss": "test", "exp": 0, "iat": 0
+            });
+            base.as_object_mut()
+                .unwrap()
+                .extend(json.as_object().unwrap().clone());
+            serde_json::from_value(base).unwrap()
+        };
+
+        // Existing tokens carry neither claim
+        let regular = Permissions::from_claims(&claims(serde_json::json!({})));
+        assert_eq!(regular, Permissions::default());
+        assert!(!regular.admin);
+
+        let viewer = Permissions::from_claims(&claims(serde_json::json!({
+            "permissions": ["can_chat", "unknown"]
+        })));
+        assert_eq!(
+            viewer,
+            Permissions {
+                can_create_room: false,
+                can_control: false,
+                can_chat: true,
+                admin: false,
+            }
+        );
+        // A single value may come as a plain string
+        let muted =
+            Permissions::from_claims(&claims(serde_json::json!({ "permissions": "can_control" })));
+        assert!(muted.can_control && !muted.can_chat);
+
+        let admin = claims(serde_json::json!({ "roles": "admin", "permissions": [] }));
+        assert_eq!(Permissions::from_claims(&admin), Permissions::ADMIN);
+    }
+
+    fn signed_token(algorithm: Algorithm, kid: Option<&str>, key: &EncodingKey) -> String {
+        let mut header = Header::new(algorithm);
+        header.kid = kid.map(str::to_string);
+        let claims = Claims {
+            sub: "user-1".to_string(),
+            name: "Alice".to_string(),
+            aud: "test".to_string(),
+            iss: "test".to_string(),
+            exp: (crate::utils::now_ms() / 1000) as usize + 600,
+            iat: 0,
+            jti: None,
+            roles: Vec::new(),
+            permissions: None,
+        };
+        encode(&header, &claims, key).unwrap()
+    }
+
+    fn public_key_config(source: KeySource, keys: Vec<VerifyingKey>) -> JwtConfig {
+        JwtConfig {
+            secret: String::new(),
+            audience: "test".to_string(),
+            issuer: "test".to_string(),
+            enabled: true,
+            public_keys: Some(Arc::new(PublicKeys::with_keys(source, keys))),
+            tokens: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_jwt_validate_es256_pem() {
+        let config = public_key_config(
+            KeySource::Pem("test.pem".to_string()),
+            vec![parse_pem(EC_A_PUBLIC.as_bytes()).unwrap()],
+        );
+        let key_a = EncodingKey::from_ec_pem(EC_A_PRIVATE.as_bytes()).unwrap();
+        let key_b = EncodingKey::from_ec_pem(EC_B_PRIVATE.as_bytes()).unwrap();
+
+        let claims = config
+            .validate_token(&signed_token(Algorithm::ES256, None, &key_a))
+            .unwrap();
+        assert_eq!(claims.sub, "user-1");
+        assert!(config
+            .validate_token(&signed_token(Algorithm::ES256, None, &key_b))
+            .is_err());
+        // A shared-secret token must not be accepted once public keys are configured
+        let hs256 = EncodingKey::from_secret(b"");
+        assert!(config
+            .validate_token(&signed_token(Algorithm::HS256, None, &hs256))
+            .is_err());
+    }
+
+    #[test]
+    fn test_jwt_validate_jwks_rotation() {
+        let config = public_key_config(
+            KeySource::Jwks("test.json".to_string()),
+            parse_jwks(&test_jwks()).unwrap(),
+        );
+        let key_a = EncodingKey::from_ec_pem(EC_A_PRIVATE.as_bytes()).unwrap();
+        let key_b = EncodingKey::from_ec_pem(EC_B_PRIVATE.as_bytes()).unwrap();
+
+        // Both keys are active during a rotation
+        for (kid, key) in [("a", &key_a), ("b", &key_b)] {
+            assert!(config
+                .validate_token(&signed_token(Algorithm::ES256, Some(kid), key))
+                .is_ok());
+        }
+        // The key is chosen by `kid`, not by trying every key
+        assert!(config
+            .validate_token(&signed_token(Algorithm::ES256, Some("b"), &key_a))
+            .is_err());
+        assert!(config
+            .validate_token(&signed_token(Algorithm::ES256, Some("retired"), &key_a))
+            .is_err());
+        assert!(config
+            .validate_token(&signed_token(Algorithm::ES256, None, &key_b))
+            .is_ok());
+    }
 }
@@ -0,0 +1,289 @@
+use crate::auth::Permissions;
+use crate::messaging::RoomListItem;
+use crate::protocol::LEGACY_PROTOCOL_VERSION;
+use crate::tokens::{Revocation, TokenSnapshot, UsedToken};
+use crate::utils::now_ms;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+
+// Per-node inbox size (the memory backend; Redis buffers on its side)
+const NODE_CHANNEL_BUFFER: usize = 1024;
+
+pub type BackendFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, String>> + Send + 'a>>;
+
+/// A room as advertised in the shared directory by the node that owns it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoomEntry {
+    pub node_id: String,
+    /// Shown in other nodes' `room_list` (public rooms)
+    pub listed: bool,
+    /// Playback position is as of `position_ts`; readers extrapolate
+    pub item: RoomListItem,
+    pub position_ts: u64,
+}
+
+/// Node-to-node message. `from` is the sending node.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Envelope {
+    pub from: String,
+    pub event: NodeEvent,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum NodeEvent {
+    /// Proxy -> owner: a client connected to `from` now talks to this node
+    Attach {
+        client_id: String,
+        user_id: String,
+        user_name: String,
+        authenticated: bool,
+        // Nodes predating version negotiation don't send it
+        #[serde(default = "legacy_protocol_version")]
+        protocol_version: u32,
+        #[serde(default)]
+        permissions: Permissions,
+        #[serde(default)]
+        token_jti: Option<String>,
+    },
+    /// Proxy -> owner: a frame received from the client's socket
+    Frame { client_id: String, text: String },
+    /// Proxy -> owner: the client's socket closed (session held for resume)
+    Lost { client_id: String },
+    /// Proxy -> owner: the client resumed its session on a new socket
+    Resumed { client_id: String },
+    /// Proxy -> owner: the client moved elsewhere; drop it
+    Detach { client_id: String },
+    /// Owner -> proxy: a frame for the client's socket
+    Deliver { client_id: String, text: String },
+    /// Owner -> proxy: the owner dropped the client; close its socket and end
+    /// the session
+    Close { client_id: String },
+    /// To all: a connection on `from` authenticated with this token
+    TokenUsed { token: UsedToken },
+    /// To all: an admin revoked tokens; holders are disconnected everywhere
+    Revoke {
+        revocation: Revocation,
+        expires_at: Option<u64>,
+    },
+    /// To all: an admin lifted a revocation
+    Unrevoke { revocation: Revocation },
+    /// To all: an admin announcement for every connected client
+    Announce { text: String },
+    /// To all: `from` just started and wants the token registry
+    Hello,
+    /// Reply to `Hello`: the sender's token registry
+    Tokens { tokens: TokenSnapshot },
+}
+
+fn legacy_protocol_version() -> u32 {
+    LEGACY_PROTOCOL_VERSION
+}
+
+/// Shared room directory and node-to-node messaging for running several
+/// server instances. Room state stays on the node that owns the room (the
+/// one it was created on); other nodes relay their clients' frames to it.
+pub trait Backend: Send + Sync {
+    /// Advertises a room owned by this node; it disappears after `ttl_ms`
+    /// unless refreshed.
+    fn put_room<'a>(
+        &'a self,
+        room_id: &'a str,
+        entry: &'a RoomEntry,
+        ttl_ms: u64,
+    ) -> BackendFuture<'a, ()>;
+    fn remove_room<'a>(&'a self, room_id: &'a str) -> BackendFuture<'a, ()>;
+    fn get_room<'a>(&'a self, room_id: &'a str) -> BackendFuture<'a, Option<RoomEntry>>;
+    fn list_rooms(&self) -> BackendFuture<'_, Vec<RoomEntry>>;
+    /// Delivers `envelope` to node `to`. Fails if no such node is listening.
+    fn send<'a>(&'a self, to: &'a str, envelope: &'a Envelope) -> BackendFuture<'a, ()>;
+    /// Delivers `envelope` to every listening node but its sender.
+    fn publish<'a>(&'a self, envelope: &'a Envelope) -> BackendFuture<'a, ()>;
+    /// Starts receiving the envelopes addressed to `node_id`, and those
+    /// published to all nodes.
+    fn subscribe<'a>(&'a self, node_id: &'a str) -> BackendFuture<'a, mpsc::Receiver<Envelope>>;
+}
+
+/// In-process backend: the default single-node setup. Nodes sharing one
+/// instance (tests) see each other's rooms and messages.
+#[derive(Default)]
+pub struct MemoryBackend {
+    rooms: Mutex<HashMap<String, (RoomEntry, u64)>>, // Entry and expiry time
+    nodes: Mutex<HashMap<String, mpsc::Sender<Envelope>>>,
+}
+
+impl Backend for MemoryBackend {
+    fn put_room<'a>(
+        &'a self,
+        room_id: &'a str,
+        entry: &'a RoomEntry,
+        ttl_ms: u64,
+    ) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            let mut rooms = self.rooms.lock().map_err(|e| e.to_string())?;
+            rooms.insert(room_id.to_string(), (entry.clone(), now_ms() + ttl_ms));
+            Ok(())
+        })
+    }
+
+    fn remove_room<'a>(&'a self, room_id: &'a str) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            self.rooms
+                .lock()
+                .map_err(|e| e.to_string())?
+                .remove(room_id);
+            Ok(())
+        })
+    }
+
+    fn get_room<'a>(&'a self, room_id: &'a str) -> BackendFuture<'a, Option<RoomEntry>> {
+        Box::pin(async move {
+            let now = now_ms();
+            let rooms = self.rooms.lock().map_err(|e| e.to_string())?;
+            Ok(rooms
+                .get(room_id)
+                .filter(|(_, expires_at)| *expires_at > now)
+                .map(|(entry, _)| entry.clone()))
+        })
+    }
+
+    fn list_rooms(&self) -> BackendFuture<'_, Vec<RoomEntry>> {
+        Box::pin(async move {
+            let now = now_ms();
+            let mut rooms = self.rooms.lock().map_err(|e| e.to_string())?;
+            rooms.retain(|_, (_, expires_at)| *expires_at > now);
+            Ok(rooms.values().map(|(entry, _)| entry.clone()).collect())
+        })
+    }
+
+    fn send<'a>(&'a self, to: &'a str, envelope: &'a Envelope) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            let sender = self
+                .nodes
+                .lock()
+                .map_err(|e| e.to_string())?
+                .get(to)
+                .cloned()
+                .ok_or_else(|| format!("Node {} is not reachable", to))?;
+            sender
+                .send(envelope.clone())
+                .await
+                .map_err(|_| format!("Node {} is not reachable", to))
+        })
+    }
+
+    fn publish<'a>(&'a self, envelope: &'a Envelope) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            let senders: Vec<_> = self
+                .nodes
+                .lock()
+                .map_err(|e| e.to_string())?
+                .iter()
+                .filter(|(node, _)| **node != envelope.from)
+                .map(|(_, sender)| sender.clone())
+                .collect();
+            // Nodes that went away just miss it
+            for sender in senders {
+                let _ = sender.send(envelope.clone()).await;
+            }
+            Ok(())
+        })
+    }
+
+    fn subscribe<'a>(&'a self, node_id: &'a str) -> BackendFuture<'a, mpsc::Receiver<Envelope>> {
+        Box::pin(async move {
+            let (tx, rx) = mpsc::channel(NODE_CHANNEL_BUFFER);
+            self.nodes
+                .lock()
+                .map_err(|e| e.to_string())?
+                .insert(node_id.to_string(), tx);
+            Ok(rx)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::PlaybackState;
+
+    fn entry(node_id: &str) -> RoomEntry {
+        RoomEntry {
+            node_id: node_id.to_string(),
+            listed: true,
+            item: RoomListItem {
+                id: "room-1".to_string(),
+                name: "Movie night".to_string(),
+                count: 1,
+                media_id: None,
+                queue: Vec::new(),
+                has_password: false,
+                state: PlaybackState {
+                    position: 0.0,
+                    play_state: "paused".to_string(),
+                },
+            },
+            position_ts: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_memory_directory() {
+        let backend = MemoryBackend::default();
+        backend
+            .put_room("room-1", &entry("a"), 60_000)
+            .await
+            .unwrap();
+        backend.put_room("room-2", &entry("a"), 0).await.unwrap(); // Already expired
+
+        assert_eq!(backend.get_room("room-1").await.unwrap(), Some(entry("a")));
+        assert_eq!(backend.get_room("room-2").await.unwrap(), None);
+        assert_eq!(backend.list_rooms().await.unwrap().len(), 1);
+
+        backend.remove_room("room-1").await.unwrap();
+        assert!(backend.list_rooms().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_memory_messaging() {
+        let backend = MemoryBackend::default();
+        let mut inbox = backend.subscribe("b").await.unwrap();
+        let envelope = Envelope {
+            from: "a".to_string(),
+            event: NodeEvent::Lost {
+                client_id: "c1".to_string(),
+            },
+        };
+        backend.send("b", &envelope).await.unwrap();
+        assert_eq!(inbox.recv().await, Some(envelope.clone()));
+        assert!(backend.send("nowhere", &envelope).await.is_err());
+
+        // Published to everyone but the sender
+        let mut sender_inbox = backend.subscribe("a").await.unwrap();
+        let hello = Envelope {
+            from: "a".to_string(),
+            event: NodeEvent::Hello,
+        };
+        backend.publish(&hello).await.unwrap();
+        assert_eq!(inbox.recv().await, Some(hello));
+        assert!(sender_inbox.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_envelope_wire_format() {
+        let envelope = Envelope {
+            from: "a".to_string(),
+            event: NodeEvent::Frame {
+                client_id: "c1".to_string(),
+                text: "{}".to_string(),
+            },
+        };
+        let json = serde_json::to_value(&envelope).unwrap();
+        assert_eq!(json["event"]["kind"], "frame");
+        assert_eq!(serde_json::from_value::<Envelope>(json).unwrap(), envelope);
+    }
+}
@@ -0,0 +1,103 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+// Bounds for the per-room chat history replayed to late joiners
+const CHAT_HISTORY_MAX_MESSAGES: usize = 50;
+const CHAT_HISTORY_MAX_AGE_MS: u64 = 30 * 60 * 1000; // 30 minutes
+
+/// A chat message as relayed to the room and kept for replay.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, JsonSchema)]
+pub struct ChatEntry {
+    /// Server-assigned, increasing within a room; clients dedupe on it
+    pub id: u64,
+    pub client_id: String,
+    pub username: String,
+    pub text: String,
+    pub ts: u64,
+}
+
+/// Ring buffer of a room's recent chat, bounded by count and age.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChatHistory {
+    entries: VecDeque<ChatEntry>,
+    next_id: u64,
+}
+
+impl ChatHistory {
+    /// Records a message, assigning its ID, and returns the stored entry.
+    pub fn push(&mut self, client_id: &str, username: &str, text: &str, now: u64) -> ChatEntry {
+        self.next_id += 1;
+        let entry = ChatEntry {
+            id: self.next_id,
+            client_id: client_id.to_string(),
+            username: username.to_string(),
+            text: text.to_string(),
+            ts: now,
+        };
+        self.prune(now);
+        if self.entries.len() >= CHAT_HISTORY_MAX_MESSAGES {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry.clone());
+        entry
+    }
+
+    /// Messages still within the age limit, oldest first.
+    pub fn recent(&self, now: u64) -> Vec<ChatEntry> {
+        self.entries
+            .iter()
+            .filter(|e| now.saturating_sub(e.ts) <= CHAT_HISTORY_MAX_AGE_MS)
+            .cloned()
+            .collect()
+    }
+
+    fn prune(&mut self, now: u64) {
+        while self
+            .entries
+            .front()
+            .is_some_and(|e| now.saturating_sub(e.ts) > CHAT_HISTORY_MAX_AGE_MS)
+        {
+            self.entries.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chat_history_assigns_increasing_ids() {
+        let mut history = ChatHistory::default();
+        let first = history.push("c1", "Alice", "hi", 1_000);
+        let second = history.push("c2", "Bob", "hello", 2_000);
+        assert_eq!((first.id, second.id), (1, 2));
+        let ids: Vec<u64> = history.recent(3_000).iter().map(|e| e.id).collect();
+        assert_eq!(ids, [1, 2]);
+    }
+
+    #[test]
+    fn test_chat_history_bounded_by_count() {
+        let mut history = ChatHistory::default();
+        for i in 0..(CHAT_HISTORY_MAX_MESSAGES as u64 + 5) {
+            history.push("c1", "Alice", "spam", i);
+        }
+        let recent = history.recent(100);
+        assert_eq!(recent.len(), CHAT_HISTORY_MAX_MESSAGES);
+        assert_eq!(recent[0].id, 6); // Oldest five dropped
+    }
+
+    #[test]
+    fn test_chat_history_bounded_by_age() {
+        let mut history = ChatHistory::default();
+        history.push("c1", "Alice", "old", 0);
+        history.push("c1", "Alice", "new", CHAT_HISTORY_MAX_AGE_MS);
+        let recent = history.recent(CHAT_HISTORY_MAX_AGE_MS + 1);
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].text, "new");
+        // IDs keep increasing after old entries expire
+        let next = history.push("c1", "Alice", "newer", CHAT_HISTORY_MAX_AGE_MS + 2);
+        assert_eq!(next.id, 3);
+    }
+}
@@ -0,0 +1,1059 @@
+use crate::auth::JwtConfig;
+use crate::backend::{Backend, Envelope, MemoryBackend, NodeEvent, RoomEntry};
+use crate::config::{BackendKind, ClusterConfig, SharedConfig};
+use crate::connections::ConnectionLimiter;
+use crate::messaging::{broadcast_room_list, send_to_client, RoomListItem};
+use crate::metrics::METRICS;
+use crate::redis_backend::RedisBackend;
+use crate::room::{extrapolate, handle_connection_lost, handle_disconnect, leave_room, reattach};
+use crate::tokens::{Revocation, UsedToken};
+use crate::types::{
+    Client, ClientMessageType, Clients, IncomingMessage, RoomVisibility, Rooms, ServerMessage,
+    WsMessage,
+};
+use crate::utils::now_ms;
+use crate::ws::CLIENT_CHANNEL_BUFFER;
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+// Directory entries expire this long after their node stops refreshing them
+const DIRECTORY_TTL_MS: u64 = 15_000;
+// How often a node publishes its rooms and re-reads the other nodes' rooms
+const DIRECTORY_SYNC_MS: u64 = 1_000;
+
+type ClientSender = mpsc::Sender<std::result::Result<warp::ws::Message, warp::Error>>;
+
+/// Public rooms hosted on other nodes, as last seen by a node's directory
+/// sync. Merged into the `room_list` its clients get.
+#[derive(Default)]
+pub struct RemoteRooms(RwLock<Vec<RoomEntry>>);
+
+impl RemoteRooms {
+    /// `room_list` entries for the public rooms of other nodes.
+    pub fn list(&self, now: u64) -> Vec<RoomListItem> {
+        let remote = match self.0.read() {
+            Ok(remote) => remote,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        remote
+            .iter()
+            .map(|entry| RoomListItem {
+                state: extrapolate(&entry.item.state, entry.position_ts, now),
+                ..entry.item.clone()
+            })
+            .collect()
+    }
+
+    /// Replaces the cached rooms (sorted by ID); returns whether the
+    /// listing changed.
+    fn replace(&self, remote: Vec<RoomEntry>) -> bool {
+        let mut cache = match self.0.write() {
+            Ok(cache) => cache,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let changed = cache.len() != remote.len()
+            || cache.iter().zip(&remote).any(|(a, b)| !same_listing(a, b));
+        *cache = remote;
+        changed
+    }
+}
+
+/// Equal apart from the playback position, which moves on its own while playing.
+fn same_listing(a: &RoomEntry, b: &RoomEntry) -> bool {
+    a.node_id == b.node_id
+        && a.listed == b.listed
+        && a.item.id == b.item.id
+        && a.item.name == b.item.name
+        && a.item.count == b.item.count
+        && a.item.media_id == b.item.media_id
+        && a.item.queue == b.item.queue
+        && a.item.has_password == b.item.has_password
+        && a.item.state.play_state == b.item.state.play_state
+}
+
+/// This node's view of the cluster. A room lives on the node it was created
+/// on. When a client joins a room owned by another node, its node becomes a
+/// proxy: frames from the socket are forwarded to the owner, which handles
+/// them like local messages and streams its replies back. The room logic
+/// itself is unaware of the relaying.
+pub struct Cluster {
+    pub node_id: String,
+    backend: Arc<dyn Backend>,
+    remote_rooms: Arc<RemoteRooms>,
+}
+
+impl Cluster {
+    pub fn new(node_id: String, backend: Arc<dyn Backend>) -> Self {
+        Self {
+            node_id,
+            backend,
+            remote_rooms: Default::default(),
+        }
+    }
+
+    /// Rooms of the other nodes, for `room_list`.
+    pub fn remote_rooms(&self) -> &Arc<RemoteRooms> {
+        &self.remote_rooms
+    }
+
+    pub async fn connect(config: &ClusterConfig) -> Result<Self, String> {
+        let node_id = if config.node_id.is_empty() {
+            uuid::Uuid::new_v4().to_string()
+        } else {
+            config.node_id.clone()
+        };
+        let backend: Arc<dyn Backend> = match config.backend {
+            BackendKind::Memory => Arc::new(MemoryBackend::default()),
+            BackendKind::Redis => Arc::new(RedisBackend::connect(&config.redis_url).await?),
+        };
+        Ok(Self::new(node_id, backend))
+    }
+
+    /// Subscribes to this node's inbox and starts the event and directory loops.
+    pub async fn start(
+        self: &Arc<Self>,
+        clients: Clients,
+        rooms: Rooms,
+        jwt_config: Arc<JwtConfig>,
+        config: SharedConfig,
+        limiter: Arc<ConnectionLimiter>,
+    ) -> Result<(), String> {
+        self.listen(clients.clone(), rooms.clone(), jwt_config, config, limiter)
+            .await?;
+        // Catch up on the token uses and revocations of the running nodes
+        self.broadcast(NodeEvent::Hello).await;
+        let cluster = self.clone();
+        tokio::spawn(async move {
+            let mut published = HashMap::new();
+            loop {
+                cluster
+                    .sync_directory(&mut published, &clients, &rooms)
+                    .await;
+                tokio::time::sleep(Duration::from_millis(DIRECTORY_SYNC_MS)).await;
+            }
+        });
+        info!("Cluster node {} started", self.node_id);
+        Ok(())
+    }
+
+    async fn listen(
+        self: &Arc<Self>,
+        clients: Clients,
+        rooms: Rooms,
+        jwt_config: Arc<JwtConfig>,
+        config: SharedConfig,
+        limiter: Arc<ConnectionLimiter>,
+    ) -> Result<(), String> {
+        let mut inbox = self.backend.subscribe(&self.node_id).await?;
+        let cluster = self.clone();
+        // One task for all events keeps each client's frames in order
+        tokio::spawn(async move {
+            while let Some(envelope) = inbox.recv().await {
+                cluster
+                    .handle_event(envelope, &clients, &rooms, &jwt_config, &config, &limiter)
+                    .await;
+            }
+        });
+        Ok(())
+    }
+
+    /// Forwards a client's message to the node owning its room. Returns false
+    /// when this node should handle it. A `join_room` first moves the client
+    /// to whichever node owns the target room.
+    pub async fn route(
+        &self,
+        client_id: &str,
+        parsed: &IncomingMessage,
+        text: &str,
+        clients: &Clients,
+        rooms: &Rooms,
+    ) -> bool {
+        let (mut owner, relayed) = match clients.get(client_id) {
+            Some(c) => (c.owner_node.clone(), c.origin_node.is_some()),
+            None => return false,
+        };
+        // Relayed clients are already on their room's node. Sessions and
+        // identity belong to the socket's node (see `identity_changed`).
+        if relayed
+            || matches!(
+                parsed.msg_type,
+                ClientMessageType::Resume | ClientMessageType::Auth | ClientMessageType::Reauth
+            )
+        {
+            return false;
+        }
+        if parsed.msg_type == ClientMessageType::JoinRoom {
+            if let Some(room_id) = &parsed.room {
+                let target = self.owner_of(room_id, rooms).await;
+                if target != owner {
+                    self.reroute(
+                        client_id,
+                        owner.as_deref(),
+                        target.as_deref(),
+                        clients,
+                        rooms,
+                    )
+                    .await;
+                    owner = target;
+                }
+            }
+        }
+        let Some(node) = owner else {
+            return false;
+        };
+        let envelope = Envelope {
+            from: self.node_id.clone(),
+            event: NodeEvent::Frame {
+                client_id: client_id.to_string(),
+                text: text.to_string(),
+            },
+        };
+        if let Err(e) = self.backend.send(&node, &envelope).await {
+            warn!("Cannot relay client {} to node {}: {}", client_id, node, e);
+            self.owner_unreachable(client_id, clients).await;
+        }
+        true
+    }
+
+    /// Tells the owner of a relayed client that its socket closed, unless the
+    /// session already moved to another socket.
+    pub async fn connection_lost(&self, client_id: &str, sender: &ClientSender, clients: &Clients) {
+        let owner = clients
+            .get(client_id)
+            .filter(|c| c.sender.same_channel(sender))
+            .and_then(|c| c.owner_node.clone());
+        if let Some(node) = owner {
+            let client_id = client_id.to_string();
+            self.notify(&node, NodeEvent::Lost { client_id }).await;
+        }
+    }
+
+    /// Passes a routed client's new identity (after `auth`) on to its owner.
+    pub async fn identity_changed(&self, client_id: &str, clients: &Clients) {
+        let attach = clients.get(client_id).and_then(|c| {
+            let node = c.owner_node.clone()?;
+            Some((node, attach_event(client_id, &c)))
+        });
+        if let Some((node, event)) = attach {
+            self.notify(&node, event).await;
+        }
+    }
+
+    /// Tells the owner of a relayed client that it resumed on a new socket.
+    pub async fn session_resumed(&self, client_id: &str, clients: &Clients) {
+        let owner = clients.get(client_id).and_then(|c| c.owner_node.clone());
+        if let Some(node) = owner {
+            let client_id = client_id.to_string();
+            self.notify(&node, NodeEvent::Resumed { client_id }).await;
+        }
+    }
+
+    /// Takes a client out of a room hosted on another node; the client itself
+    /// stays connected here.
+    pub async fn leave_remote_room(&self, client_id: &str, clients: &Clients, rooms: &Rooms) {
+        let owner = clients.get(client_id).and_then(|c| c.owner_node.clone());
+        if let Some(node) = owner {
+            self.reroute(client_id, Some(&node), None, clients, rooms)
+                .await;
+        }
+    }
+
+    /// Tells the other nodes that a connection here took `token`, so they
+    /// turn it away too.
+    pub async fn token_used(&self, token: UsedToken) {
+        self.broadcast(NodeEvent::TokenUsed { token }).await;
+    }
+
+    /// Passes an admin revocation on to the other nodes, which disconnect
+    /// the sockets they hold with it.
+    pub async fn revoked(&self, revocation: &Revocation, expires_at: Option<u64>) {
+        let revocation = revocation.clone();
+        self.broadcast(NodeEvent::Revoke {
+            revocation,
+            expires_at,
+        })
+        .await;
+    }
+
+    pub async fn unrevoked(&self, revocation: &Revocation) {
+        let revocation = revocation.clone();
+        self.broadcast(NodeEvent::Unrevoke { revocation }).await;
+    }
+
+    /// Passes an admin announcement on to the other nodes' clients.
+    pub async fn announced(&self, text: &str) {
+        let text = text.to_string();
+        self.broadcast(NodeEvent::Announce { text }).await;
+    }
+
+    /// Tells the owner of a routed client's room that the client is gone for
+    /// good (rather than moved or waiting for a resume).
+    pub async fn client_dropped(&self, client_id: &str, clients: &Clients) {
+        let owner = clients.get(client_id).and_then(|c| c.owner_node.clone());
+        if let Some(node) = owner {
+            let client_id = client_id.to_string();
+            self.notify(&node, NodeEvent::Detach { client_id }).await;
+        }
+    }
+
+    /// Node owning `room_id`, or None if that is this node (or nobody).
+    async fn owner_of(&self, room_id: &str, rooms: &Rooms) -> Option<String> {
+        if rooms.contains(room_id) {
+            return None;
+        }
+        match self.backend.get_room(room_id).await {
+            Ok(entry) => entry
+                .map(|e| e.node_id)
+                .filter(|node| *node != self.node_id),
+            Err(e) => {
+                warn!("Room directory lookup failed: {}", e);
+                None
+            }
+        }
+    }
+
+    async fn reroute(
+        &self,
+        client_id: &str,
+        from: Option<&str>,
+        to: Option<&str>,
+        clients: &Clients,
+        rooms: &Rooms,
+    ) {
+        if let Some(old) = from {
+            let client_id = client_id.to_string();
+            self.notify(old, NodeEvent::Detach { client_id }).await;
+        }
+        let Some(in_room) = clients.get(client_id).map(|c| c.room_id.is_some()) else {
+            return;
+        };
+        // Leave any local room before following the client to another node
+        let left_room = to.is_some() && in_room;
+        if left_room {
+            leave_room(client_id, clients, rooms).await;
+        }
+        let attach = {
+            let Some(mut client) = clients.get_mut(client_id) else {
+                return;
+            };
+            client.owner_node = to.map(str::to_string);
+            attach_event(client_id, &client)
+        };
+        if left_room {
+            broadcast_room_list(clients, rooms, &self.remote_rooms);
+        }
+        if let Some(node) = to {
+            debug!("Routing client {} to node {}", client_id, node);
+            self.notify(node, attach).await;
+        }
+    }
+
+    /// The owning node is gone: the room is lost for this client.
+    async fn owner_unreachable(&self, client_id: &str, clients: &Clients) {
+        if let Some(mut client) = clients.get_mut(client_id) {
+            client.owner_node = None;
+        }
+        send_to_client(
+            client_id,
+            clients,
+            &WsMessage {
+                body: ServerMessage::RoomClosed {
+                    reason: "The server hosting this room is unavailable".to_string(),
+                },
+                id: None,
+                room: None,
+                client: None,
+                ts: now_ms(),
+                server_ts: Some(now_ms()),
+            },
+        );
+    }
+
+    async fn notify(&self, node: &str, event: NodeEvent) {
+        let envelope = Envelope {
+            from: self.node_id.clone(),
+            event,
+        };
+        if let Err(e) = self.backend.send(node, &envelope).await {
+            warn!("Cannot reach node {}: {}", node, e);
+        }
+    }
+
+    async fn broadcast(&self, event: NodeEvent) {
+        let envelope = Envelope {
+            from: self.node_id.clone(),
+            event,
+        };
+        if let Err(e) = self.backend.publish(&envelope).await {
+            warn!("Cannot reach the other nodes: {}", e);
+        }
+    }
+
+    async fn handle_event(
+        &self,
+        envelope: Envelope,
+        clients: &Clients,
+        rooms: &Rooms,
+        jwt_config: &Arc<JwtConfig>,
+        config: &SharedConfig,
+        limiter: &Arc<ConnectionLimiter>,
+    ) {
+        let from = envelope.from;
+        match envelope.event {
+            NodeEvent::Attach {
+                client_id,
+                user_id,
+                user_name,
+                authenticated,
+                protocol_version,
+                permissions,
+                token_jti,
+            } => {
+                // Already attached from there: the client re-authenticated
+                if let Some(mut client) = clients
+                    .get_mut(&client_id)
+                    .filter(|c| c.origin_node.as_deref() == Some(from.as_str()))
+                {
+                    client.user_id = user_id;
+                    client.user_name = user_name;
+                    client.authenticated = authenticated;
+                    client.protocol_version = protocol_version;
+                    client.permissions = permissions;
+                    client.token_jti = token_jti;
+                    return;
+                }
+                // Drop a stale copy under the same ID before attaching
+                if clients.contains_key(&client_id) {
+                    handle_disconnect(&client_id, clients, rooms, &self.remote_rooms).await;
+                }
+                debug!("Client {} attached from node {}", client_id, from);
+                let sender = self.relay_sender(&from, &client_id);
+                clients.insert(
+                    client_id,
+                    Client {
+                        user_id,
+                        user_name,
+                        authenticated,
+                        protocol_version,
+                        token_jti,
+                        permissions,
+                        ..Client::relayed(sender, from)
+                    },
+                );
+            }
+            NodeEvent::Frame { client_id, text } => {
+                if self.relayed_from(&client_id, &from, clients) {
+                    crate::ws::client_msg(
+                        &client_id,
+                        warp::ws::Message::text(text),
+                        clients,
+                        rooms,
+                        jwt_config,
+                        &config.get(),
+                        self,
+                        limiter,
+                    )
+                    .await;
+                } else {
+                    debug!("Dropping frame for unknown relayed client {}", client_id);
+                }
+            }
+            NodeEvent::Lost { client_id } => {
+                let sender = clients
+                    .get(&client_id)
+                    .filter(|c| c.origin_node.as_deref() == Some(from.as_str()))
+                    .map(|c| c.sender.clone());
+                if let Some(sender) = sender {
+                    let grace_ms = config.get().session.resume_grace_ms;
+                    handle_connection_lost(
+                        &client_id,
+                        &sender,
+                        clients,
+                        rooms,
+                        &self.remote_rooms,
+                        grace_ms,
+                    )
+                    .await;
+                }
+            }
+            NodeEvent::Resumed { client_id } => {
+                if self.relayed_from(&client_id, &from, clients) {
+                    reattach(&client_id, clients, rooms).await;
+                }
+            }
+            NodeEvent::Detach { client_id } => {
+                if self.relayed_from(&client_id, &from, clients) {
+                    handle_disconnect(&client_id, clients, rooms, &self.remote_rooms).await;
+                }
+            }
+            NodeEvent::Deliver { client_id, text } => {
+                deliver(&client_id, &from, warp::ws::Message::text(text), clients);
+            }
+            NodeEvent::Close { client_id } => {
+                deliver(&client_id, &from, warp::ws::Message::close(), clients);
+                // The owner dropped the client; so does the session here
+                let routed = clients
+                    .get(&client_id)
+                    .is_some_and(|c| c.owner_node.as_deref() == Some(from.as_str()));
+                if routed {
+                    handle_disconnect(&client_id, clients, rooms, &self.remote_rooms).await;
+                }
+            }
+            NodeEvent::TokenUsed { token } => {
+                jwt_config.tokens.record_used(token, now_ms());
+            }
+            NodeEvent::Revoke {
+                revocation,
+                expires_at,
+            } => {
+                if let Err(e) =
+                    jwt_config
+                        .tokens
+                        .apply_revocation(revocation.clone(), expires_at, now_ms())
+                {
+                    warn!("Cannot apply revocation from node {}: {}", from, e);
+                }
+                crate::admin::disconnect_revoked(&revocation, clients, rooms, self).await;
+            }
+            NodeEvent::Unrevoke { revocation } => {
+                jwt_config.tokens.unrevoke(&revocation);
+            }
+            NodeEvent::Announce { text } => {
+                crate::admin::announce_locally(&text, clients);
+            }
+            NodeEvent::Hello => {
+                let tokens = jwt_config.tokens.snapshot(now_ms());
+                self.notify(&from, NodeEvent::Tokens { tokens }).await;
+            }
+            NodeEvent::Tokens { tokens } => {
+                jwt_config.tokens.restore(tokens, now_ms());
+            }
+        }
+    }
+
+    fn relayed_from(&self, client_id: &str, node: &str, clients: &Clients) -> bool {
+        clients
+            .get(client_id)
+            .is_some_and(|c| c.origin_node.as_deref() == Some(node))
+    }
+
+    /// Channel standing in for a relayed client's socket: whatever the room
+    /// logic sends to it is passed on to the node holding the real socket.
+    fn relay_sender(&self, node: &str, client_id: &str) -> ClientSender {
+        let (sender, mut receiver): (ClientSender, _) = mpsc::channel(CLIENT_CHANNEL_BUFFER);
+        let backend = self.backend.clone();
+        let from = self.node_id.clone();
+        let node = node.to_string();
+        let client_id = client_id.to_string();
+        tokio::spawn(async move {
+            while let Some(Ok(msg)) = receiver.recv().await {
+                let event = if msg.is_close() {
+                    NodeEvent::Close {
+                        client_id: client_id.clone(),
+                    }
+                } else if let Ok(text) = msg.to_str() {
+                    NodeEvent::Deliver {
+                        client_id: client_id.clone(),
+                        text: text.to_string(),
+                    }
+                } else {
+                    continue;
+                };
+                let envelope = Envelope {
+                    from: from.clone(),
+                    event,
+                };
+                if let Err(e) = backend.send(&node, &envelope).await {
+                    METRICS.broadcast_failed();
+                    warn!(
+                        "Failed to relay to client {} on node {}: {}",
+                        client_id, node, e
+                    );
+                }
+            }
+        });
+        sender
+    }
+
+    /// Publishes this node's rooms and refreshes the other nodes' public rooms.
+    /// `published` remembers what was last written, to skip unchanged rooms.
+    async fn sync_directory(
+        &self,
+        published: &mut HashMap<String, (RoomEntry, u64)>,
+        clients: &Clients,
+        rooms: &Rooms,
+    ) {
+        let now = now_ms();
+        let entries: Vec<(String, RoomEntry)> = rooms
+            .summaries()
+            .into_iter()
+            .map(|summary| {
+                let entry = RoomEntry {
+                    node_id: self.node_id.clone(),
+                    listed: summary.visibility == RoomVisibility::Public,
+                    item: summary.listing,
+                    position_ts: summary.position_ts,
+                };
+                (entry.item.id.clone(), entry)
+            })
+            .collect();
+
+        for (room_id, entry) in &entries {
+            let fresh = published.get(room_id).is_some_and(|(last, at)| {
+                same_listing(last, entry) && now.saturating_sub(*at) < DIRECTORY_TTL_MS / 3
+            });
+            if fresh {
+                continue;
+            }
+            match self
+                .backend
+                .put_room(room_id, entry, DIRECTORY_TTL_MS)
+                .await
+            {
+                Ok(()) => {
+                    published.insert(room_id.clone(), (entry.clone(), now));
+                }
+                Err(e) => warn!("Failed to publish room {}: {}", room_id, e),
+            }
+        }
+        let closed: Vec<String> = published
+            .keys()
+            .filter(|id| !entries.iter().any(|(room_id, _)| room_id == *id))
+            .cloned()
+            .collect();
+        for room_id in closed {
+            match self.backend.remove_room(&room_id).await {
+                Ok(()) => {
+                    published.remove(&room_id);
+                }
+                Err(e) => warn!("Failed to unpublish room {}: {}", room_id, e),
+            }
+        }
+
+        let mut remote: Vec<RoomEntry> = match self.backend.list_rooms().await {
+            Ok(list) => list
+                .into_iter()
+                .filter(|e| e.node_id != self.node_id && e.listed)
+                .collect(),
+            Err(e) => {
+                warn!("Failed to read the room directory: {}", e);
+                return;
+            }
+        };
+        remote.sort_by(|a, b| a.item.id.cmp(&b.item.id));
+        if self.remote_rooms.replace(remote) {
+            broadcast_room_list(clients, rooms, &self.remote_rooms);
+        }
+    }
+}
+
+fn attach_event(client_id: &str, client: &Client) -> NodeEvent {
+    NodeEvent::Attach {
+        client_id: client_id.to_string(),
+        user_id: client.user_id.clone(),
+        user_name: client.user_name.clone(),
+        authenticated: client.authenticated,
+        protocol_version: client.protocol_version,
+        permissions: client.permissions,
+        token_jti: client.token_jti.clone(),
+    }
+}
+
+/// Passes a frame from a room's owner to the local socket of a routed client.
+fn deliver(client_id: &str, owner: &str, msg: warp::ws::Message, clients: &Clients) {
+    let Some(client) = clients
+        .get(client_id)
+        .filter(|c| c.owner_node.as_deref() == Some(owner) && c.disconnected_at.is_none())
+    else {
+        return;
+    };
+    if let Err(e) = client.sender.try_send(Ok(msg)) {
+        METRICS.broadcast_failed();
+        warn!(
+            "Failed to send to client {} (buffer full or closed): {}",
+            client_id, e
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::types::PlaybackState;
+    use crate::ws::tests::{test_client, test_jwt_config, Receiver};
+
+    struct Node {
+        cluster: Arc<Cluster>,
+        clients: Clients,
+        rooms: Rooms,
+        jwt_config: Arc<JwtConfig>,
+        config: SharedConfig,
+        limiter: Arc<ConnectionLimiter>,
+    }
+
+    impl Node {
+        async fn new(node_id: &str, backend: &Arc<MemoryBackend>) -> Self {
+            let node = Self {
+                cluster: Arc::new(Cluster::new(node_id.to_string(), backend.clone())),
+                clients: Default::default(),
+                rooms: Default::default(),
+                jwt_config: test_jwt_config(None),
+                config: SharedConfig::new(Config::default()),
+                limiter: Default::default(),
+            };
+            node.cluster
+                .listen(
+                    node.clients.clone(),
+                    node.rooms.clone(),
+                    node.jwt_config.clone(),
+                    node.config.clone(),
+                    node.limiter.clone(),
+                )
+                .await
+                .unwrap();
+            node
+        }
+
+        async fn connect(&self, client_id: &str) -> Receiver {
+            let (client, rx) = test_client(client_id);
+            self.clients.insert(client_id.to_string(), client);
+            rx
+        }
+
+        async fn send(&self, client_id: &str, msg: serde_json::Value) {
+            crate::ws::client_msg(
+                client_id,
+                warp::ws::Message::text(msg.to_string()),
+                &self.clients,
+                &self.rooms,
+                &self.jwt_config,
+                &self.config.get(),
+                &self.cluster,
+                &self.limiter,
+            )
+            .await;
+        }
+    }
+
+    /// Waits for the next message of type `msg_type`, skipping others.
+    async fn expect(rx: &mut Receiver, msg_type: &str) -> serde_json::Value {
+        let wait = async {
+            while let Some(Ok(msg)) = rx.recv().await {
+                let json: serde_json::Value = serde_json::from_str(msg.to_str().unwrap()).unwrap();
+                if json["type"] == msg_type {
+                    return json;
+                }
+            }
+            panic!("channel closed while waiting for {}", msg_type);
+        };
+        tokio::time::timeout(Duration::from_secs(5), wait)
+            .await
+            .unwrap_or_else(|_| panic!("timed out waiting for {}", msg_type))
+    }
+
+    #[tokio::test]
+    async fn test_remote_room_is_relayed() {
+        let backend = Arc::new(MemoryBackend::default());
+        let a = Node::new("a", &backend).await;
+        let b = Node::new("b", &backend).await;
+
+        let mut host_rx = a.connect("host").await;
+        a.send(
+            "host",
+            serde_json::json!({ "type": "create_room", "ts": 0 }),
+        )
+        .await;
+        let room_id = expect(&mut host_rx, "room_state").await["room"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let summary = a.rooms.get(&room_id).unwrap().summary();
+        let entry = RoomEntry {
+            node_id: "a".to_string(),
+            listed: true,
+            item: summary.listing,
+            position_ts: summary.position_ts,
+        };
+        backend.put_room(&room_id, &entry, 60_000).await.unwrap();
+
+        // A guest on node b joins the room hosted on node a
+        let mut guest_rx = b.connect("guest").await;
+        b.send(
+            "guest",
+            serde_json::json!({ "type": "join_room", "room": room_id, "ts": 0 }),
+        )
+        .await;
+        expect(&mut guest_rx, "room_state").await;
+        expect(&mut host_rx, "participants_update").await;
+        assert_eq!(
+            a.rooms.call(&room_id, |room, _| room.clients.len()).await,
+            Some(2)
+        );
+        assert_eq!(
+            a.clients.get("guest").unwrap().origin_node.as_deref(),
+            Some("b")
+        );
+        assert_eq!(
+            b.clients.get("guest").unwrap().owner_node.as_deref(),
+            Some("a")
+        );
+
+        // Room broadcasts reach the guest through its node
+        a.send(
+            "host",
+            serde_json::json!({ "type": "chat_message", "room": room_id, "payload": { "text": "hi" }, "ts": 0 }),
+        )
+        .await;
+        let chat = expect(&mut guest_rx, "chat_message").await;
+        assert_eq!(chat["payload"]["text"], "hi");
+
+        // Losing the guest's socket holds its session on the owning node
+        let sender = b.clients.get("guest").unwrap().sender.clone();
+        b.cluster
+            .connection_lost("guest", &sender, &b.clients)
+            .await;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while a.clients.get("guest").unwrap().disconnected_at.is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_nodes_keep_their_own_remote_rooms() {
+        let backend = Arc::new(MemoryBackend::default());
+        let a = Node::new("a", &backend).await;
+        let b = Node::new("b", &backend).await;
+        let mut host_rx = a.connect("host").await;
+        a.send(
+            "host",
+            serde_json::json!({ "type": "create_room", "ts": 0 }),
+        )
+        .await;
+        let room_id = expect(&mut host_rx, "room_state").await["room"].clone();
+
+        for node in [&a, &b] {
+            node.cluster
+                .sync_directory(&mut HashMap::new(), &node.clients, &node.rooms)
+                .await;
+        }
+        // Each node lists the other's rooms only; its own come from `rooms`
+        assert!(a.cluster.remote_rooms().list(now_ms()).is_empty());
+        let remote = b.cluster.remote_rooms().list(now_ms());
+        assert_eq!(remote.len(), 1);
+        assert_eq!(remote[0].id, room_id.as_str().unwrap());
+
+        let mut guest_rx = b.connect("guest").await;
+        b.send(
+            "guest",
+            serde_json::json!({ "type": "list_rooms", "ts": 0 }),
+        )
+        .await;
+        let list = expect(&mut guest_rx, "room_list").await;
+        assert_eq!(list["payload"].as_array().unwrap().len(), 1);
+        a.send("host", serde_json::json!({ "type": "list_rooms", "ts": 0 }))
+            .await;
+        let list = expect(&mut host_rx, "room_list").await;
+        assert_eq!(list["payload"].as_array().unwrap().len(), 1);
+    }
+
+    /// Polls `done` until it holds (the other node's event loop is async).
+    async fn eventually(done: impl Fn() -> bool) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !done() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("timed out waiting for the other node");
+    }
+
+    #[tokio::test]
+    async fn test_token_registry_is_shared() {
+        let backend = Arc::new(MemoryBackend::default());
+        let a = Node::new("a", &backend).await;
+        let b = Node::new("b", &backend).await;
+        let claims = |jti: &str| crate::auth::Claims {
+            sub: "bob".to_string(),
+            name: "Bob".to_string(),
+            aud: "test".to_string(),
+            iss: "test".to_string(),
+            exp: (now_ms() / 1000 + 3600) as usize,
+            iat: 0,
+            jti: Some(jti.to_string()),
+            roles: Vec::new(),
+            permissions: None,
+        };
+
+        // A token used on node a is turned away on node b
+        a.jwt_config
+            .tokens
+            .admit(&claims("t1"), "c1", now_ms())
+            .unwrap();
+        let token = UsedToken::new(&claims("t1"), "c1").unwrap();
+        a.cluster.token_used(token).await;
+        eventually(|| b.jwt_config.tokens.snapshot(now_ms()) != Default::default()).await;
+        assert!(b
+            .jwt_config
+            .tokens
+            .admit(&claims("t1"), "c2", now_ms())
+            .is_err());
+
+        // Revoking on node a disconnects the socket held by node b
+        let mut alice_rx = b.connect("alice").await;
+        let revocation = Revocation::Sub("alice".to_string());
+        a.jwt_config
+            .tokens
+            .revoke(revocation.clone(), now_ms())
+            .unwrap();
+        a.cluster.revoked(&revocation, None).await;
+        expect(&mut alice_rx, "disconnected").await;
+        assert!(!b.clients.contains_key("alice"));
+        assert_eq!(b.jwt_config.tokens.revocations(now_ms()), vec![revocation]);
+
+        // A node joining later catches up from the running ones
+        let c = Node::new("c", &backend).await;
+        c.cluster.broadcast(NodeEvent::Hello).await;
+        eventually(|| c.jwt_config.tokens.revocations(now_ms()).len() == 1).await;
+        assert!(c
+            .jwt_config
+            .tokens
+            .admit(&claims("t1"), "c3", now_ms())
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_attach_carries_token_jti() {
+        let backend = Arc::new(MemoryBackend::default());
+        let a = Node::new("a", &backend).await;
+        let b = Node::new("b", &backend).await;
+        let _guest_rx = b.connect("guest").await;
+        b.clients.get_mut("guest").unwrap().token_jti = Some("t1".to_string());
+        b.cluster
+            .reroute("guest", None, Some("a"), &b.clients, &b.rooms)
+            .await;
+        eventually(|| {
+            a.clients
+                .get("guest")
+                .is_some_and(|c| c.token_jti.as_deref() == Some("t1"))
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_admin_actions_reach_other_nodes() {
+        let backend = Arc::new(MemoryBackend::default());
+        let a = Node::new("a", &backend).await;
+        let b = Node::new("b", &backend).await;
+        let admin = |node: &Node, method: &str, path: &str, body: serde_json::Value| {
+            let api = crate::admin::routes(
+                node.clients.clone(),
+                node.rooms.clone(),
+                node.jwt_config.tokens.clone(),
+                node.cluster.clone(),
+                Some(Arc::new("s3cret".to_string())),
+            );
+            let request = warp::test::request()
+                .method(method)
+                .path(path)
+                .header("authorization", "Bearer s3cret")
+                .json(&body);
+            async move { request.reply(&api).await }
+        };
+        let attach = |client_id: &'static str| {
+            let b = &b;
+            let a = &a;
+            async move {
+                let rx = b.connect(client_id).await;
+                b.cluster
+                    .reroute(client_id, None, Some("a"), &b.clients, &b.rooms)
+                    .await;
+                eventually(|| a.clients.contains_key(client_id)).await;
+                rx
+            }
+        };
+        let mut guest_rx = attach("guest").await;
+
+        // Once, from the node holding the socket
+        admin(
+            &a,
+            "POST",
+            "/admin/announce",
+            serde_json::json!({ "text": "hi" }),
+        )
+        .await;
+        assert_eq!(
+            expect(&mut guest_rx, "announcement").await["payload"]["text"],
+            "hi"
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(guest_rx.try_recv().is_err());
+
+        // Dropped by the room's owner: the session ends on the socket's node
+        admin(
+            &a,
+            "DELETE",
+            "/admin/clients/guest",
+            serde_json::Value::Null,
+        )
+        .await;
+        expect(&mut guest_rx, "disconnected").await;
+        eventually(|| !b.clients.contains_key("guest")).await;
+
+        // Dropped by the socket's node: the owner lets go of its copy
+        let _other_rx = attach("other").await;
+        admin(
+            &b,
+            "DELETE",
+            "/admin/clients/other",
+            serde_json::Value::Null,
+        )
+        .await;
+        eventually(|| !a.clients.contains_key("other")).await;
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_owner_closes_room() {
+        let backend = Arc::new(MemoryBackend::default());
+        let b = Node::new("b", &backend).await;
+        let mut guest_rx = b.connect("guest").await;
+        b.clients.get_mut("guest").unwrap().owner_node = Some("gone".to_string());
+
+        b.send(
+            "guest",
+            serde_json::json!({ "type": "list_rooms", "ts": 0 }),
+        )
+        .await;
+        expect(&mut guest_rx, "room_closed").await;
+        assert!(b.clients.get("guest").unwrap().owner_node.is_none());
+    }
+
+    #[test]
+    fn test_same_listing_ignores_position() {
+        let entry = |position: f64, count: usize| RoomEntry {
+            node_id: "a".to_string(),
+            listed: true,
+            item: RoomListItem {
+                id: "room-1".to_string(),
+                name: "Movie night".to_string(),
+                count,
+                media_id: None,
+                queue: Vec::new(),
+                has_password: false,
+                state: PlaybackState {
+                    position,
+                    play_state: "playing".to_string(),
+                },
+            },
+            position_ts: 0,
+        };
+        assert!(same_listing(&entry(1.0, 1), &entry(2.0, 1)));
+        assert!(!same_listing(&entry(1.0, 1), &entry(1.0, 2)));
+    }
+}
@@ -0,0 +1,513 @@
+use crate::timesync::ScheduleConfig;
+use log::LevelFilter;
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+// Config file used when CONFIG_FILE is not set (optional: defaults apply if missing)
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+/// Server configuration: defaults, overridden by the TOML file, overridden
+/// by environment variables. Secrets (JWT secret, admin/metrics tokens)
+/// are read from the environment only and are not part of this struct.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub limits: LimitsConfig,
+    pub sync: SyncConfig,
+    pub session: SessionConfig,
+    pub persistence: PersistenceConfig,
+    pub cluster: ClusterConfig,
+}
+
+/// Listener and logging. `bind_address`/`port` need a restart to change.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind_address: String,
+    pub port: u16,
+    pub allowed_origins: Vec<String>,
+    pub log_level: String,
+    pub trusted_proxies: Vec<IpAddr>, // Peers whose X-Forwarded-For is believed
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: "0.0.0.0".to_string(),
+            port: 3000,
+            allowed_origins: vec![
+                "http://localhost:8096".to_string(),
+                "https://localhost:8096".to_string(),
+            ],
+            log_level: "info".to_string(),
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+/// Per-client and per-room resource limits.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub rate_limit_messages: u32, // Max messages per window
+    pub rate_limit_window_ms: u64,
+    pub max_clients_per_room: usize,
+    pub max_message_size: usize, // Bytes
+    pub max_chat_message_length: usize,
+    pub max_invites_per_room: usize,
+    pub max_queue_length: usize,
+    pub max_connections_per_user: usize, // 0 = unlimited
+    pub max_connections_per_ip: usize,   // 0 = unlimited
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            rate_limit_messages: 30,
+            rate_limit_window_ms: 1000,
+            max_clients_per_room: 20,
+            max_message_size: 64 * 1024,
+            max_chat_message_length: 500,
+            max_invites_per_room: 50,
+            max_queue_length: 100,
+            max_connections_per_user: 10,
+            max_connections_per_ip: 50,
+        }
+    }
+}
+
+/// Playback scheduling and state-update filtering.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SyncConfig {
+    pub play_schedule_ms: u64, // Used until participant latency is measured
+    pub control_schedule_ms: u64,
+    pub schedule_margin_ms: u64,
+    pub schedule_floor_ms: u64,
+    pub schedule_ceiling_ms: u64,
+    pub min_state_update_interval_ms: u64,
+    pub position_jitter_threshold: f64, // Seconds
+    pub command_cooldown_ms: u64,
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        let schedule = ScheduleConfig::default();
+        Self {
+            play_schedule_ms: 1000, // Reduced from 1500ms for better UX (UX-P1)
+            control_schedule_ms: 300,
+            schedule_margin_ms: schedule.margin_ms,
+            schedule_floor_ms: schedule.floor_ms,
+            schedule_ceiling_ms: schedule.ceiling_ms,
+            min_state_update_interval_ms: 500,
+            position_jitter_threshold: 0.5,
+            command_cooldown_ms: 2000,
+        }
+    }
+}
+
+impl SyncConfig {
+    pub fn schedule(&self) -> ScheduleConfig {
+        ScheduleConfig {
+            margin_ms: self.schedule_margin_ms,
+            floor_ms: self.schedule_floor_ms,
+            ceiling_ms: self.schedule_ceiling_ms,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// How long a dropped client's session is held for a resume (0 disables resuming)
+    pub resume_grace_ms: u64,
+    /// How long before a client's JWT expires it is sent `token_expiring`
+    pub token_warning_ms: u64,
+    /// How long a client may stay on an expired JWT before it is demoted
+    pub token_grace_ms: u64,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            resume_grace_ms: 30_000,
+            token_warning_ms: 300_000,
+            token_grace_ms: 60_000,
+        }
+    }
+}
+
+/// Room snapshots that let active rooms survive a restart.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PersistenceConfig {
+    /// JSON snapshot file; empty disables persistence
+    pub snapshot_path: String,
+    pub snapshot_interval_secs: u64,
+}
+
+impl Default for PersistenceConfig {
+    fn default() -> Self {
+        Self {
+            snapshot_path: String::new(),
+            snapshot_interval_secs: 30,
+        }
+    }
+}
+
+/// Where room directory and node-to-node messages live.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendKind {
+    /// Single node (or several nodes inside one process, in tests)
+    #[default]
+    Memory,
+    /// Redis pub/sub: several server instances behind a load balancer
+    Redis,
+}
+
+impl FromStr for BackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(Self::Memory),
+            "redis" => Ok(Self::Redis),
+            _ => Err(format!("unknown backend: {}", s)),
+        }
+    }
+}
+
+/// Multi-node setup. Changes need a restart.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClusterConfig {
+    pub backend: BackendKind,
+    pub redis_url: String,
+    /// Unique per instance; empty picks a random ID at startup
+    pub node_id: String,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        Self {
+            backend: BackendKind::Memory,
+            redis_url: "redis://127.0.0.1:6379".to_string(),
+            node_id: String::new(),
+        }
+    }
+}
+
+/// Overwrites `target` with the parsed value of env var `key`, if set.
+fn env_override<T: FromStr>(key: &str, target: &mut T) -> Result<(), String> {
+    match std::env::var(key) {
+        Ok(value) => {
+            *target = value
+                .trim()
+                .parse()
+                .map_err(|_| format!("Invalid value for {}: {:?}", key, value))?;
+            Ok(())
+        }
+        Err(_) => Ok(()),
+    }
+}
+
+impl Config {
+    /// Path of the TOML file: `CONFIG_FILE`, else `config.toml`.
+    pub fn path() -> String {
+        std::env::var("CONFIG_FILE").unwrap_or_else(|_| DEFAULT_CONFIG_FILE.to_string())
+    }
+
+    /// Loads the file at `path` (if present), applies env overrides and validates.
+    /// An explicitly configured `CONFIG_FILE` must exist.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let mut config = match std::fs::read_to_string(path) {
+            Ok(text) => Self::from_toml(&text).map_err(|e| format!("{}: {}", path, e))?,
+            Err(e)
+                if e.kind() == std::io::ErrorKind::NotFound
+                    && std::env::var("CONFIG_FILE").is_err() =>
+            {
+                Self::default()
+            }
+            Err(e) => return Err(format!("Cannot read {}: {}", path, e)),
+        };
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self, String> {
+        toml::from_str(text).map_err(|e| e.to_string())
+    }
+
+    /// Environment variables take precedence over the file.
+    fn apply_env(&mut self) -> Result<(), String> {
+        let server = &mut self.server;
+        env_override("BIND_ADDRESS", &mut server.bind_address)?;
+        env_override("PORT", &mut server.port)?;
+        if let Ok(origins) = std::env::var("ALLOWED_ORIGINS") {
+            server.allowed_origins = origins
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect();
+        }
+        // A bare level in RUST_LOG (e.g. "debug") still works as before
+        if let Ok(level) = std::env::var("RUST_LOG") {
+            if LevelFilter::from_str(level.trim()).is_ok() {
+                server.log_level = level.trim().to_string();
+            }
+        }
+        env_override("LOG_LEVEL", &mut server.log_level)?;
+        if let Ok(proxies) = std::env::var("TRUSTED_PROXIES") {
+            server.trusted_proxies = proxies
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(|s| {
+                    s.parse()
+                        .map_err(|_| format!("Invalid IP address in TRUSTED_PROXIES: {}", s))
+                })
+                .collect::<Result<_, _>>()?;
+        }
+
+        let limits = &mut self.limits;
+        env_override("RATE_LIMIT_MESSAGES", &mut limits.rate_limit_messages)?;
+        env_override("RATE_LIMIT_WINDOW_MS", &mut limits.rate_limit_window_ms)?;
+        env_override("MAX_CLIENTS_PER_ROOM", &mut limits.max_clients_per_room)?;
+        env_override("MAX_MESSAGE_SIZE", &mut limits.max_message_size)?;
+        env_override(
+            "MAX_CHAT_MESSAGE_LENGTH",
+            &mut limits.max_chat_message_length,
+        )?;
+        env_override("MAX_INVITES_PER_ROOM", &mut limits.max_invites_per_room)?;
+        env_override("MAX_QUEUE_LENGTH", &mut limits.max_queue_length)?;
+        env_override(
+            "MAX_CONNECTIONS_PER_USER",
+            &mut limits.max_connections_per_user,
+        )?;
+        env_override("MAX_CONNECTIONS_PER_IP", &mut limits.max_connections_per_ip)?;
+
+        let sync = &mut self.sync;
+        env_override("PLAY_SCHEDULE_MS", &mut sync.play_schedule_ms)?;
+        env_override("CONTROL_SCHEDULE_MS", &mut sync.control_schedule_ms)?;
+        env_override("SCHEDULE_MARGIN_MS", &mut sync.schedule_margin_ms)?;
+        env_override("SCHEDULE_FLOOR_MS", &mut sync.schedule_floor_ms)?;
+        env_override("SCHEDULE_CEILING_MS", &mut sync.schedule_ceiling_ms)?;
+        env_override(
+            "MIN_STATE_UPDATE_INTERVAL_MS",
+            &mut sync.min_state_update_interval_ms,
+        )?;
+        env_override(
+            "POSITION_JITTER_THRESHOLD",
+            &mut sync.position_jitter_threshold,
+        )?;
+        env_override("COMMAND_COOLDOWN_MS", &mut sync.command_cooldown_ms)?;
+
+        env_override("RESUME_GRACE_MS", &mut self.session.resume_grace_ms)?;
+        env_override("TOKEN_WARNING_MS", &mut self.session.token_warning_ms)?;
+        env_override("TOKEN_GRACE_MS", &mut self.session.token_grace_ms)?;
+        env_override("SNAPSHOT_PATH", &mut self.persistence.snapshot_path)?;
+        env_override(
+            "SNAPSHOT_INTERVAL_SECS",
+            &mut self.persistence.snapshot_interval_secs,
+        )?;
+        env_override("CLUSTER_BACKEND", &mut self.cluster.backend)?;
+        env_override("REDIS_URL", &mut self.cluster.redis_url)?;
+        env_override("NODE_ID", &mut self.cluster.node_id)?;
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.server.port == 0 {
+            return Err("server.port must be non-zero".to_string());
+        }
+        if self
+            .server
+            .bind_address
+            .parse::<std::net::IpAddr>()
+            .is_err()
+        {
+            return Err(format!(
+                "server.bind_address is not an IP address: {}",
+                self.server.bind_address
+            ));
+        }
+        self.log_level()?;
+        let limits = &self.limits;
+        if limits.rate_limit_messages == 0
+            || limits.rate_limit_window_ms == 0
+            || limits.max_clients_per_room == 0
+            || limits.max_message_size == 0
+            || limits.max_chat_message_length == 0
+        {
+            return Err("limits must be greater than zero".to_string());
+        }
+        if self.sync.schedule_ceiling_ms < self.sync.schedule_floor_ms {
+            return Err("sync.schedule_ceiling_ms must be >= sync.schedule_floor_ms".to_string());
+        }
+        if !(self.sync.position_jitter_threshold.is_finite()
+            && self.sync.position_jitter_threshold >= 0.0)
+        {
+            return Err("sync.position_jitter_threshold must be a non-negative number".to_string());
+        }
+        if self.persistence.snapshot_interval_secs == 0 {
+            return Err("persistence.snapshot_interval_secs must be greater than zero".to_string());
+        }
+        if self.cluster.backend == BackendKind::Redis && self.cluster.redis_url.is_empty() {
+            return Err("cluster.redis_url is required for the redis backend".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn log_level(&self) -> Result<LevelFilter, String> {
+        LevelFilter::from_str(&self.server.log_level)
+            .map_err(|_| format!("Invalid server.log_level: {}", self.server.log_level))
+    }
+
+    /// Socket address to listen on (validated by `validate`).
+    pub fn socket_addr(&self) -> std::net::SocketAddr {
+        let ip = self
+            .server
+            .bind_address
+            .parse()
+            .unwrap_or(std::net::Ipv4Addr::UNSPECIFIED.into());
+        std::net::SocketAddr::new(ip, self.server.port)
+    }
+}
+
+/// Live configuration shared by all connections. Readers take a cheap
+/// snapshot with `get`; a SIGHUP reload swaps in a new one with `set`.
+#[derive(Clone)]
+pub struct SharedConfig(Arc<RwLock<Arc<Config>>>);
+
+impl SharedConfig {
+    pub fn new(config: Config) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(config))))
+    }
+
+    pub fn get(&self) -> Arc<Config> {
+        // A poisoned lock still holds a valid config (writes are a single swap)
+        match self.0.read() {
+            Ok(guard) => guard.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    /// Applies a reloaded config. Structural settings (bind address, port)
+    /// cannot change without a restart, so the running values are kept;
+    /// returns the names of settings that were ignored for that reason.
+    pub fn reload(&self, mut new: Config) -> Vec<&'static str> {
+        let current = self.get();
+        let mut ignored = Vec::new();
+        if new.server.bind_address != current.server.bind_address {
+            new.server.bind_address = current.server.bind_address.clone();
+            ignored.push("server.bind_address");
+        }
+        if new.server.port != current.server.port {
+            new.server.port = current.server.port;
+            ignored.push("server.port");
+        }
+        if new.cluster != current.cluster {
+            new.cluster = current.cluster.clone();
+            ignored.push("cluster");
+        }
+        let mut guard = match self.0.write() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        *guard = Arc::new(new);
+        ignored
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_defaults_are_valid() {
+        let config = Config::default();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.socket_addr().to_string(), "0.0.0.0:3000");
+        assert_eq!(config.sync.schedule(), ScheduleConfig::default());
+    }
+
+    #[test]
+    fn test_partial_toml_keeps_defaults() {
+        let config = Config::from_toml(
+            r#"
+            [server]
+            port = 8080
+            allowed_origins = ["https://jellyfin.example.com"]
+            trusted_proxies = ["10.0.0.2", "::1"]
+
+            [limits]
+            max_clients_per_room = 5
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.server.port, 8080);
+        assert_eq!(config.server.bind_address, "0.0.0.0");
+        assert_eq!(config.limits.max_clients_per_room, 5);
+        assert_eq!(config.limits.rate_limit_messages, 30);
+        assert_eq!(
+            config.server.trusted_proxies,
+            [
+                "10.0.0.2".parse::<IpAddr>().unwrap(),
+                "::1".parse().unwrap()
+            ]
+        );
+        assert_eq!(config.sync, SyncConfig::default());
+    }
+
+    #[test]
+    fn test_example_file_matches_defaults() {
+        let config = Config::from_toml(include_str!("../config.example.toml")).unwrap();
+        assert_eq!(config, Config::default());
+    }
+
+    #[test]
+    fn test_unknown_keys_rejected() {
+        assert!(Config::from_toml("[limits]\nmax_clients = 5\n").is_err());
+        assert!(Config::from_toml("[unknown]\n").is_err());
+        assert!(Config::from_toml("[server]\ntrusted_proxies = [\"proxy\"]\n").is_err());
+    }
+
+    #[test]
+    fn test_validate_rejects_bad_values() {
+        let mut config = Config::default();
+        config.sync.schedule_ceiling_ms = 50;
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.server.log_level = "loud".to_string();
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.limits.rate_limit_messages = 0;
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.server.bind_address = "localhost:3000".to_string();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_reload_keeps_structural_settings() {
+        let shared = SharedConfig::new(Config::default());
+        let mut new = Config::default();
+        new.server.port = 4000;
+        new.limits.max_clients_per_room = 8;
+        new.server.allowed_origins = vec!["https://example.com".to_string()];
+
+        assert_eq!(shared.reload(new), ["server.port"]);
+        let config = shared.get();
+        assert_eq!(config.server.port, 3000);
+        assert_eq!(config.limits.max_clients_per_room, 8);
+        assert_eq!(config.server.allowed_origins, ["https://example.com"]);
+    }
+}
@@ -0,0 +1,192 @@
+use dashmap::DashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+/// Address a connection is counted against. Behind a trusted proxy this is
+/// the nearest `X-Forwarded-For` hop that isn't itself a trusted proxy;
+/// the header is ignored from any other peer, since clients can forge it.
+pub fn client_ip(
+    peer: Option<SocketAddr>,
+    forwarded_for: Option<&str>,
+    trusted_proxies: &[IpAddr],
+) -> Option<IpAddr> {
+    let mut ip = peer?.ip();
+    if !trusted_proxies.contains(&ip) {
+        return Some(ip);
+    }
+    // Each proxy appends the address it received from, so walk right to left
+    for hop in forwarded_for.unwrap_or_default().rsplit(',') {
+        let Ok(hop) = hop.trim().parse::<IpAddr>() else {
+            break;
+        };
+        ip = hop;
+        if !trusted_proxies.contains(&ip) {
+            break;
+        }
+    }
+    Some(ip)
+}
+
+/// Open WebSocket connections per client address, checked before the
+/// upgrade, and per authenticated user, checked at `auth`. Each connection
+/// holds a `ConnectionSlot` for every limit it counts against.
+#[derive(Default)]
+pub struct ConnectionLimiter {
+    per_ip: DashMap<IpAddr, usize>,
+    per_user: DashMap<String, usize>,
+}
+
+impl ConnectionLimiter {
+    /// Takes a slot for `ip`, or None if it already has `max` connections
+    /// (0 = unlimited).
+    pub fn acquire(self: &Arc<Self>, ip: IpAddr, max: usize) -> Option<ConnectionSlot> {
+        take(&self.per_ip, ip, max)?;
+        Some(ConnectionSlot {
+            limiter: self.clone(),
+            key: SlotKey::Ip(ip),
+        })
+    }
+
+    /// Takes a slot for user `sub` (the token's subject), or None if it
+    /// already has `max` connections (0 = unlimited). Checked and taken in
+    /// one step, so concurrent logins can't both squeeze in.
+    pub fn acquire_user(self: &Arc<Self>, sub: &str, max: usize) -> Option<ConnectionSlot> {
+        take(&self.per_user, sub.to_string(), max)?;
+        Some(ConnectionSlot {
+            limiter: self.clone(),
+            key: SlotKey::User(sub.to_string()),
+        })
+    }
+}
+
+/// Counts one more connection under `key` unless it is at `max`.
+fn take<K: Eq + Hash>(counts: &DashMap<K, usize>, key: K, max: usize) -> Option<()> {
+    // The entry stays locked between the check and the increment
+    let mut count = counts.entry(key).or_insert(0);
+    if max > 0 && *count >= max {
+        return None;
+    }
+    *count += 1;
+    Some(())
+}
+
+fn release<K: Eq + Hash>(counts: &DashMap<K, usize>, key: &K) {
+    if let Some(mut count) = counts.get_mut(key) {
+        *count = count.saturating_sub(1);
+    }
+    counts.remove_if(key, |_, count| *count == 0);
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum SlotKey {
+    Ip(IpAddr),
+    User(String),
+}
+
+/// A counted connection; released when dropped.
+pub struct ConnectionSlot {
+    limiter: Arc<ConnectionLimiter>,
+    key: SlotKey,
+}
+
+impl ConnectionSlot {
+    /// Whether this is a slot of user `sub`.
+    pub fn is_user(&self, sub: &str) -> bool {
+        matches!(&self.key, SlotKey::User(user) if user == sub)
+    }
+}
+
+impl fmt::Debug for ConnectionSlot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ConnectionSlot").field(&self.key).finish()
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        match &self.key {
+            SlotKey::Ip(ip) => release(&self.limiter.per_ip, ip),
+            SlotKey::User(sub) => release(&self.limiter.per_user, sub),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let peer = Some("10.0.0.2:4000".parse().unwrap());
+        let proxies = [ip("10.0.0.2"), ip("10.0.0.3")];
+
+        // Untrusted peers can't pick their address
+        assert_eq!(
+            client_ip(peer, Some("198.51.100.7"), &[]),
+            Some(ip("10.0.0.2"))
+        );
+        assert_eq!(
+            client_ip(peer, Some("198.51.100.7"), &proxies),
+            Some(ip("198.51.100.7"))
+        );
+        // Hops added by trusted proxies are skipped; anything left of the
+        // first untrusted hop was written by the client
+        assert_eq!(
+            client_ip(peer, Some("1.2.3.4, 198.51.100.7, 10.0.0.3"), &proxies),
+            Some(ip("198.51.100.7"))
+        );
+        assert_eq!(
+            client_ip(peer, Some("garbage, 10.0.0.3"), &proxies),
+            Some(ip("10.0.0.3"))
+        );
+        assert_eq!(client_ip(peer, None, &proxies), Some(ip("10.0.0.2")));
+        assert_eq!(client_ip(None, Some("198.51.100.7"), &proxies), None);
+    }
+
+    #[test]
+    fn test_limiter_releases_slots() {
+        let limiter = Arc::new(ConnectionLimiter::default());
+        let (a, b) = (ip("198.51.100.7"), ip("198.51.100.8"));
+
+        let first = limiter.acquire(a, 2).unwrap();
+        let second = limiter.acquire(a, 2).unwrap();
+        assert!(limiter.acquire(a, 2).is_none());
+        assert!(limiter.acquire(b, 2).is_some());
+        assert!(limiter.acquire(a, 0).is_some());
+
+        drop(first);
+        assert_eq!(*limiter.per_ip.get(&a).unwrap(), 1);
+        let third = limiter.acquire(a, 2).unwrap();
+        drop(second);
+        drop(third);
+        assert!(limiter.per_ip.is_empty());
+    }
+
+    #[test]
+    fn test_user_slots_are_atomic() {
+        let limiter = Arc::new(ConnectionLimiter::default());
+        // Logins racing for the last slots: exactly `max` get one
+        let slots: Vec<_> = std::thread::scope(|scope| {
+            let racers: Vec<_> = (0..16)
+                .map(|_| scope.spawn(|| limiter.acquire_user("bob", 3)))
+                .collect();
+            racers
+                .into_iter()
+                .filter_map(|racer| racer.join().unwrap())
+                .collect()
+        });
+        assert_eq!(slots.len(), 3);
+        assert!(slots[0].is_user("bob"));
+        assert!(!slots[0].is_user("alice"));
+        assert!(limiter.acquire_user("alice", 3).is_some());
+
+        drop(slots);
+        assert!(limiter.per_user.is_empty());
+    }
+}
@@ -0,0 +1,122 @@
+use crate::types::{ErrorPayload, ReplyTo, ServerMessage, WsMessage};
+use crate::utils::now_ms;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+/// Stable, machine-readable reason sent as `payload.code` of `error` messages.
+/// Clients branch on these; the human `message` may change at any time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    RateLimited,
+    MessageTooLarge,
+    InvalidMessage,
+    UnknownMessageType,
+    InvalidRequest,
+    AuthFailed,
+    AuthRequired,
+    TooManyConnections,
+    TokenExpired,
+    PermissionDenied,
+    UnsupportedProtocol,
+    ResumeFailed,
+    RoomRequired,
+    RoomNotFound,
+    NotInRoom,
+    NotHost,
+    RoomFull,
+    Banned,
+    InvalidPassword,
+    PasswordTooLong,
+    InviteRequired,
+    InvalidInvite,
+    TooManyInvites,
+    QueueFull,
+    QueueEmpty,
+    Muted,
+    ChatEmpty,
+    ChatTooLong,
+    InternalError,
+}
+
+/// An error reported to a client: a code, a readable message and, for
+/// `rate_limited`, when to try again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerError {
+    pub code: ErrorCode,
+    pub message: Cow<'static, str>,
+    pub retry_after_ms: Option<u64>,
+}
+
+impl ServerError {
+    pub fn new(code: ErrorCode, message: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            retry_after_ms: None,
+        }
+    }
+
+    pub fn retry_after(mut self, ms: u64) -> Self {
+        self.retry_after_ms = Some(ms);
+        self
+    }
+
+    /// The `error` message for `client_id`. `request` is the message that
+    /// failed, when it could be parsed.
+    pub fn to_message(
+        &self,
+        client_id: &str,
+        room: Option<&str>,
+        request: Option<&ReplyTo>,
+    ) -> WsMessage {
+        WsMessage {
+            body: ServerMessage::Error(ErrorPayload {
+                code: self.code,
+                message: self.message.clone(),
+                request: request.map(|r| r.msg_type.clone()),
+                retry_after_ms: self.retry_after_ms,
+            }),
+            id: request.and_then(|r| r.id.clone()),
+            room: room.map(str::to_string),
+            client: Some(client_id.to_string()),
+            ts: now_ms(),
+            server_ts: Some(now_ms()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::ClientMessageType;
+
+    #[test]
+    fn test_error_payload() {
+        let msg = ServerError::new(ErrorCode::RoomFull, "Room is full").to_message(
+            "client",
+            Some("room"),
+            Some(&ReplyTo {
+                msg_type: ClientMessageType::JoinRoom,
+                id: Some("7".to_string()),
+            }),
+        );
+        assert_eq!(msg.id.as_deref(), Some("7"));
+        assert_eq!(msg.room.as_deref(), Some("room"));
+        let json = serde_json::to_value(&msg).unwrap();
+        assert_eq!(json["type"], "error");
+        assert_eq!(
+            json["payload"],
+            serde_json::json!({ "code": "room_full", "message": "Room is full", "request": "join_room" })
+        );
+
+        let msg = ServerError::new(ErrorCode::RateLimited, "Rate limit exceeded")
+            .retry_after(250)
+            .to_message("client", None, None);
+        assert_eq!(
+            serde_json::to_value(&msg).unwrap()["payload"],
+            serde_json::json!({ "code": "rate_limited", "message": "Rate limit exceeded", "retry_after_ms": 250 })
+        );
+    }
+}
@@ -1,33 +1,76 @@
+mod actor;
+mod admin;
 mod auth;
+mod backend;
+mod chat;
+mod cluster;
+mod config;
+mod connections;
+mod error;
+mod jwks;
 mod messaging;
+mod metrics;
+mod persistence;
+mod protocol;
+mod redis_backend;
 mod room;
+mod timesync;
+mod tokens;
 mod types;
 mod utils;
 mod ws;
 
-use crate::auth::JwtConfig;
+use crate::auth::{bearer_matches, JwtConfig};
+use crate::cluster::{Cluster, RemoteRooms};
+use crate::config::{Config, SharedConfig};
+use crate::connections::ConnectionLimiter;
+use crate::jwks::KeySource;
+use crate::metrics::METRICS;
+use crate::tokens::TokenRegistry;
 use crate::types::{Clients, Rooms};
 use crate::utils::now_ms;
-use log::{info, warn};
+use log::{info, warn, LevelFilter};
+use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
-use warp::Filter;
+use warp::{Filter, Reply};
 
 // Zombie connection detection
 const ZOMBIE_CHECK_INTERVAL_SECS: u64 = 30;
 const ZOMBIE_TIMEOUT_MS: u64 = 60_000; // 60 seconds without message = zombie
 
-fn get_allowed_origins() -> Vec<String> {
-    std::env::var("ALLOWED_ORIGINS")
-        .unwrap_or_else(|_| "http://localhost:8096,https://localhost:8096".to_string())
-        .split(',')
-        .map(|s| s.trim().to_string())
-        .filter(|s| !s.is_empty())
-        .collect()
+// How often client token expiry is checked (`token_expiring`, demotion)
+const TOKEN_CHECK_INTERVAL_SECS: u64 = 10;
+
+const DEFAULT_JWKS_REFRESH_SECS: u64 = 300;
+
+/// Bearer token for the `/admin` API (unset = API disabled)
+fn get_admin_token() -> Option<String> {
+    std::env::var("ADMIN_TOKEN")
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/// Optional bearer token required to scrape `/metrics` (unset = open)
+fn get_metrics_token() -> Option<String> {
+    std::env::var("METRICS_TOKEN")
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
 }
 
-// P-RS10 fix: Accept Arc<Vec<String>> to avoid cloning on each request
-fn is_origin_allowed(origin: &str, allowed: &Arc<Vec<String>>) -> bool {
+/// How often a JWKS key set is re-read to pick up rotated keys
+fn get_jwks_refresh_interval() -> Duration {
+    let secs = std::env::var("JWT_JWKS_REFRESH_SECS")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .filter(|&secs| secs > 0)
+        .unwrap_or(DEFAULT_JWKS_REFRESH_SECS);
+    Duration::from_secs(secs)
+}
+
+fn is_origin_allowed(origin: &str, allowed: &[String]) -> bool {
     if allowed.iter().any(|o| o == "*") {
         // Security warning: wildcard allows all origins
         warn!("SECURITY: Wildcard origin (*) configured - ALL origins allowed. This disables CORS protection!");
@@ -36,16 +79,143 @@ fn is_origin_allowed(origin: &str, allowed: &Arc<Vec<String>>) -> bool {
     allowed.iter().any(|o| o == origin)
 }
 
+/// Logs everything the global max level lets through, so the level from the
+/// config can be raised or lowered on reload. Module directives in RUST_LOG
+/// (e.g. `warp=warn`) still apply.
+fn init_logger(level: LevelFilter) {
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(LevelFilter::Trace);
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        if LevelFilter::from_str(filters.trim()).is_err() {
+            builder.parse_filters(&filters);
+        }
+    }
+    builder.init();
+    log::set_max_level(level);
+}
+
+/// Re-reads the config file and environment. Invalid configs are rejected
+/// as a whole and the running one is kept.
+fn reload_config(config: &SharedConfig) {
+    let path = Config::path();
+    match Config::load(&path) {
+        Ok(new) => {
+            let level = new.log_level().unwrap_or(LevelFilter::Info);
+            for name in config.reload(new) {
+                warn!("{} changed; restart the server to apply it", name);
+            }
+            log::set_max_level(level);
+            info!("Configuration reloaded from {}", path);
+        }
+        Err(e) => warn!(
+            "Configuration reload failed, keeping current settings: {}",
+            e
+        ),
+    }
+}
+
+async fn restore_rooms(
+    path: &str,
+    config: &Config,
+    clients: &Clients,
+    rooms: &Rooms,
+    tokens: &TokenRegistry,
+    remote_rooms: &Arc<RemoteRooms>,
+) {
+    let mut snapshot = match persistence::load(path) {
+        Ok(Some(snapshot)) => snapshot,
+        Ok(None) => return,
+        Err(e) => {
+            warn!("Ignoring room snapshot: {}", e);
+            return;
+        }
+    };
+    let now = now_ms();
+    tokens.restore(snapshot.take_tokens(), now);
+    let grace_ms = config.session.resume_grace_ms;
+    if grace_ms == 0 {
+        warn!("Room persistence needs session resume (resume_grace_ms > 0); not restoring");
+        return;
+    }
+    let restored = snapshot.restore(clients, rooms, now);
+    for client_id in &restored {
+        room::expire_session_after(client_id, now, clients, rooms, remote_rooms, grace_ms);
+    }
+    info!(
+        "Restored {} rooms ({} sessions) from {}",
+        rooms.len(),
+        restored.len(),
+        path
+    );
+}
+
 #[tokio::main]
 async fn main() {
-    // Initialize logger with default level INFO (can override with RUST_LOG env var)
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    if std::env::args().nth(1).as_deref() == Some("--print-schema") {
+        println!("{:#}", protocol::schema());
+        return;
+    }
+
+    // File + env config; the logger is not up yet, so report errors on stderr
+    let config_path = Config::path();
+    let initial_config = match Config::load(&config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    init_logger(initial_config.log_level().unwrap_or(LevelFilter::Info));
+    let config = SharedConfig::new(initial_config.clone());
 
     let jwt_config = Arc::new(JwtConfig::from_env());
-    // P-RS10 fix: Wrap in Arc to avoid cloning on each request
-    let allowed_origins = Arc::new(get_allowed_origins());
+    // Public keys must be loaded before the first `auth`; a JWKS is then
+    // re-read periodically so rotated keys are picked up
+    if let Some(keys) = jwt_config.public_keys.clone() {
+        match keys.refresh().await {
+            Ok(count) => info!("JWT verification keys: {} from {:?}", count, keys.source()),
+            Err(e) => {
+                log::error!("Cannot load JWT verification keys: {}", e);
+                std::process::exit(1);
+            }
+        }
+        if matches!(keys.source(), KeySource::Jwks(_)) {
+            let interval = get_jwks_refresh_interval();
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(interval).await;
+                    if let Err(e) = keys.refresh().await {
+                        warn!("JWKS refresh failed, keeping current keys: {}", e);
+                    }
+                }
+            });
+        }
+    }
+    let metrics_token = Arc::new(get_metrics_token());
+    let admin_token = get_admin_token().map(Arc::new);
 
-    info!("Allowed origins: {:?}", allowed_origins);
+    info!("Configuration: {}", config_path);
+    info!(
+        "Allowed origins: {:?}",
+        initial_config.server.allowed_origins
+    );
+    info!(
+        "Admin API: {}",
+        if admin_token.is_some() {
+            "ENABLED"
+        } else {
+            "DISABLED (set ADMIN_TOKEN to enable)"
+        }
+    );
+    info!(
+        "Session resume grace period: {} ms",
+        initial_config.session.resume_grace_ms
+    );
+    let schedule = initial_config.sync.schedule();
+    info!(
+        "Command scheduling: RTT + {} ms, bounded to {}-{} ms",
+        schedule.margin_ms, schedule.floor_ms, schedule.ceiling_ms
+    );
     info!(
         "JWT authentication: {}",
         if jwt_config.enabled {
@@ -55,13 +225,76 @@ async fn main() {
         }
     );
 
-    let clients: Clients = Arc::new(tokio::sync::RwLock::new(std::collections::HashMap::new()));
-    let rooms: Rooms = Arc::new(tokio::sync::RwLock::new(std::collections::HashMap::new()));
+    let cluster = match Cluster::connect(&initial_config.cluster).await {
+        Ok(cluster) => Arc::new(cluster),
+        Err(e) => {
+            log::error!("Cannot connect the cluster backend: {}", e);
+            std::process::exit(1);
+        }
+    };
+    info!(
+        "Cluster backend: {:?} (node {})",
+        initial_config.cluster.backend, cluster.node_id
+    );
+
+    let clients: Clients = Default::default();
+    let rooms: Rooms = Default::default();
+
+    // Bring back the rooms saved before the last shutdown; their members get
+    // the usual resume grace period to reconnect
+    let snapshot_path = initial_config.persistence.snapshot_path.clone();
+    if !snapshot_path.is_empty() {
+        restore_rooms(
+            &snapshot_path,
+            &initial_config,
+            &clients,
+            &rooms,
+            &jwt_config.tokens,
+            cluster.remote_rooms(),
+        )
+        .await;
+
+        let config = config.clone();
+        let clients = clients.clone();
+        let rooms = rooms.clone();
+        let tokens = jwt_config.tokens.clone();
+        tokio::spawn(async move {
+            loop {
+                let interval = config.get().persistence.snapshot_interval_secs;
+                tokio::time::sleep(Duration::from_secs(interval)).await;
+                let path = config.get().persistence.snapshot_path.clone();
+                if path.is_empty() {
+                    continue;
+                }
+                if let Err(e) = persistence::save(&path, &clients, &rooms, &tokens, now_ms()).await
+                {
+                    warn!("Failed to save room snapshot: {}", e);
+                }
+            }
+        });
+    }
+
+    // Open connections per client address and per user
+    let limiter = Arc::new(ConnectionLimiter::default());
+    if let Err(e) = cluster
+        .start(
+            clients.clone(),
+            rooms.clone(),
+            jwt_config.clone(),
+            config.clone(),
+            limiter.clone(),
+        )
+        .await
+    {
+        log::error!("Cannot start the cluster backend: {}", e);
+        std::process::exit(1);
+    }
 
     // Spawn zombie connection cleanup task
     {
         let clients_clone = clients.clone();
         let rooms_clone = rooms.clone();
+        let remote_rooms = cluster.remote_rooms().clone();
         tokio::spawn(async move {
             loop {
                 tokio::time::sleep(Duration::from_secs(ZOMBIE_CHECK_INTERVAL_SECS)).await;
@@ -69,73 +302,147 @@ async fn main() {
                 let mut zombies = Vec::new();
 
                 // Find zombie clients
-                {
-                    let locked_clients = clients_clone.read().await;
-                    for (id, client) in locked_clients.iter() {
-                        if now - client.last_seen > ZOMBIE_TIMEOUT_MS {
-                            zombies.push(id.clone());
-                        }
+                for client in clients_clone.iter() {
+                    // Sessions awaiting a resume are expired by their own grace timer
+                    if client.disconnected_at.is_some() {
+                        continue;
+                    }
+                    if now - client.last_seen > ZOMBIE_TIMEOUT_MS {
+                        zombies.push(client.key().clone());
                     }
                 }
 
                 // Disconnect zombies
                 for id in zombies {
                     warn!("Removing zombie connection: {}", id);
-                    room::handle_disconnect(&id, &clients_clone, &rooms_clone).await;
+                    METRICS.zombie_evicted();
+                    room::handle_disconnect(&id, &clients_clone, &rooms_clone, &remote_rooms).await;
                 }
             }
         });
     }
 
-    let clients_filter = warp::any().map(move || clients.clone());
-    let rooms_filter = warp::any().map(move || rooms.clone());
+    // Spawn token expiry task: warns before a JWT lapses, demotes after the grace period
+    if jwt_config.enabled {
+        let clients = clients.clone();
+        let rooms = rooms.clone();
+        let cluster = cluster.clone();
+        let config = config.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(TOKEN_CHECK_INTERVAL_SECS)).await;
+                let session = config.get().session.clone();
+                ws::expire_tokens(&clients, &rooms, &cluster, &session, now_ms()).await;
+            }
+        });
+    }
+
+    let clients_filter = {
+        let clients = clients.clone();
+        warp::any().map(move || clients.clone())
+    };
+    let rooms_filter = {
+        let rooms = rooms.clone();
+        warp::any().map(move || rooms.clone())
+    };
     let jwt_filter = {
         let config = jwt_config.clone();
         warp::any().map(move || config.clone())
     };
 
-    // P-RS10 fix: Clone Arc (cheap) instead of Vec (expensive) on each request
-    let allowed_origins_filter = {
-        let origins = allowed_origins.clone();
-        warp::any().map(move || origins.clone())
+    let cluster_filter = {
+        let cluster = cluster.clone();
+        warp::any().map(move || cluster.clone())
     };
 
-    // Origin validation filter
+    // P-RS10 fix: Clone Arc (cheap) instead of the config on each request
+    let config_filter = {
+        let config = config.clone();
+        warp::any().map(move || config.clone())
+    };
+
+    // Origin validation filter (reads the live origins so a reload applies)
     let origin_check = warp::header::optional::<String>("origin")
-        .and(allowed_origins_filter.clone())
-        .and_then(
-            |origin: Option<String>, allowed: Arc<Vec<String>>| async move {
-                match origin {
-                    Some(ref o) if is_origin_allowed(o, &allowed) => Ok(()),
-                    Some(o) => {
-                        warn!("Rejected connection from origin: {}", o);
-                        Err(warp::reject::custom(OriginRejected))
-                    }
-                    None => Ok(()), // Allow connections without Origin header (non-browser clients)
+        .and(config_filter.clone())
+        .and_then(|origin: Option<String>, config: SharedConfig| async move {
+            match origin {
+                Some(ref o) if is_origin_allowed(o, &config.get().server.allowed_origins) => Ok(()),
+                Some(o) => {
+                    warn!("Rejected connection from origin: {}", o);
+                    Err(warp::reject::custom(OriginRejected))
                 }
-            },
-        )
+                None => Ok(()), // Allow connections without Origin header (non-browser clients)
+            }
+        })
         .untuple_one();
 
-    // WebSocket route with Origin validation (auth via message after connection)
+    let limiter_filter = {
+        let limiter = limiter.clone();
+        warp::any().map(move || limiter.clone())
+    };
+
+    // WebSocket route with Origin validation (auth via message after connection).
+    // The per-address limit is checked before the upgrade; the per-user one
+    // on `auth`, since the token only arrives after it.
     let ws_route = warp::path("ws")
         .and(origin_check)
         .and(warp::ws())
+        .and(warp::addr::remote())
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .and(limiter_filter)
         .and(clients_filter)
         .and(rooms_filter)
         .and(jwt_filter.clone())
+        .and(config_filter)
+        .and(cluster_filter)
         .map(
-            |ws: warp::ws::Ws, clients, rooms, jwt_config: Arc<JwtConfig>| {
-                ws.on_upgrade(move |socket| {
-                    ws::client_connection(socket, clients, rooms, jwt_config)
+            |ws: warp::ws::Ws,
+             peer,
+             forwarded_for: Option<String>,
+             limiter: Arc<ConnectionLimiter>,
+             clients,
+             rooms,
+             jwt_config: Arc<JwtConfig>,
+             config: SharedConfig,
+             cluster| {
+                let live = config.get();
+                let ip = connections::client_ip(
+                    peer,
+                    forwarded_for.as_deref(),
+                    &live.server.trusted_proxies,
+                );
+                let slot = match ip {
+                    Some(ip) => match limiter.acquire(ip, live.limits.max_connections_per_ip) {
+                        Some(slot) => Some(slot),
+                        None => {
+                            warn!("Rejected connection from {}: too many connections", ip);
+                            METRICS.connection_rejected();
+                            return warp::reply::with_status(
+                                "Too many connections",
+                                warp::http::StatusCode::TOO_MANY_REQUESTS,
+                            )
+                            .into_response();
+                        }
+                    },
+                    None => None,
+                };
+                ws.on_upgrade(move |socket| async move {
+                    let _slot = slot;
+                    ws::client_connection(
+                        socket, clients, rooms, jwt_config, config, cluster, limiter,
+                    )
+                    .await
                 })
+                .into_response()
             },
         );
 
-    // Health check endpoint with CORS
+    // Health check endpoint with CORS (origins as of startup)
     let cors = warp::cors()
         .allow_origins(
-            allowed_origins
+            initial_config
+                .server
+                .allowed_origins
                 .iter()
                 .map(|s| s.as_str())
                 .collect::<Vec<_>>(),
@@ -154,7 +461,58 @@ async fn main() {
         })
         .with(cors);
 
-    let routes = ws_route.or(health_route);
+    let admin_route = admin::routes(
+        clients.clone(),
+        rooms.clone(),
+        jwt_config.tokens.clone(),
+        cluster.clone(),
+        admin_token,
+    );
+    let (snapshot_clients, snapshot_rooms, snapshot_tokens) =
+        (clients.clone(), rooms.clone(), jwt_config.tokens.clone());
+
+    // Prometheus metrics (text exposition format)
+    let metrics_route = warp::path("metrics")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(jwt_filter.clone())
+        .then(
+            move |authorization: Option<String>, jwt_config: Arc<JwtConfig>| {
+                let clients = clients.clone();
+                let rooms = rooms.clone();
+                let metrics_token = metrics_token.clone();
+                async move {
+                    if let Some(token) = metrics_token.as_deref() {
+                        if !bearer_matches(authorization.as_deref(), token) {
+                            return warp::reply::with_status(
+                                String::new(),
+                                warp::http::StatusCode::UNAUTHORIZED,
+                            );
+                        }
+                    }
+                    let body = METRICS.render(&clients, &rooms, jwt_config.enabled);
+                    warp::reply::with_status(body, warp::http::StatusCode::OK)
+                }
+            },
+        )
+        .map(|reply| warp::reply::with_header(reply, "content-type", "text/plain; version=0.0.4"));
+
+    let routes = ws_route.or(health_route).or(metrics_route).or(admin_route);
+
+    // Reload non-structural settings on SIGHUP without dropping connections
+    #[cfg(unix)]
+    {
+        let config = config.clone();
+        tokio::spawn(async move {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut sighup =
+                signal(SignalKind::hangup()).expect("Failed to register SIGHUP handler");
+            while sighup.recv().await.is_some() {
+                info!("Received SIGHUP, reloading configuration...");
+                reload_config(&config);
+            }
+        });
+    }
 
     // Graceful shutdown support (fixes M-Q18)
     let (tx, rx) = tokio::sync::oneshot::channel::<()>();
@@ -183,13 +541,28 @@ async fn main() {
         let _ = tx.send(());
     });
 
-    info!("OpenWatchParty server listening on 0.0.0.0:3000");
-    let (_, server) =
-        warp::serve(routes).bind_with_graceful_shutdown(([0, 0, 0, 0], 3000), async {
-            rx.await.ok();
-        });
+    let addr = initial_config.socket_addr();
+    info!("OpenWatchParty server listening on {}", addr);
+    let (_, server) = warp::serve(routes).bind_with_graceful_shutdown(addr, async {
+        rx.await.ok();
+    });
 
     server.await;
+
+    let path = config.get().persistence.snapshot_path.clone();
+    if !path.is_empty() {
+        let saved = persistence::save(
+            &path,
+            &snapshot_clients,
+            &snapshot_rooms,
+            &snapshot_tokens,
+            now_ms(),
+        );
+        match saved.await {
+            Ok(count) => info!("Saved {} rooms to {}", count, path),
+            Err(e) => warn!("Failed to save room snapshot: {}", e),
+        }
+    }
     info!("Server shutdown complete");
 }
 
@@ -1,40 +1,85 @@
-use crate::types::{Client, Clients, Room, Rooms, WsMessage};
+use crate::actor::RoomRegistry;
+use crate::cluster::RemoteRooms;
+use crate::metrics::METRICS;
+use crate::room::extrapolate;
+use crate::types::{ClientMap, PlaybackState, Room, RoomVisibility, ServerMessage, WsMessage};
 use crate::utils::now_ms;
-use std::collections::HashMap;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 
-pub async fn send_room_list(client_id: &str, clients: &Clients, rooms: &Rooms) {
-    let locked_rooms = rooms.read().await;
-    let list: Vec<serde_json::Value> = locked_rooms.values().map(|r| {
-        serde_json::json!({ "id": r.room_id, "name": r.name, "count": r.clients.len(), "media_id": r.media_id })
-    }).collect();
+/// One `room_list` entry (also what the cluster directory advertises).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct RoomListItem {
+    pub id: String,
+    pub name: String,
+    pub count: usize,
+    pub media_id: Option<String>,
+    pub queue: Vec<String>,
+    pub has_password: bool,
+    pub state: PlaybackState,
+}
+
+impl RoomListItem {
+    /// `state` is the room's raw state; callers extrapolate it as needed.
+    pub fn new(room: &Room, state: PlaybackState) -> Self {
+        Self {
+            id: room.room_id.clone(),
+            name: room.name.clone(),
+            count: room.clients.len(),
+            media_id: room.media_id.clone(),
+            queue: room.queue.clone(),
+            has_password: room.password_hash.is_some(),
+            state,
+        }
+    }
+}
 
+/// Builds the `room_list` payload. Only public rooms are advertised,
+/// including those hosted on other cluster nodes.
+fn room_list_payload(rooms: &RoomRegistry, remote: &RemoteRooms) -> Vec<RoomListItem> {
+    let now = now_ms();
+    let mut list: Vec<RoomListItem> = rooms
+        .summaries()
+        .into_iter()
+        .filter(|s| s.visibility == RoomVisibility::Public)
+        .map(|s| RoomListItem {
+            state: extrapolate(&s.listing.state, s.position_ts, now),
+            ..s.listing
+        })
+        .collect();
+    list.extend(remote.list(now));
+    list
+}
+
+/// Sends the room list to one client; `id` answers its `list_rooms` request.
+pub fn send_room_list(
+    client_id: &str,
+    clients: &ClientMap,
+    rooms: &RoomRegistry,
+    remote: &RemoteRooms,
+    id: Option<String>,
+) {
     let msg = WsMessage {
-        msg_type: "room_list".to_string(),
+        body: ServerMessage::RoomList(room_list_payload(rooms, remote)),
+        id,
         room: None,
         client: None,
-        payload: Some(serde_json::json!(list)),
         ts: now_ms(),
         server_ts: Some(now_ms()),
     };
 
-    let locked_clients = clients.read().await;
-    send_to_client(client_id, &locked_clients, &msg);
+    send_to_client(client_id, clients, &msg);
 }
 
 // P-RS08 fix: Serialize room list once and send to all clients (avoids N serializations)
-pub async fn broadcast_room_list(clients: &Clients, rooms: &Rooms) {
+pub fn broadcast_room_list(clients: &ClientMap, rooms: &RoomRegistry, remote: &RemoteRooms) {
     // Build and serialize message once
     let json = {
-        let locked_rooms = rooms.read().await;
-        let list: Vec<serde_json::Value> = locked_rooms.values().map(|r| {
-            serde_json::json!({ "id": r.room_id, "name": r.name, "count": r.clients.len(), "media_id": r.media_id })
-        }).collect();
-
         let msg = WsMessage {
-            msg_type: "room_list".to_string(),
+            body: ServerMessage::RoomList(room_list_payload(rooms, remote)),
+            id: None,
             room: None,
             client: None,
-            payload: Some(serde_json::json!(list)),
             ts: now_ms(),
             server_ts: Some(now_ms()),
         };
@@ -49,21 +94,26 @@ pub async fn broadcast_room_list(clients: &Clients, rooms: &Rooms) {
     };
 
     // Send pre-serialized message to all clients
-    let locked_clients = clients.read().await;
     let warp_msg = warp::ws::Message::text(json);
-    for client in locked_clients.values() {
+    // Relayed clients get the list from the node holding their socket
+    for client in clients
+        .iter()
+        .filter(|c| c.disconnected_at.is_none() && c.origin_node.is_none())
+    {
         if let Err(e) = client.sender.try_send(Ok(warp_msg.clone())) {
+            METRICS.broadcast_failed();
             log::warn!("Failed to send room list (buffer full or closed): {}", e);
         }
     }
 }
 
-pub fn send_to_client(client_id: &str, clients: &HashMap<String, Client>, msg: &WsMessage) {
+pub fn send_to_client(client_id: &str, clients: &ClientMap, msg: &WsMessage) {
     if let Some(client) = clients.get(client_id) {
         match serde_json::to_string(msg) {
             Ok(json) => {
                 // Use try_send to avoid blocking on full buffer (bounded channel)
                 if let Err(e) = client.sender.try_send(Ok(warp::ws::Message::text(json))) {
+                    METRICS.broadcast_failed();
                     log::warn!(
                         "Failed to send to client {} (buffer full or closed): {}",
                         client_id,
@@ -82,12 +132,7 @@ pub fn send_to_client(client_id: &str, clients: &HashMap<String, Client>, msg: &
     }
 }
 
-pub fn broadcast_to_room(
-    room: &Room,
-    clients: &HashMap<String, Client>,
-    msg: &WsMessage,
-    exclude: Option<&str>,
-) {
+pub fn broadcast_to_room(room: &Room, clients: &ClientMap, msg: &WsMessage, exclude: Option<&str>) {
     let json = match serde_json::to_string(msg) {
         Ok(j) => j,
         Err(e) => {
@@ -105,8 +150,13 @@ pub fn broadcast_to_room(
             continue;
         }
         if let Some(client) = clients.get(client_id) {
+            // Skip sessions held open for a reconnect; their socket is gone
+            if client.disconnected_at.is_some() {
+                continue;
+            }
             // Use try_send to avoid blocking on full buffer (bounded channel)
             if let Err(e) = client.sender.try_send(Ok(warp_msg.clone())) {
+                METRICS.broadcast_failed();
                 log::warn!(
                     "Failed to broadcast to client {} (buffer full or closed): {}",
                     client_id,
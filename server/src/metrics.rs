@@ -0,0 +1,209 @@
+use crate::actor::RoomRegistry;
+use crate::types::{ClientMap, ClientMessageType};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Process-wide counters, exported with room/client gauges on `/metrics`.
+pub static METRICS: Metrics = Metrics::new();
+
+/// Counters updated from the message path. Gauges (clients, rooms) are read
+/// from the shared state at scrape time instead of being tracked here.
+pub struct Metrics {
+    messages: Mutex<BTreeMap<String, u64>>, // Keyed by protocol message type
+    rate_limited: AtomicU64,
+    broadcast_failures: AtomicU64,
+    zombie_evictions: AtomicU64,
+    auth_failures: AtomicU64,
+    connections_rejected: AtomicU64,
+}
+
+impl Metrics {
+    pub const fn new() -> Self {
+        Self {
+            messages: Mutex::new(BTreeMap::new()),
+            rate_limited: AtomicU64::new(0),
+            broadcast_failures: AtomicU64::new(0),
+            zombie_evictions: AtomicU64::new(0),
+            auth_failures: AtomicU64::new(0),
+            connections_rejected: AtomicU64::new(0),
+        }
+    }
+
+    pub fn message_received(&self, msg_type: &ClientMessageType) {
+        // Serialize to get the snake_case wire name used in the protocol docs
+        let name = serde_json::to_value(msg_type)
+            .ok()
+            .and_then(|v| v.as_str().map(str::to_string))
+            .unwrap_or_else(|| "unknown".to_string());
+        if let Ok(mut messages) = self.messages.lock() {
+            *messages.entry(name).or_insert(0) += 1;
+        }
+    }
+
+    pub fn rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn broadcast_failed(&self) {
+        self.broadcast_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn zombie_evicted(&self) {
+        self.zombie_evictions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn auth_failed(&self) {
+        self.auth_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_rejected(&self) {
+        self.connections_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Renders all series in the Prometheus text exposition format.
+    /// A client counts as authenticated only if it presented a valid JWT.
+    pub fn render(&self, clients: &ClientMap, rooms: &RoomRegistry, jwt_enabled: bool) -> String {
+        let mut out = String::new();
+
+        let (mut connected, mut authenticated) = (0, 0);
+        for client in clients.iter().filter(|c| c.disconnected_at.is_none()) {
+            connected += 1;
+            if jwt_enabled && client.authenticated {
+                authenticated += 1;
+            }
+        }
+        let anonymous = connected - authenticated;
+        header(
+            &mut out,
+            "owp_clients_connected",
+            "gauge",
+            "Connected WebSocket clients",
+        );
+        let _ = writeln!(
+            out,
+            "owp_clients_connected{{auth=\"authenticated\"}} {}",
+            authenticated
+        );
+        let _ = writeln!(
+            out,
+            "owp_clients_connected{{auth=\"anonymous\"}} {}",
+            anonymous
+        );
+
+        header(&mut out, "owp_rooms_active", "gauge", "Active rooms");
+        let _ = writeln!(out, "owp_rooms_active {}", rooms.len());
+
+        header(
+            &mut out,
+            "owp_room_participants",
+            "gauge",
+            "Participants per room",
+        );
+        let mut summaries = rooms.summaries();
+        summaries.sort_by(|a, b| a.listing.id.cmp(&b.listing.id));
+        for summary in summaries {
+            let _ = writeln!(
+                out,
+                "owp_room_participants{{room=\"{}\"}} {}",
+                escape_label(&summary.listing.id),
+                summary.listing.count
+            );
+        }
+
+        header(
+            &mut out,
+            "owp_messages_total",
+            "counter",
+            "Client messages received by type",
+        );
+        if let Ok(messages) = self.messages.lock() {
+            for (msg_type, count) in messages.iter() {
+                let _ = writeln!(
+                    out,
+                    "owp_messages_total{{type=\"{}\"}} {}",
+                    escape_label(msg_type),
+                    count
+                );
+            }
+        }
+
+        let counters = [
+            (
+                "owp_rate_limited_total",
+                "Messages dropped by the per-client rate limit",
+                &self.rate_limited,
+            ),
+            (
+                "owp_broadcast_send_failures_total",
+                "Outgoing messages dropped because a client buffer was full or closed",
+                &self.broadcast_failures,
+            ),
+            (
+                "owp_zombie_evictions_total",
+                "Connections removed after going silent",
+                &self.zombie_evictions,
+            ),
+            (
+                "owp_auth_failures_total",
+                "Rejected JWT authentication attempts",
+                &self.auth_failures,
+            ),
+            (
+                "owp_connections_rejected_total",
+                "Connections refused by the per-user or per-address limit",
+                &self.connections_rejected,
+            ),
+        ];
+        for (name, help, value) in counters {
+            header(&mut out, name, "counter", help);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Escapes a label value per the exposition format.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_counters() {
+        let metrics = Metrics::new();
+        metrics.message_received(&ClientMessageType::Ping);
+        metrics.message_received(&ClientMessageType::Ping);
+        metrics.message_received(&ClientMessageType::PlayerEvent);
+        metrics.rate_limited();
+        metrics.auth_failed();
+
+        let out = metrics.render(&ClientMap::new(), &RoomRegistry::default(), true);
+        assert!(out.contains("owp_messages_total{type=\"ping\"} 2\n"));
+        assert!(out.contains("owp_messages_total{type=\"player_event\"} 1\n"));
+        assert!(out.contains("owp_rate_limited_total 1\n"));
+        assert!(out.contains("owp_auth_failures_total 1\n"));
+        assert!(out.contains("owp_zombie_evictions_total 0\n"));
+        assert!(out.contains("owp_clients_connected{auth=\"anonymous\"} 0\n"));
+        assert!(out.contains("owp_rooms_active 0\n"));
+        assert!(out.contains("# TYPE owp_broadcast_send_failures_total counter\n"));
+    }
+
+    #[test]
+    fn test_escape_label() {
+        assert_eq!(escape_label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
@@ -0,0 +1,402 @@
+use crate::auth::Permissions;
+use crate::chat::ChatHistory;
+use crate::protocol::LEGACY_PROTOCOL_VERSION;
+use crate::timesync::ClockSync;
+use crate::tokens::{TokenRegistry, TokenSnapshot};
+use crate::types::{
+    Client, ClientMap, Clients, ControlMode, HostTransferPolicy, PlaybackState, Room,
+    RoomVisibility, Rooms,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io::Write;
+use tokio::sync::mpsc;
+
+// Bumped whenever the snapshot layout changes; other versions are ignored
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// On-disk copy of the rooms and the sessions of their members, written
+/// periodically and on graceful shutdown. Restored members come back as held
+/// sessions (as if their socket had just dropped), so clients reclaim them
+/// with the normal `resume` flow. The token registry is saved along with
+/// them, so used and revoked tokens stay that way across a restart.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    version: u32,
+    saved_at: u64,
+    rooms: Vec<RoomSnapshot>,
+    #[serde(default)]
+    tokens: TokenSnapshot,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RoomSnapshot {
+    room_id: String,
+    name: String,
+    host_id: String,
+    host_user_id: Option<String>, // Informational: the host session is in `members`
+    media_id: Option<String>,
+    queue: Vec<String>,
+    state: PlaybackState,
+    position_ts: u64,
+    host_policy: HostTransferPolicy,
+    successor_id: Option<String>,
+    control_mode: ControlMode,
+    controllers: HashSet<String>,
+    visibility: RoomVisibility,
+    password_hash: Option<String>,
+    invite_codes: HashSet<String>,
+    banned_users: HashSet<String>,
+    muted: HashSet<String>,
+    chat_history: ChatHistory,
+    members: Vec<SessionSnapshot>, // In join order
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SessionSnapshot {
+    client_id: String,
+    user_id: String,
+    user_name: String,
+    authenticated: bool,
+    resume_token: String,
+    #[serde(default = "legacy_protocol_version")]
+    protocol_version: u32,
+    #[serde(default)]
+    token_exp: Option<u64>,
+    #[serde(default)]
+    token_jti: Option<String>,
+    #[serde(default)]
+    permissions: Permissions,
+}
+
+fn legacy_protocol_version() -> u32 {
+    LEGACY_PROTOCOL_VERSION
+}
+
+impl Snapshot {
+    pub fn capture(clients: &ClientMap, rooms: &[Room], tokens: &TokenRegistry, now: u64) -> Self {
+        let rooms = rooms
+            .iter()
+            .map(|room| RoomSnapshot {
+                room_id: room.room_id.clone(),
+                name: room.name.clone(),
+                host_id: room.host_id.clone(),
+                host_user_id: clients.get(&room.host_id).map(|c| c.user_id.clone()),
+                media_id: room.media_id.clone(),
+                queue: room.queue.clone(),
+                state: room.state.clone(),
+                position_ts: room.position_ts,
+                host_policy: room.host_policy,
+                successor_id: room.successor_id.clone(),
+                control_mode: room.control_mode,
+                controllers: room.controllers.clone(),
+                visibility: room.visibility,
+                password_hash: room.password_hash.clone(),
+                invite_codes: room.invite_codes.clone(),
+                banned_users: room.banned_users.clone(),
+                muted: room.muted.clone(),
+                chat_history: room.chat_history.clone(),
+                members: room
+                    .clients
+                    .iter()
+                    .filter_map(|id| {
+                        // Relayed clients' sessions live on their own node
+                        let c = clients.get(id).filter(|c| c.origin_node.is_none())?;
+                        Some(SessionSnapshot {
+                            client_id: id.clone(),
+                            user_id: c.user_id.clone(),
+                            user_name: c.user_name.clone(),
+                            authenticated: c.authenticated,
+                            resume_token: c.resume_token.clone(),
+                            protocol_version: c.protocol_version,
+                            token_exp: c.token_exp,
+                            token_jti: c.token_jti.clone(),
+                            permissions: c.permissions,
+                        })
+                    })
+                    .collect(),
+            })
+            .collect();
+        Self {
+            version: SNAPSHOT_VERSION,
+            saved_at: now,
+            rooms,
+            tokens: tokens.snapshot(now),
+        }
+    }
+
+    /// Takes out the saved token registry, to restore separately from the rooms.
+    pub fn take_tokens(&mut self) -> TokenSnapshot {
+        std::mem::take(&mut self.tokens)
+    }
+
+    /// Recreates the rooms and their members' sessions, marked disconnected at
+    /// `now`. Returns the restored client IDs so the caller can start their
+    /// resume grace timers. Rooms that already exist are left alone.
+    pub fn restore(self, clients: &Clients, rooms: &Rooms, now: u64) -> Vec<String> {
+        let mut restored = Vec::new();
+        for snap in self.rooms {
+            if rooms.contains(&snap.room_id) || snap.members.is_empty() {
+                continue;
+            }
+            let mut members = Vec::new();
+            for session in snap.members {
+                if clients.contains_key(&session.client_id) {
+                    continue;
+                }
+                // No socket yet: messages to the held session are dropped until it resumes
+                let (sender, _) = mpsc::channel(1);
+                clients.insert(
+                    session.client_id.clone(),
+                    Client {
+                        sender,
+                        room_id: Some(snap.room_id.clone()),
+                        user_id: session.user_id,
+                        user_name: session.user_name,
+                        authenticated: session.authenticated,
+                        message_count: 0,
+                        last_reset: now,
+                        last_seen: now,
+                        resume_token: session.resume_token,
+                        disconnected_at: Some(now),
+                        clock: ClockSync::default(),
+                        owner_node: None,
+                        origin_node: None,
+                        protocol_version: session.protocol_version,
+                        token_exp: session.token_exp,
+                        token_warned: false,
+                        token_jti: session.token_jti,
+                        permissions: session.permissions,
+                        user_slot: None,
+                    },
+                );
+                members.push(session.client_id);
+            }
+            if members.is_empty() {
+                continue;
+            }
+            restored.extend(members.iter().cloned());
+            let host_id = if members.contains(&snap.host_id) {
+                snap.host_id
+            } else {
+                members[0].clone()
+            };
+            rooms.spawn(
+                Room {
+                    room_id: snap.room_id,
+                    name: snap.name,
+                    host_id,
+                    media_id: snap.media_id,
+                    queue: snap.queue,
+                    clients: members,
+                    ready_clients: HashSet::new(),
+                    pending_play: None,
+                    state: snap.state,
+                    host_policy: snap.host_policy,
+                    successor_id: snap.successor_id,
+                    control_mode: snap.control_mode,
+                    controllers: snap.controllers,
+                    visibility: snap.visibility,
+                    password_hash: snap.password_hash,
+                    invite_codes: snap.invite_codes,
+                    banned_users: snap.banned_users,
+                    muted: snap.muted,
+                    chat_history: snap.chat_history,
+                    // Players kept running while the server was down, so keep
+                    // extrapolating from the original timestamp
+                    position_ts: snap.position_ts,
+                    last_state_ts: 0,
+                    last_command_ts: 0,
+                    last_command_by: None,
+                },
+                clients,
+            );
+        }
+        restored
+    }
+
+    pub fn room_count(&self) -> usize {
+        self.rooms.len()
+    }
+}
+
+/// Writes a snapshot of the current rooms to `path` (atomically, via a
+/// temporary file). Returns the number of rooms saved.
+pub async fn save(
+    path: &str,
+    clients: &Clients,
+    rooms: &Rooms,
+    tokens: &TokenRegistry,
+    now: u64,
+) -> Result<usize, String> {
+    // Each room copies itself on its own task; rooms that close meanwhile are skipped
+    let mut copies = Vec::new();
+    for handle in rooms.handles() {
+        if let Some(room) = handle.call(|room, _| room.clone()).await {
+            copies.push(room);
+        }
+    }
+    let snapshot = Snapshot::capture(clients, &copies, tokens, now);
+    let json = serde_json::to_vec(&snapshot).map_err(|e| e.to_string())?;
+    let count = snapshot.room_count();
+    let path = path.to_string();
+    tokio::task::spawn_blocking(move || write_atomically(&path, &json))
+        .await
+        .map_err(|e| e.to_string())??;
+    Ok(count)
+}
+
+/// Reads the snapshot at `path`. A missing file is not an error.
+pub fn load(path: &str) -> Result<Option<Snapshot>, String> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Cannot read {}: {}", path, e)),
+    };
+    let snapshot: Snapshot =
+        serde_json::from_slice(&data).map_err(|e| format!("{}: {}", path, e))?;
+    if snapshot.version != SNAPSHOT_VERSION {
+        return Err(format!(
+            "{}: unsupported snapshot version {}",
+            path, snapshot.version
+        ));
+    }
+    Ok(Some(snapshot))
+}
+
+fn write_atomically(path: &str, data: &[u8]) -> Result<(), String> {
+    let tmp = format!("{}.tmp", path);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    // Holds password hashes and resume tokens: owner-only
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(&tmp)
+        .map_err(|e| format!("Cannot write {}: {}", tmp, e))?;
+    file.write_all(data)
+        .and_then(|_| file.sync_all())
+        .map_err(|e| format!("Cannot write {}: {}", tmp, e))?;
+    std::fs::rename(&tmp, path).map_err(|e| format!("Cannot replace {}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(room_id: &str, token: &str) -> Client {
+        let (sender, _) = mpsc::channel(1);
+        Client {
+            sender,
+            room_id: Some(room_id.to_string()),
+            user_id: format!("user-{}", token),
+            user_name: "Alice".to_string(),
+            authenticated: true,
+            message_count: 0,
+            last_reset: 0,
+            last_seen: 0,
+            resume_token: token.to_string(),
+            disconnected_at: None,
+            clock: ClockSync::default(),
+            owner_node: None,
+            origin_node: None,
+            protocol_version: LEGACY_PROTOCOL_VERSION,
+            token_exp: None,
+            token_warned: false,
+            token_jti: None,
+            permissions: Default::default(),
+            user_slot: None,
+        }
+    }
+
+    fn room() -> Room {
+        Room {
+            room_id: "room-1".to_string(),
+            name: "Movie night".to_string(),
+            host_id: "host".to_string(),
+            media_id: Some("media-1".to_string()),
+            queue: vec!["media-2".to_string()],
+            clients: vec!["host".to_string(), "guest".to_string()],
+            ready_clients: HashSet::from(["host".to_string()]),
+            pending_play: None,
+            state: PlaybackState {
+                position: 42.0,
+                play_state: "playing".to_string(),
+            },
+            host_policy: HostTransferPolicy::default(),
+            successor_id: None,
+            control_mode: ControlMode::default(),
+            controllers: HashSet::new(),
+            visibility: RoomVisibility::Unlisted,
+            password_hash: Some("$argon2id$hash".to_string()),
+            invite_codes: HashSet::new(),
+            banned_users: HashSet::from(["troll".to_string()]),
+            muted: HashSet::new(),
+            chat_history: ChatHistory::default(),
+            position_ts: 1_000,
+            last_state_ts: 0,
+            last_command_ts: 0,
+            last_command_by: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_roundtrip() {
+        let clients = ClientMap::new();
+        clients.insert("host".to_string(), client("room-1", "t-host"));
+        clients.insert("guest".to_string(), client("room-1", "t-guest"));
+        let mut room = room();
+        room.chat_history.push("guest", "Bob", "hi", 500);
+
+        let tokens = TokenRegistry::default();
+        tokens
+            .revoke(crate::tokens::Revocation::Sub("troll".to_string()), 0)
+            .unwrap();
+
+        let snapshot = Snapshot::capture(&clients, &[room], &tokens, 2_000);
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let mut snapshot: Snapshot = serde_json::from_str(&json).unwrap();
+        let tokens = TokenRegistry::default();
+        tokens.restore(snapshot.take_tokens(), 5_000);
+        assert_eq!(tokens.revocations(5_000).len(), 1);
+
+        let clients: Clients = Default::default();
+        let rooms: Rooms = Default::default();
+        let restored = snapshot.restore(&clients, &rooms, 5_000);
+        assert_eq!(restored, vec!["host".to_string(), "guest".to_string()]);
+
+        let room = rooms.call("room-1", |room, _| room.clone()).await.unwrap();
+        assert_eq!(room.host_id, "host");
+        assert_eq!(room.queue, vec!["media-2".to_string()]);
+        assert_eq!(room.position_ts, 1_000);
+        assert_eq!(room.password_hash.as_deref(), Some("$argon2id$hash"));
+        assert!(room.banned_users.contains("troll"));
+        assert!(room.ready_clients.is_empty());
+        assert_eq!(room.chat_history.recent(5_000).len(), 1);
+
+        let host = clients.get("host").unwrap();
+        assert_eq!(host.resume_token, "t-host");
+        assert_eq!(host.disconnected_at, Some(5_000));
+        assert_eq!(host.room_id.as_deref(), Some("room-1"));
+    }
+
+    #[tokio::test]
+    async fn test_restore_skips_existing_rooms() {
+        let clients: Clients = Default::default();
+        clients.insert("host".to_string(), client("room-1", "t-host"));
+        let rooms: Rooms = Default::default();
+        rooms.spawn(room(), &clients);
+        let snapshot = Snapshot::capture(&clients, &[room()], &TokenRegistry::default(), 0);
+
+        let restored = snapshot.restore(&clients, &rooms, 0);
+        assert!(restored.is_empty());
+    }
+
+    #[test]
+    fn test_load_missing_file() {
+        assert!(load("/nonexistent/owp-rooms.json").unwrap().is_none());
+    }
+}
@@ -0,0 +1,118 @@
+use crate::config::Config;
+use crate::types::{IncomingMessage, WsMessage};
+use schemars::gen::SchemaSettings;
+use schemars::schema::SchemaObject;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+// Protocol versions:
+//   1 - original protocol, assumed for clients that don't declare one
+//   2 - request `id`s with `ack`s, error `code`s
+/// Newest protocol version this server speaks.
+pub const PROTOCOL_VERSION: u32 = 2;
+/// Oldest client protocol version still accepted.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// Version of clients that never declared one.
+pub const LEGACY_PROTOCOL_VERSION: u32 = 1;
+
+/// First version with `ack` messages; older clients don't get them.
+pub const ACK_PROTOCOL_VERSION: u32 = 2;
+
+/// Optional features this server supports, advertised in `client_hello` so
+/// clients can hide what is not available.
+pub fn features(config: &Config) -> Vec<&'static str> {
+    let mut features = vec![
+        "chat",
+        "chat_history",
+        "queue",
+        "control_modes",
+        "moderation",
+        "room_passwords",
+        "invites",
+        "time_sync",
+        "request_ids",
+        "token_refresh",
+    ];
+    if config.session.resume_grace_ms > 0 {
+        features.push("session_resume");
+    }
+    features
+}
+
+/// The `protocol` object of `client_hello`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ProtocolInfo {
+    pub version: u32,
+    pub min_version: u32,
+    pub features: Vec<String>,
+}
+
+pub fn hello_payload(config: &Config) -> ProtocolInfo {
+    ProtocolInfo {
+        version: PROTOCOL_VERSION,
+        min_version: MIN_PROTOCOL_VERSION,
+        features: features(config).into_iter().map(str::to_string).collect(),
+    }
+}
+
+/// JSON Schema of the wire protocol, for client authors: `ClientMessage` is
+/// what clients send, `ServerMessage` what the server sends back. Checked in
+/// as `docs/technical/protocol.schema.json`.
+pub fn schema() -> serde_json::Value {
+    let mut generator = SchemaSettings::draft07().into_generator();
+    let client = generator.subschema_for::<IncomingMessage>();
+    let server = generator.subschema_for::<WsMessage>();
+    let mut root = generator.root_schema_for::<()>();
+    root.schema = SchemaObject::default();
+    root.schema.metadata().title = Some(format!("OpenWatchParty protocol v{}", PROTOCOL_VERSION));
+    root.schema.subschemas().one_of = Some(vec![client, server]);
+    root.definitions = generator.take_definitions();
+    serde_json::to_value(root).expect("schema serializes")
+}
+
+/// Picks the version to speak with a client supporting `client_min..=client_max`:
+/// the newest one both sides know. None if the ranges don't overlap.
+pub fn negotiate(client_max: u32, client_min: u32) -> Option<u32> {
+    let version = client_max.min(PROTOCOL_VERSION);
+    (version >= client_min.max(MIN_PROTOCOL_VERSION)).then_some(version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate(PROTOCOL_VERSION, 1), Some(PROTOCOL_VERSION));
+        // Newer clients that still speak our version are talked down to it
+        assert_eq!(negotiate(PROTOCOL_VERSION + 3, 1), Some(PROTOCOL_VERSION));
+        assert_eq!(negotiate(1, 1), Some(1));
+        // Clients requiring something newer than we speak are rejected
+        assert_eq!(negotiate(PROTOCOL_VERSION + 3, PROTOCOL_VERSION + 1), None);
+        assert_eq!(negotiate(0, 0), None);
+    }
+
+    #[test]
+    fn test_schema_is_up_to_date() {
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../docs/technical/protocol.schema.json"
+        );
+        let expected = format!("{:#}\n", schema());
+        if std::env::var_os("UPDATE_SCHEMA").is_some() {
+            std::fs::write(path, &expected).unwrap();
+        }
+        assert!(
+            std::fs::read_to_string(path).unwrap() == expected,
+            "protocol.schema.json is stale, regenerate it with UPDATE_SCHEMA=1 cargo test"
+        );
+    }
+
+    #[test]
+    fn test_features_follow_config() {
+        let mut config = Config::default();
+        assert!(features(&config).contains(&"session_resume"));
+        config.session.resume_grace_ms = 0;
+        assert!(!features(&config).contains(&"session_resume"));
+    }
+}
@@ -0,0 +1,293 @@
+use crate::backend::{Backend, BackendFuture, Envelope, RoomEntry};
+use futures::StreamExt;
+use log::{info, warn};
+use redis::aio::{ConnectionManager, PubSub};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+const ROOM_KEY_PREFIX: &str = "owp:room:";
+const NODE_CHANNEL_PREFIX: &str = "owp:node:";
+// Messages for every node (`Backend::publish`)
+const ALL_NODES_CHANNEL: &str = "owp:nodes";
+// Inbox size between the subscriber task and the event loop
+const NODE_CHANNEL_BUFFER: usize = 1024;
+// Wait before re-subscribing after the pub/sub connection drops
+const RESUBSCRIBE_DELAY_MS: u64 = 1_000;
+
+/// Room directory in Redis keys (expiring with `SET PX`), node-to-node
+/// messages over Redis pub/sub (one channel per node, plus one for all).
+pub struct RedisBackend {
+    client: redis::Client,
+    conn: ConnectionManager,
+}
+
+impl RedisBackend {
+    pub async fn connect(url: &str) -> Result<Self, String> {
+        let client = redis::Client::open(url).map_err(|e| format!("{}: {}", url, e))?;
+        let conn = client
+            .get_connection_manager()
+            .await
+            .map_err(|e| format!("{}: {}", url, e))?;
+        Ok(Self { client, conn })
+    }
+
+    async fn pubsub(client: &redis::Client, channels: &[String]) -> Result<PubSub, String> {
+        let mut pubsub = client.get_async_pubsub().await.map_err(|e| e.to_string())?;
+        pubsub
+            .subscribe(channels)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(pubsub)
+    }
+
+    async fn publish_json(&self, channel: &str, envelope: &Envelope) -> Result<u64, String> {
+        let json = serde_json::to_string(envelope).map_err(|e| e.to_string())?;
+        redis::cmd("PUBLISH")
+            .arg(channel)
+            .arg(json)
+            .query_async(&mut self.conn.clone())
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
+
+fn room_key(room_id: &str) -> String {
+    format!("{}{}", ROOM_KEY_PREFIX, room_id)
+}
+
+fn node_channel(node_id: &str) -> String {
+    format!("{}{}", NODE_CHANNEL_PREFIX, node_id)
+}
+
+/// Channels a node listens on: its own and the one for all nodes.
+fn inbox_channels(node_id: &str) -> [String; 2] {
+    [node_channel(node_id), ALL_NODES_CHANNEL.to_string()]
+}
+
+/// Forwards the messages of `pubsub` to `tx`, re-subscribing whenever the
+/// connection drops, until the receiving side goes away. Messages published
+/// to all nodes come back to their sender too; those are dropped here.
+async fn relay_messages(
+    client: redis::Client,
+    node_id: String,
+    mut pubsub: PubSub,
+    tx: mpsc::Sender<Envelope>,
+) {
+    let channels = inbox_channels(&node_id);
+    let channel = channels.join(", ");
+    loop {
+        let mut messages = pubsub.into_on_message();
+        while let Some(msg) = messages.next().await {
+            let envelope = msg
+                .get_payload::<String>()
+                .map_err(|e| e.to_string())
+                .and_then(|json| {
+                    serde_json::from_str::<Envelope>(&json).map_err(|e| e.to_string())
+                });
+            match envelope {
+                Ok(envelope) if envelope.from == node_id => {}
+                Ok(envelope) => {
+                    if tx.send(envelope).await.is_err() {
+                        return;
+                    }
+                }
+                Err(e) => warn!("Ignoring malformed message on {}: {}", channel, e),
+            }
+        }
+        warn!("Lost the Redis subscription to {}, reconnecting", channel);
+        pubsub = loop {
+            tokio::time::sleep(Duration::from_millis(RESUBSCRIBE_DELAY_MS)).await;
+            if tx.is_closed() {
+                return;
+            }
+            match RedisBackend::pubsub(&client, &channels).await {
+                Ok(pubsub) => break pubsub,
+                Err(e) => warn!("Cannot resubscribe to {}: {}", channel, e),
+            }
+        };
+        info!("Resubscribed to {}", channel);
+    }
+}
+
+impl Backend for RedisBackend {
+    fn put_room<'a>(
+        &'a self,
+        room_id: &'a str,
+        entry: &'a RoomEntry,
+        ttl_ms: u64,
+    ) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            let json = serde_json::to_string(entry).map_err(|e| e.to_string())?;
+            redis::cmd("SET")
+                .arg(room_key(room_id))
+                .arg(json)
+                .arg("PX")
+                .arg(ttl_ms)
+                .query_async::<()>(&mut self.conn.clone())
+                .await
+                .map_err(|e| e.to_string())
+        })
+    }
+
+    fn remove_room<'a>(&'a self, room_id: &'a str) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            redis::cmd("DEL")
+                .arg(room_key(room_id))
+                .query_async::<()>(&mut self.conn.clone())
+                .await
+                .map_err(|e| e.to_string())
+        })
+    }
+
+    fn get_room<'a>(&'a self, room_id: &'a str) -> BackendFuture<'a, Option<RoomEntry>> {
+        Box::pin(async move {
+            let json: Option<String> = redis::cmd("GET")
+                .arg(room_key(room_id))
+                .query_async(&mut self.conn.clone())
+                .await
+                .map_err(|e| e.to_string())?;
+            json.map(|json| serde_json::from_str(&json).map_err(|e| e.to_string()))
+                .transpose()
+        })
+    }
+
+    fn list_rooms(&self) -> BackendFuture<'_, Vec<RoomEntry>> {
+        Box::pin(async move {
+            let mut conn = self.conn.clone();
+            let mut keys = Vec::new();
+            let mut cursor = 0u64;
+            loop {
+                let (next, batch): (u64, Vec<String>) = redis::cmd("SCAN")
+                    .arg(cursor)
+                    .arg("MATCH")
+                    .arg(format!("{}*", ROOM_KEY_PREFIX))
+                    .arg("COUNT")
+                    .arg(100)
+                    .query_async(&mut conn)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                keys.extend(batch);
+                if next == 0 {
+                    break;
+                }
+                cursor = next;
+            }
+            if keys.is_empty() {
+                return Ok(Vec::new());
+            }
+            // Keys can expire between SCAN and MGET
+            let values: Vec<Option<String>> = redis::cmd("MGET")
+                .arg(&keys)
+                .query_async(&mut conn)
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok(values
+                .into_iter()
+                .flatten()
+                .filter_map(|json| match serde_json::from_str(&json) {
+                    Ok(entry) => Some(entry),
+                    Err(e) => {
+                        warn!("Ignoring malformed room directory entry: {}", e);
+                        None
+                    }
+                })
+                .collect())
+        })
+    }
+
+    fn send<'a>(&'a self, to: &'a str, envelope: &'a Envelope) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            let receivers = self.publish_json(&node_channel(to), envelope).await?;
+            if receivers == 0 {
+                return Err(format!("Node {} is not reachable", to));
+            }
+            Ok(())
+        })
+    }
+
+    fn publish<'a>(&'a self, envelope: &'a Envelope) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            self.publish_json(ALL_NODES_CHANNEL, envelope).await?;
+            Ok(())
+        })
+    }
+
+    fn subscribe<'a>(&'a self, node_id: &'a str) -> BackendFuture<'a, mpsc::Receiver<Envelope>> {
+        Box::pin(async move {
+            let channels = inbox_channels(node_id);
+            // Subscribe before returning, so messages sent from now on arrive
+            let pubsub = Self::pubsub(&self.client, &channels).await?;
+            let (tx, rx) = mpsc::channel(NODE_CHANNEL_BUFFER);
+            tokio::spawn(relay_messages(
+                self.client.clone(),
+                node_id.to_string(),
+                pubsub,
+                tx,
+            ));
+            Ok(rx)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::NodeEvent;
+    use crate::messaging::RoomListItem;
+    use crate::types::PlaybackState;
+
+    async fn backend() -> RedisBackend {
+        let url =
+            std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+        RedisBackend::connect(&url)
+            .await
+            .expect("Redis unreachable")
+    }
+
+    /// Needs a Redis server (`REDIS_URL`, default `redis://127.0.0.1:6379`):
+    /// `docker run --rm -p 6379:6379 redis:7` then
+    /// `cargo test test_redis -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn test_redis_directory_and_messaging() {
+        let backend = backend().await;
+        let room_id = uuid::Uuid::new_v4().to_string();
+        let entry = RoomEntry {
+            node_id: "a".to_string(),
+            listed: true,
+            item: RoomListItem {
+                id: room_id.clone(),
+                name: "Movie night".to_string(),
+                count: 1,
+                media_id: None,
+                queue: Vec::new(),
+                has_password: false,
+                state: PlaybackState {
+                    position: 0.0,
+                    play_state: "paused".to_string(),
+                },
+            },
+            position_ts: 0,
+        };
+        backend.put_room(&room_id, &entry, 60_000).await.unwrap();
+        assert_eq!(
+            backend.get_room(&room_id).await.unwrap(),
+            Some(entry.clone())
+        );
+        assert!(backend.list_rooms().await.unwrap().contains(&entry));
+        backend.remove_room(&room_id).await.unwrap();
+        assert_eq!(backend.get_room(&room_id).await.unwrap(), None);
+
+        let node_id = uuid::Uuid::new_v4().to_string();
+        let envelope = Envelope {
+            from: "a".to_string(),
+            event: NodeEvent::Lost {
+                client_id: "c1".to_string(),
+            },
+        };
+        assert!(backend.send(&node_id, &envelope).await.is_err());
+        let mut inbox = backend.subscribe(&node_id).await.unwrap();
+        backend.send(&node_id, &envelope).await.unwrap();
+        assert_eq!(inbox.recv().await, Some(envelope));
+    }
+}
@@ -1,122 +1,1070 @@
+use crate::auth::Permissions;
+use crate::cluster::RemoteRooms;
+use crate::error::{ErrorCode, ServerError};
 use crate::messaging::{broadcast_room_list, broadcast_to_room, send_to_client};
-use crate::types::{Client, Clients, Room, Rooms, WsMessage};
+use crate::types::{
+    ClientMap, Clients, ControlMode, HostTransferPolicy, ModerationAction, ModerationNotice,
+    PlaybackState, QueueEdit, Room, RoomStatePayload, Rooms, ServerMessage, WsMessage,
+};
 use crate::utils::now_ms;
 use log::info;
-use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
 
-pub async fn handle_disconnect(client_id: &str, clients: &Clients, rooms: &Rooms) {
+pub async fn handle_disconnect(
+    client_id: &str,
+    clients: &Clients,
+    rooms: &Rooms,
+    remote_rooms: &RemoteRooms,
+) {
     info!("Disconnecting client {}", client_id);
-    {
-        let mut locked_clients = clients.write().await;
-        let mut locked_rooms = rooms.write().await;
-        handle_leave(client_id, &mut locked_clients, &mut locked_rooms);
-        locked_clients.remove(client_id);
+    leave_room(client_id, clients, rooms).await;
+    clients.remove(client_id);
+    broadcast_room_list(clients, rooms, remote_rooms);
+}
+
+/// Extrapolates the room's playback state to server time `now`.
+/// While playing, the position advances in real time from `position_ts`;
+/// a play scheduled in the future (`position_ts > now`) has not started yet.
+pub fn expected_state(room: &Room, now: u64) -> PlaybackState {
+    extrapolate(&room.state, room.position_ts, now)
+}
+
+/// `expected_state` for a state known to be accurate at `position_ts`.
+pub fn extrapolate(state: &PlaybackState, position_ts: u64, now: u64) -> PlaybackState {
+    let mut state = state.clone();
+    if state.play_state == "playing" {
+        state.position += now.saturating_sub(position_ts) as f64 / 1000.0;
     }
-    broadcast_room_list(clients, rooms).await;
+    state
 }
 
-pub fn handle_leave(
+/// Builds the `room_state` payload sent to a client entering a room.
+/// The position is extrapolated to the message's `server_ts` (now).
+pub fn room_state_payload(room: &Room) -> RoomStatePayload {
+    RoomStatePayload {
+        name: room.name.clone(),
+        host_id: room.host_id.clone(),
+        state: expected_state(room, now_ms()),
+        participant_count: room.clients.len(),
+        media_id: room.media_id.clone(),
+        queue: room.queue.clone(),
+        control_mode: room.control_mode,
+        controllers: room.controllers.clone(),
+        visibility: room.visibility,
+        has_password: room.password_hash.is_some(),
+    }
+}
+
+/// Applies a host edit to the watch queue.
+pub fn edit_queue(room: &mut Room, edit: QueueEdit) -> Result<(), ServerError> {
+    match edit {
+        QueueEdit::Add { media_id, index } => {
+            let index = index.unwrap_or(room.queue.len()).min(room.queue.len());
+            room.queue.insert(index, media_id);
+        }
+        QueueEdit::Remove(index) => {
+            if index >= room.queue.len() {
+                return Err(ServerError::new(
+                    ErrorCode::InvalidRequest,
+                    "Queue index out of range",
+                ));
+            }
+            room.queue.remove(index);
+        }
+        QueueEdit::Move { from, to } => {
+            if from >= room.queue.len() || to >= room.queue.len() {
+                return Err(ServerError::new(
+                    ErrorCode::InvalidRequest,
+                    "Queue index out of range",
+                ));
+            }
+            let media_id = room.queue.remove(from);
+            room.queue.insert(to, media_id);
+        }
+    }
+    Ok(())
+}
+
+/// Makes the next queued item current. Everyone has to load the new item,
+/// so readiness and any pending play are reset and playback starts paused at 0.
+pub fn advance_queue(room: &mut Room, now: u64) -> Option<String> {
+    if room.queue.is_empty() {
+        return None;
+    }
+    let media_id = room.queue.remove(0);
+    room.media_id = Some(media_id.clone());
+    room.ready_clients.clear();
+    room.pending_play = None;
+    room.state = PlaybackState {
+        position: 0.0,
+        play_state: "paused".to_string(),
+    };
+    room.position_ts = now;
+    Some(media_id)
+}
+
+/// Builds the `chat_history` message replayed to a client entering a room.
+pub fn chat_history_message(room: &Room, client_id: &str) -> WsMessage {
+    let now = now_ms();
+    WsMessage {
+        body: ServerMessage::ChatHistory {
+            messages: room.chat_history.recent(now),
+        },
+        id: None,
+        room: Some(room.room_id.clone()),
+        client: Some(client_id.to_string()),
+        ts: now,
+        server_ts: Some(now),
+    }
+}
+
+/// Returns true if `client_id` may send playback commands in this room.
+pub fn can_control(room: &Room, client_id: &str) -> bool {
+    if room.host_id == client_id {
+        return true;
+    }
+    let is_member = room.clients.iter().any(|id| id == client_id);
+    match room.control_mode {
+        ControlMode::HostOnly => false,
+        ControlMode::Everyone => is_member,
+        ControlMode::AllowList => is_member && room.controllers.contains(client_id),
+    }
+}
+
+/// `can_control` refined by the client's token permissions: users without
+/// `can_control` never send playback commands, server admins always may (in
+/// rooms they are in).
+pub fn may_control(room: &Room, client_id: &str, permissions: Permissions) -> bool {
+    if permissions.admin {
+        return room.clients.iter().any(|id| id == client_id);
+    }
+    permissions.can_control && can_control(room, client_id)
+}
+
+/// Picks the participant that should become host according to the room's policy.
+/// Must be called after the departing host has been removed from `room.clients`.
+pub fn pick_successor(room: &Room) -> Option<String> {
+    let oldest = room.clients.first().cloned();
+    match room.host_policy {
+        HostTransferPolicy::OldestMember => oldest,
+        HostTransferPolicy::FirstReady => room
+            .clients
+            .iter()
+            .find(|id| room.ready_clients.contains(*id))
+            .cloned()
+            .or(oldest),
+        HostTransferPolicy::Designated => room
+            .successor_id
+            .as_ref()
+            .filter(|id| room.clients.contains(id))
+            .cloned()
+            .or(oldest),
+    }
+}
+
+/// Called when a client's socket closes. Instead of leaving immediately, the
+/// session (room membership, host role) is held for `grace_ms` so the client
+/// can reclaim it with its resume token. `sender` identifies the socket that
+/// closed: if the session was already resumed on another socket, nothing happens.
+pub async fn handle_connection_lost(
     client_id: &str,
-    clients: &mut HashMap<String, Client>,
-    rooms: &mut HashMap<String, Room>,
+    sender: &mpsc::Sender<std::result::Result<warp::ws::Message, warp::Error>>,
+    clients: &Clients,
+    rooms: &Rooms,
+    remote_rooms: &Arc<RemoteRooms>,
+    grace_ms: u64,
 ) {
-    let mut room_to_remove = None;
-    let mut clients_to_notify = Vec::new();
-
-    if let Some(client) = clients.get_mut(client_id) {
-        if let Some(room_id) = client.room_id.take() {
-            if let Some(room) = rooms.get_mut(&room_id) {
-                room.clients.retain(|id| id != client_id);
-                room.ready_clients.remove(client_id);
-                if room.host_id == client_id {
-                    room.pending_play = None;
-                }
-                if room.clients.is_empty() || room.host_id == client_id {
-                    clients_to_notify = room.clients.clone();
-                    room_to_remove = Some(room_id.clone());
-                } else {
-                    let msg = WsMessage {
-                        msg_type: "client_left".to_string(),
-                        room: Some(room_id),
-                        client: Some(client_id.to_string()),
-                        payload: Some(
-                            serde_json::json!({ "participant_count": room.clients.len() }),
-                        ),
-                        ts: now_ms(),
-                        server_ts: Some(now_ms()),
-                    };
-                    broadcast_to_room(room, clients, &msg, None);
-                }
-            }
+    if grace_ms == 0 {
+        handle_disconnect(client_id, clients, rooms, remote_rooms).await;
+        return;
+    }
+
+    let disconnected_at = now_ms();
+    match clients.get_mut(client_id) {
+        Some(mut client) if client.sender.same_channel(sender) => {
+            client.disconnected_at = Some(disconnected_at);
+            // Held sessions don't count against the user's connection cap
+            client.user_slot = None;
         }
+        _ => return,
     }
+    info!(
+        "Client {} connection lost, holding session for {} ms",
+        client_id, grace_ms
+    );
+    expire_session_after(
+        client_id,
+        disconnected_at,
+        clients,
+        rooms,
+        remote_rooms,
+        grace_ms,
+    );
+}
 
-    if let Some(room_id) = room_to_remove {
-        info!("Closing room {}", room_id);
-        rooms.remove(&room_id);
-        // Use WsMessage struct for consistent message format (fixes B03)
-        let msg = WsMessage {
-            msg_type: "room_closed".to_string(),
-            room: Some(room_id),
-            client: None,
-            payload: Some(serde_json::json!({ "reason": "Host left the room" })),
+/// Ends the held session of `client_id` once `grace_ms` has passed, unless it
+/// was resumed (or lost again, which restarts the clock) in the meantime.
+pub fn expire_session_after(
+    client_id: &str,
+    disconnected_at: u64,
+    clients: &Clients,
+    rooms: &Rooms,
+    remote_rooms: &Arc<RemoteRooms>,
+    grace_ms: u64,
+) {
+    let client_id = client_id.to_string();
+    let clients = clients.clone();
+    let rooms = rooms.clone();
+    let remote_rooms = remote_rooms.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(grace_ms)).await;
+        // Still the same disconnection (not resumed in the meantime)?
+        let expired = clients
+            .get(&client_id)
+            .is_some_and(|c| c.disconnected_at == Some(disconnected_at));
+        if expired {
+            info!("Resume grace period expired for client {}", client_id);
+            handle_disconnect(&client_id, &clients, &rooms, &remote_rooms).await;
+        }
+    });
+}
+
+/// Moves the socket of `new_id` onto the session identified by `token`.
+/// Returns the reclaimed client ID, or None if the token is unknown or expired.
+pub async fn handle_resume(
+    new_id: &str,
+    token: &str,
+    clients: &Clients,
+    rooms: &Rooms,
+    grace_ms: u64,
+) -> Option<String> {
+    let now = now_ms();
+    let old_id = resumable(new_id, token, clients, grace_ms, now)?;
+    let sender = clients.get(new_id)?.sender.clone();
+    // This socket becomes `old_id`; a room it joined under its own ID would
+    // otherwise keep a member that no longer exists
+    leave_room(new_id, clients, rooms).await;
+
+    let resume_token = uuid::Uuid::new_v4().to_string();
+    let room_id = {
+        let mut client = clients.get_mut(&old_id)?;
+        // Checked again under the entry lock: only one socket wins a token
+        if client.resume_token != token {
+            return None;
+        }
+        match client.disconnected_at {
+            Some(at) if now.saturating_sub(at) > grace_ms => return None,
+            Some(_) => {}
+            None => {
+                // The old socket is still open (e.g. half-open after a network
+                // change): close it, it stops acting for the session once the
+                // sender is swapped
+                info!("Session {} resumed while still connected", old_id);
+                let _ = client.sender.try_send(Ok(warp::ws::Message::close()));
+            }
+        }
+        client.sender = sender;
+        client.disconnected_at = None;
+        client.last_seen = now;
+        client.message_count = 0;
+        client.last_reset = now;
+        // Rotate the token so a captured one cannot be replayed
+        client.resume_token = resume_token.clone();
+        client.room_id.clone()
+    };
+    clients.remove(new_id);
+    info!("Client {} resumed session {}", new_id, old_id);
+
+    send_to_client(
+        &old_id,
+        clients,
+        &WsMessage {
+            body: ServerMessage::SessionResumed {
+                client_id: old_id.clone(),
+                resume_token,
+            },
+            id: None,
+            room: room_id.clone(),
+            client: Some(old_id.clone()),
             ts: now_ms(),
             server_ts: Some(now_ms()),
+        },
+    );
+
+    if let Some(room_id) = room_id {
+        let client_id = old_id.clone();
+        rooms
+            .call(&room_id, move |room, clients| {
+                send_catch_up(&client_id, room, clients)
+            })
+            .await;
+    }
+
+    Some(old_id)
+}
+
+/// The session `token` resumes, if it is within its grace period.
+pub fn resumable(
+    new_id: &str,
+    token: &str,
+    clients: &ClientMap,
+    grace_ms: u64,
+    now: u64,
+) -> Option<String> {
+    if token.is_empty() {
+        return None;
+    }
+    clients
+        .iter()
+        .find(|c| {
+            c.key() != new_id
+                && c.resume_token == token
+                && c.disconnected_at
+                    .is_none_or(|at| now.saturating_sub(at) <= grace_ms)
+        })
+        .map(|c| c.key().clone())
+}
+
+/// Reconnects a held session whose socket came back on another node (the
+/// proxy already answered the `resume`) and brings it up to date.
+pub async fn reattach(client_id: &str, clients: &Clients, rooms: &Rooms) {
+    let room_id = {
+        let Some(mut client) = clients.get_mut(client_id) else {
+            return;
         };
-        if let Ok(msg_json) = serde_json::to_string(&msg) {
-            for cid in clients_to_notify {
-                if let Some(c) = clients.get(&cid) {
-                    // Use try_send for bounded channel (non-blocking)
-                    let _ = c
-                        .sender
-                        .try_send(Ok(warp::ws::Message::text(msg_json.clone())));
-                }
+        client.disconnected_at = None;
+        client.last_seen = now_ms();
+        client.room_id.clone()
+    };
+    info!("Relayed client {} resumed", client_id);
+    if let Some(room_id) = room_id {
+        let client_id = client_id.to_string();
+        rooms
+            .call(&room_id, move |room, clients| {
+                send_catch_up(&client_id, room, clients)
+            })
+            .await;
+    }
+}
+
+/// Current room state and recent chat, for a client returning to `room`.
+fn send_catch_up(client_id: &str, room: &Room, clients: &ClientMap) {
+    send_to_client(
+        client_id,
+        clients,
+        &WsMessage {
+            body: ServerMessage::RoomState(room_state_payload(room)),
+            id: None,
+            room: Some(room.room_id.clone()),
+            client: Some(client_id.to_string()),
+            ts: now_ms(),
+            server_ts: Some(now_ms()),
+        },
+    );
+    send_to_client(client_id, clients, &chat_history_message(room, client_id));
+}
+
+/// Takes `client_id` out of its current room (which closes if it empties).
+pub async fn leave_room(client_id: &str, clients: &Clients, rooms: &Rooms) {
+    let room_id = clients
+        .get_mut(client_id)
+        .and_then(|mut client| client.room_id.take());
+    if let Some(room_id) = room_id {
+        let client_id = client_id.to_string();
+        rooms
+            .call(&room_id, move |room, clients| {
+                handle_leave(room, &client_id, clients)
+            })
+            .await;
+    }
+}
+
+/// Removes `client_id` from `room`, on the room's task. An emptied room is
+/// closed by its task afterwards.
+pub fn handle_leave(room: &mut Room, client_id: &str, clients: &ClientMap) {
+    if let Some(mut client) = clients.get_mut(client_id) {
+        if client.room_id.as_deref() == Some(room.room_id.as_str()) {
+            client.room_id = None;
+        }
+    }
+    if !room.clients.iter().any(|id| id == client_id) {
+        return;
+    }
+    let room_id = room.room_id.clone();
+    room.clients.retain(|id| id != client_id);
+    room.ready_clients.remove(client_id);
+    if room.successor_id.as_deref() == Some(client_id) {
+        room.successor_id = None;
+    }
+    if room.clients.is_empty() {
+        return;
+    }
+    let msg = WsMessage {
+        body: ServerMessage::ClientLeft {
+            participant_count: room.clients.len(),
+        },
+        id: None,
+        room: Some(room_id.clone()),
+        client: Some(client_id.to_string()),
+        ts: now_ms(),
+        server_ts: Some(now_ms()),
+    };
+    broadcast_to_room(room, clients, &msg, None);
+
+    // Promote a remaining participant instead of closing the room.
+    // Playback state and pending_play are kept so the party continues.
+    if room.host_id == client_id {
+        if let Some(new_host) = pick_successor(room) {
+            info!(
+                "Host {} left room {}, promoting {} ({:?})",
+                client_id, room_id, new_host, room.host_policy
+            );
+            room.host_id = new_host.clone();
+            if room.successor_id.as_deref() == Some(new_host.as_str()) {
+                room.successor_id = None;
             }
+            let msg = WsMessage {
+                body: ServerMessage::HostChanged {
+                    host_id: new_host.clone(),
+                    previous_host_id: client_id.to_string(),
+                    policy: room.host_policy,
+                },
+                id: None,
+                room: Some(room_id),
+                client: Some(new_host.clone()),
+                ts: now_ms(),
+                server_ts: Some(now_ms()),
+            };
+            broadcast_to_room(room, clients, &msg, None);
         }
     }
 }
 
-/// Close a room by ID, notifying all participants.
-/// Used when a host creates a new room while one already exists.
-pub async fn close_room(room_id: &str, clients: &Clients, rooms: &Rooms) {
-    let clients_to_notify: Vec<String>;
+/// What a participant's mute is recorded under: the user ID, like bans, so
+/// leaving and rejoining doesn't lift it. Unauthenticated users have no
+/// stable identity and are muted by client ID.
+fn mute_key(client_id: &str, clients: &ClientMap) -> String {
+    clients
+        .get(client_id)
+        .map(|c| c.user_id.clone())
+        .filter(|user_id| !user_id.is_empty() && user_id != "anonymous")
+        .unwrap_or_else(|| client_id.to_string())
+}
+
+pub fn is_muted(room: &Room, client_id: &str, clients: &ClientMap) -> bool {
+    room.muted.contains(&mute_key(client_id, clients))
+}
 
-    {
-        let mut locked_rooms = rooms.write().await;
-        let locked_clients = clients.read().await;
+/// Applies a moderation action by the host (or a server admin) to `target_id`
+/// in `room` (on the room's task). The target gets a direct notice, the rest of
+/// the room a `participant_moderated` broadcast; kicked and banned participants
+/// are then removed via `handle_leave`.
+pub fn moderate(
+    room: &mut Room,
+    moderator_id: &str,
+    target_id: &str,
+    action: ModerationAction,
+    clients: &ClientMap,
+) -> Result<(), ServerError> {
+    let room_id = room.room_id.clone();
+    let is_admin = |id: &str| clients.get(id).is_some_and(|c| c.permissions.admin);
+    let moderator_is_admin = is_admin(moderator_id);
+    if room.host_id != moderator_id && !moderator_is_admin {
+        return Err(ServerError::new(
+            ErrorCode::NotHost,
+            "Only the host can moderate participants",
+        ));
+    }
+    if target_id == moderator_id {
+        return Err(ServerError::new(
+            ErrorCode::InvalidRequest,
+            "Cannot moderate yourself",
+        ));
+    }
+    if !room.clients.iter().any(|id| id == target_id) {
+        return Err(ServerError::new(
+            ErrorCode::NotInRoom,
+            "Client is not in this room",
+        ));
+    }
+    if is_admin(target_id) && !moderator_is_admin {
+        return Err(ServerError::new(
+            ErrorCode::PermissionDenied,
+            "Server admins cannot be moderated",
+        ));
+    }
+    let (target_user_id, target_name) = clients
+        .get(target_id)
+        .map(|c| (c.user_id.clone(), c.user_name.clone()))
+        .unwrap_or_default();
 
-        if let Some(room) = locked_rooms.remove(room_id) {
-            info!("Closing room {} (host creating new room)", room_id);
-            clients_to_notify = room.clients.clone();
+    let notice: fn(ModerationNotice) -> ServerMessage = match action {
+        ModerationAction::Kick => ServerMessage::Kicked,
+        ModerationAction::Ban => {
+            // Without a real identity a ban would hit every anonymous user
+            if target_user_id.is_empty() || target_user_id == "anonymous" {
+                return Err(ServerError::new(
+                    ErrorCode::InvalidRequest,
+                    "Cannot ban an unauthenticated user, kick instead",
+                ));
+            }
+            let moderator_user_id = clients.get(moderator_id).map(|c| c.user_id.clone());
+            if moderator_user_id.as_deref() == Some(target_user_id.as_str()) {
+                return Err(ServerError::new(
+                    ErrorCode::InvalidRequest,
+                    "Cannot ban your own user",
+                ));
+            }
+            room.banned_users.insert(target_user_id.clone());
+            ServerMessage::Banned
+        }
+        ModerationAction::Mute => {
+            room.muted.insert(mute_key(target_id, clients));
+            ServerMessage::MuteStatus
+        }
+        ModerationAction::Unmute => {
+            room.muted.remove(&mute_key(target_id, clients));
+            ServerMessage::MuteStatus
+        }
+    };
+    info!(
+        "Client {} applied {:?} to {} in room {}",
+        moderator_id, action, target_id, room_id
+    );
 
+    send_to_client(
+        target_id,
+        clients,
+        &WsMessage {
+            body: notice(ModerationNotice {
+                action,
+                muted: is_muted(room, target_id, clients),
+            }),
+            id: None,
+            room: Some(room_id.clone()),
+            client: Some(target_id.to_string()),
+            ts: now_ms(),
+            server_ts: Some(now_ms()),
+        },
+    );
+    broadcast_to_room(
+        room,
+        clients,
+        &WsMessage {
+            body: ServerMessage::ParticipantModerated {
+                action,
+                client_id: target_id.to_string(),
+                user_name: target_name,
+            },
+            id: None,
+            room: Some(room_id),
+            client: Some(target_id.to_string()),
+            ts: now_ms(),
+            server_ts: Some(now_ms()),
+        },
+        Some(target_id),
+    );
+
+    if matches!(action, ModerationAction::Kick | ModerationAction::Ban) {
+        handle_leave(room, target_id, clients);
+    }
+    Ok(())
+}
+
+/// Close a room by ID, notifying all participants.
+/// Used when a host creates a new room while one already exists.
+pub async fn close_room(
+    room_id: &str,
+    reason: &str,
+    clients: &Clients,
+    rooms: &Rooms,
+    remote_rooms: &RemoteRooms,
+) {
+    let Some(handle) = rooms.remove(room_id) else {
+        return;
+    };
+    info!("Closing room {} ({})", room_id, reason);
+
+    let reason = reason.to_string();
+    handle
+        .call(move |room, clients| {
             // Notify all participants that the room is closed
             let msg = WsMessage {
-                msg_type: "room_closed".to_string(),
-                room: Some(room_id.to_string()),
+                body: ServerMessage::RoomClosed {
+                    reason: reason.to_string(),
+                },
+                id: None,
+                room: Some(room.room_id.clone()),
                 client: None,
-                payload: Some(serde_json::json!({ "reason": "Host started a new room" })),
                 ts: now_ms(),
                 server_ts: Some(now_ms()),
             };
-
-            for cid in &clients_to_notify {
-                send_to_client(cid, &locked_clients, &msg);
-            }
-
-            // Clear room_id from all clients that were in this room
-            drop(locked_clients);
-            let mut locked_clients = clients.write().await;
-            for cid in &clients_to_notify {
-                if let Some(client) = locked_clients.get_mut(cid) {
-                    if client.room_id.as_deref() == Some(room_id) {
+            for cid in &room.clients {
+                send_to_client(cid, clients, &msg);
+                // Clear room_id from all clients that were in this room
+                if let Some(mut client) = clients.get_mut(cid) {
+                    if client.room_id.as_deref() == Some(room.room_id.as_str()) {
                         client.room_id = None;
                     }
                 }
             }
-        } else {
-            return;
+            // Emptied, the room's task ends
+            room.clients.clear();
+        })
+        .await;
+
+    broadcast_room_list(clients, rooms, remote_rooms);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::LEGACY_PROTOCOL_VERSION;
+    use crate::types::{Client, PendingPlay, PlaybackState, RoomVisibility};
+    use std::collections::{HashMap, HashSet};
+    use tokio::sync::mpsc;
+
+    fn test_client(
+        room_id: &str,
+    ) -> (
+        Client,
+        mpsc::Receiver<Result<warp::ws::Message, warp::Error>>,
+    ) {
+        let (sender, rx) = mpsc::channel(16);
+        let client = Client {
+            sender,
+            room_id: Some(room_id.to_string()),
+            user_id: String::new(),
+            user_name: String::new(),
+            authenticated: true,
+            message_count: 0,
+            last_reset: 0,
+            last_seen: 0,
+            resume_token: String::new(),
+            disconnected_at: None,
+            clock: Default::default(),
+            owner_node: None,
+            origin_node: None,
+            protocol_version: LEGACY_PROTOCOL_VERSION,
+            token_exp: None,
+            token_warned: false,
+            token_jti: None,
+            permissions: Default::default(),
+            user_slot: None,
+        };
+        (client, rx)
+    }
+
+    fn test_room(ids: &[&str], policy: HostTransferPolicy) -> Room {
+        Room {
+            room_id: "room".to_string(),
+            name: "Test".to_string(),
+            host_id: ids[0].to_string(),
+            media_id: None,
+            queue: Vec::new(),
+            clients: ids.iter().map(|s| s.to_string()).collect(),
+            ready_clients: HashSet::new(),
+            pending_play: None,
+            state: PlaybackState {
+                position: 42.0,
+                play_state: "playing".to_string(),
+            },
+            host_policy: policy,
+            successor_id: None,
+            control_mode: ControlMode::HostOnly,
+            controllers: HashSet::new(),
+            visibility: RoomVisibility::Public,
+            password_hash: None,
+            invite_codes: HashSet::new(),
+            banned_users: HashSet::new(),
+            muted: HashSet::new(),
+            chat_history: Default::default(),
+            position_ts: 0,
+            last_state_ts: 0,
+            last_command_ts: 0,
+            last_command_by: None,
+        }
+    }
+
+    #[test]
+    fn test_edit_queue() {
+        let mut room = test_room(&["host"], HostTransferPolicy::OldestMember);
+        let add = |id: &str, index| QueueEdit::Add {
+            media_id: id.to_string(),
+            index,
+        };
+        edit_queue(&mut room, add("a", None)).unwrap();
+        edit_queue(&mut room, add("c", None)).unwrap();
+        edit_queue(&mut room, add("b", Some(1))).unwrap();
+        edit_queue(&mut room, add("d", Some(99))).unwrap(); // Clamped to the end
+        assert_eq!(room.queue, ["a", "b", "c", "d"]);
+
+        edit_queue(&mut room, QueueEdit::Move { from: 3, to: 0 }).unwrap();
+        assert_eq!(room.queue, ["d", "a", "b", "c"]);
+        edit_queue(&mut room, QueueEdit::Remove(1)).unwrap();
+        assert_eq!(room.queue, ["d", "b", "c"]);
+
+        assert!(edit_queue(&mut room, QueueEdit::Remove(3)).is_err());
+        assert!(edit_queue(&mut room, QueueEdit::Move { from: 0, to: 3 }).is_err());
+        assert_eq!(room.queue, ["d", "b", "c"]);
+    }
+
+    #[test]
+    fn test_advance_queue_resets_playback() {
+        let mut room = test_room(&["host", "guest"], HostTransferPolicy::OldestMember);
+        room.media_id = Some("current".to_string());
+        room.queue = vec!["next".to_string(), "later".to_string()];
+        room.ready_clients.insert("guest".to_string());
+        room.pending_play = Some(PendingPlay {
+            position: 10.0,
+            created_at: 0,
+        });
+
+        assert_eq!(advance_queue(&mut room, 5_000).as_deref(), Some("next"));
+        assert_eq!(room.media_id.as_deref(), Some("next"));
+        assert_eq!(room.queue, ["later"]);
+        assert!(room.ready_clients.is_empty());
+        assert!(room.pending_play.is_none());
+        assert_eq!(room.state.position, 0.0);
+        assert_eq!(room.state.play_state, "paused");
+        assert_eq!(room.position_ts, 5_000);
+
+        advance_queue(&mut room, 6_000);
+        assert_eq!(advance_queue(&mut room, 7_000), None);
+        assert_eq!(room.media_id.as_deref(), Some("later"));
+    }
+
+    #[test]
+    fn test_expected_state_extrapolates_while_playing() {
+        let mut room = test_room(&["a"], HostTransferPolicy::OldestMember);
+        room.position_ts = 10_000;
+        assert_eq!(expected_state(&room, 12_500).position, 44.5);
+
+        // Scheduled play that has not started yet
+        assert_eq!(expected_state(&room, 9_000).position, 42.0);
+
+        room.state.play_state = "paused".to_string();
+        assert_eq!(expected_state(&room, 12_500).position, 42.0);
+    }
+
+    #[test]
+    fn test_pick_successor_oldest_member() {
+        let mut room = test_room(&["a", "b", "c"], HostTransferPolicy::OldestMember);
+        room.clients.remove(0);
+        assert_eq!(pick_successor(&room), Some("b".to_string()));
+    }
+
+    #[test]
+    fn test_pick_successor_first_ready() {
+        let mut room = test_room(&["a", "b", "c"], HostTransferPolicy::FirstReady);
+        room.clients.remove(0);
+        room.ready_clients.insert("c".to_string());
+        assert_eq!(pick_successor(&room), Some("c".to_string()));
+
+        // Nobody ready: fall back to oldest member
+        room.ready_clients.clear();
+        assert_eq!(pick_successor(&room), Some("b".to_string()));
+    }
+
+    #[test]
+    fn test_pick_successor_designated() {
+        let mut room = test_room(&["a", "b", "c"], HostTransferPolicy::Designated);
+        room.clients.remove(0);
+        room.successor_id = Some("c".to_string());
+        assert_eq!(pick_successor(&room), Some("c".to_string()));
+
+        // Designated successor no longer in the room
+        room.successor_id = Some("gone".to_string());
+        assert_eq!(pick_successor(&room), Some("b".to_string()));
+    }
+
+    #[test]
+    fn test_can_control_modes() {
+        let mut room = test_room(&["host", "a", "b"], HostTransferPolicy::OldestMember);
+        assert!(can_control(&room, "host"));
+        assert!(!can_control(&room, "a"));
+
+        room.control_mode = ControlMode::Everyone;
+        assert!(can_control(&room, "a"));
+        assert!(!can_control(&room, "outsider"));
+
+        room.control_mode = ControlMode::AllowList;
+        room.controllers.insert("b".to_string());
+        assert!(can_control(&room, "host"));
+        assert!(!can_control(&room, "a"));
+        assert!(can_control(&room, "b"));
+
+        // Allow-listed clients lose control once they leave
+        room.clients.retain(|id| id != "b");
+        assert!(!can_control(&room, "b"));
+    }
+
+    #[test]
+    fn test_host_leave_promotes_successor() {
+        let clients = ClientMap::new();
+        let mut receivers = Vec::new();
+        for id in ["a", "b"] {
+            let (client, rx) = test_client("room");
+            clients.insert(id.to_string(), client);
+            receivers.push(rx);
+        }
+        let mut room = test_room(&["a", "b"], HostTransferPolicy::OldestMember);
+
+        handle_leave(&mut room, "a", &clients);
+
+        assert_eq!(room.host_id, "b");
+        assert_eq!(room.state.position, 42.0);
+        assert_eq!(room.state.play_state, "playing");
+        assert_eq!(clients.get("a").unwrap().room_id, None);
+
+        let mut types = Vec::new();
+        while let Ok(Ok(msg)) = receivers[1].try_recv() {
+            let parsed: serde_json::Value = serde_json::from_str(msg.to_str().unwrap()).unwrap();
+            types.push(parsed["type"].as_str().unwrap().to_string());
+        }
+        assert_eq!(types, vec!["client_left", "host_changed"]);
+    }
+
+    #[tokio::test]
+    async fn test_last_leave_removes_room() {
+        let clients: Clients = Default::default();
+        let rooms: Rooms = Default::default();
+        let (client, _rx) = test_client("room");
+        clients.insert("a".to_string(), client);
+        rooms.spawn(
+            test_room(&["a"], HostTransferPolicy::OldestMember),
+            &clients,
+        );
+
+        leave_room("a", &clients, &rooms).await;
+        assert!(!rooms.contains("room"));
+        assert_eq!(clients.get("a").unwrap().room_id, None);
+    }
+
+    #[tokio::test]
+    async fn test_resume_reclaims_session() {
+        let clients: Clients = Default::default();
+        let rooms: Rooms = Default::default();
+        let (mut old, _old_rx) = test_client("room");
+        old.resume_token = "secret".to_string();
+        old.disconnected_at = Some(now_ms());
+        let (mut new, mut new_rx) = test_client("room");
+        new.room_id = None;
+        clients.insert("old".to_string(), old);
+        clients.insert("new".to_string(), new);
+        rooms.spawn(
+            test_room(&["old"], HostTransferPolicy::OldestMember),
+            &clients,
+        );
+
+        assert_eq!(
+            handle_resume("new", "wrong", &clients, &rooms, 30_000).await,
+            None
+        );
+        let resumed = handle_resume("new", "secret", &clients, &rooms, 30_000).await;
+        assert_eq!(resumed.as_deref(), Some("old"));
+
+        assert!(!clients.contains_key("new"));
+        {
+            let client = clients.get("old").unwrap();
+            assert!(client.disconnected_at.is_none());
+            assert_ne!(client.resume_token, "secret");
+        }
+        assert_eq!(rooms.get("room").unwrap().summary().host_id, "old");
+
+        let mut types = Vec::new();
+        while let Ok(Ok(msg)) = new_rx.try_recv() {
+            let parsed: serde_json::Value = serde_json::from_str(msg.to_str().unwrap()).unwrap();
+            types.push(parsed["type"].as_str().unwrap().to_string());
+        }
+        assert_eq!(types, vec!["session_resumed", "room_state", "chat_history"]);
+    }
+
+    #[tokio::test]
+    async fn test_resume_rejects_expired_session() {
+        let clients: Clients = Default::default();
+        let rooms: Rooms = Default::default();
+        let (mut old, _old_rx) = test_client("room");
+        old.resume_token = "secret".to_string();
+        old.disconnected_at = Some(now_ms() - 60_000);
+        let (new, _new_rx) = test_client("room");
+        clients.insert("old".to_string(), old);
+        clients.insert("new".to_string(), new);
+
+        assert_eq!(
+            handle_resume("new", "secret", &clients, &rooms, 30_000).await,
+            None
+        );
+        assert!(clients.contains_key("new"));
+    }
+
+    #[tokio::test]
+    async fn test_resume_leaves_room_joined_meanwhile() {
+        let clients: Clients = Default::default();
+        let rooms: Rooms = Default::default();
+        let (mut old, _old_rx) = test_client("room");
+        old.resume_token = "secret".to_string();
+        old.disconnected_at = Some(now_ms());
+        let (new, _new_rx) = test_client("other");
+        let (host, _host_rx) = test_client("other");
+        clients.insert("old".to_string(), old);
+        clients.insert("new".to_string(), new);
+        clients.insert("host".to_string(), host);
+        rooms.spawn(
+            test_room(&["old"], HostTransferPolicy::OldestMember),
+            &clients,
+        );
+        let mut other = test_room(&["host", "new"], HostTransferPolicy::OldestMember);
+        other.room_id = "other".to_string();
+        rooms.spawn(other, &clients);
+
+        let resumed = handle_resume("new", "secret", &clients, &rooms, 30_000).await;
+        assert_eq!(resumed.as_deref(), Some("old"));
+        let members = rooms
+            .call("other", |room, _| room.clients.clone())
+            .await
+            .unwrap();
+        assert_eq!(members, ["host"]);
+        assert_eq!(clients.get("old").unwrap().room_id.as_deref(), Some("room"));
+    }
+
+    #[tokio::test]
+    async fn test_resume_detaches_live_socket() {
+        let clients: Clients = Default::default();
+        let rooms: Rooms = Default::default();
+        let (mut old, mut old_rx) = test_client("room");
+        old.resume_token = "secret".to_string();
+        let (mut new, _new_rx) = test_client("room");
+        new.room_id = None;
+        let new_sender = new.sender.clone();
+        clients.insert("old".to_string(), old);
+        clients.insert("new".to_string(), new);
+        rooms.spawn(
+            test_room(&["old"], HostTransferPolicy::OldestMember),
+            &clients,
+        );
+
+        let resumed = handle_resume("new", "secret", &clients, &rooms, 30_000).await;
+        assert_eq!(resumed.as_deref(), Some("old"));
+        // The old socket is told to close and no longer receives the session's messages
+        assert!(old_rx.try_recv().unwrap().unwrap().is_close());
+        assert!(old_rx.try_recv().is_err());
+        assert!(clients.get("old").unwrap().sender.same_channel(&new_sender));
+    }
+
+    #[test]
+    fn test_moderate_ban_removes_and_records_user() {
+        let clients = ClientMap::new();
+        let mut receivers = HashMap::new();
+        for (id, user) in [("host", "u-host"), ("a", "u-a"), ("b", "u-b")] {
+            let (mut client, rx) = test_client("room");
+            client.user_id = user.to_string();
+            clients.insert(id.to_string(), client);
+            receivers.insert(id, rx);
+        }
+        let mut room = test_room(&["host", "a", "b"], HostTransferPolicy::OldestMember);
+
+        assert_eq!(
+            moderate(&mut room, "a", "b", ModerationAction::Kick, &clients)
+                .unwrap_err()
+                .code,
+            ErrorCode::NotHost
+        );
+        assert!(moderate(&mut room, "host", "a", ModerationAction::Ban, &clients).is_ok());
+
+        assert!(room.banned_users.contains("u-a"));
+        assert!(!room.clients.contains(&"a".to_string()));
+        assert_eq!(clients.get("a").unwrap().room_id, None);
+
+        let msg = receivers.get_mut("a").unwrap().try_recv().unwrap().unwrap();
+        let parsed: WsMessage = serde_json::from_str(msg.to_str().unwrap()).unwrap();
+        assert_eq!(
+            parsed.body,
+            ServerMessage::Banned(ModerationNotice {
+                action: ModerationAction::Ban,
+                muted: false
+            })
+        );
+    }
+
+    #[test]
+    fn test_may_control_follows_permissions() {
+        let room = test_room(&["host", "a"], HostTransferPolicy::OldestMember);
+        let viewer = Permissions {
+            can_control: false,
+            ..Default::default()
+        };
+        assert!(may_control(&room, "host", Permissions::default()));
+        assert!(!may_control(&room, "host", viewer));
+        assert!(!may_control(&room, "a", Permissions::default()));
+        assert!(may_control(&room, "a", Permissions::ADMIN));
+        assert!(!may_control(&room, "outsider", Permissions::ADMIN));
+    }
+
+    #[test]
+    fn test_moderate_by_server_admin() {
+        let clients = ClientMap::new();
+        for id in ["host", "a", "admin"] {
+            let (mut client, _rx) = test_client("room");
+            if id == "admin" {
+                client.permissions = Permissions::ADMIN;
+            }
+            clients.insert(id.to_string(), client);
+        }
+        let mut room = test_room(&["host", "a", "admin"], HostTransferPolicy::OldestMember);
+
+        assert_eq!(
+            moderate(&mut room, "host", "admin", ModerationAction::Kick, &clients)
+                .unwrap_err()
+                .code,
+            ErrorCode::PermissionDenied
+        );
+        moderate(&mut room, "admin", "a", ModerationAction::Mute, &clients).unwrap();
+        assert!(is_muted(&room, "a", &clients));
+        moderate(&mut room, "admin", "host", ModerationAction::Kick, &clients).unwrap();
+        assert!(!room.clients.contains(&"host".to_string()));
+    }
+
+    #[test]
+    fn test_moderate_mute_keeps_participant() {
+        let clients = ClientMap::new();
+        for id in ["host", "a"] {
+            let (client, _rx) = test_client("room");
+            clients.insert(id.to_string(), client);
         }
+        let mut room = test_room(&["host", "a"], HostTransferPolicy::OldestMember);
+
+        moderate(&mut room, "host", "a", ModerationAction::Mute, &clients).unwrap();
+        assert!(is_muted(&room, "a", &clients));
+        assert!(room.clients.contains(&"a".to_string()));
+
+        moderate(&mut room, "host", "a", ModerationAction::Unmute, &clients).unwrap();
+        assert!(!is_muted(&room, "a", &clients));
     }
 
-    broadcast_room_list(clients, rooms).await;
+    #[test]
+    fn test_mute_survives_rejoin() {
+        let clients = ClientMap::new();
+        for (id, user) in [("host", "u-host"), ("a", "u-a"), ("b", "u-b")] {
+            let (mut client, _rx) = test_client("room");
+            client.user_id = user.to_string();
+            clients.insert(id.to_string(), client);
+        }
+        let mut room = test_room(&["host", "a", "b"], HostTransferPolicy::OldestMember);
+        moderate(&mut room, "host", "a", ModerationAction::Mute, &clients).unwrap();
+
+        // Same user, back on a new connection
+        handle_leave(&mut room, "a", &clients);
+        let (mut client, _rx) = test_client("room");
+        client.user_id = "u-a".to_string();
+        clients.insert("a2".to_string(), client);
+        room.clients.push("a2".to_string());
+        assert!(is_muted(&room, "a2", &clients));
+        assert!(!is_muted(&room, "b", &clients));
+
+        moderate(&mut room, "host", "a2", ModerationAction::Unmute, &clients).unwrap();
+        assert!(!is_muted(&room, "a2", &clients));
+    }
 }
@@ -0,0 +1,252 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// Number of completed exchanges kept per client (rolling window)
+const MAX_SAMPLES: usize = 8;
+/// Exchanges slower than this are discarded as unusable
+const MAX_SAMPLE_RTT_MS: i64 = 10_000;
+
+// Scheduling lead-time defaults (see `[sync]` in the config file)
+const DEFAULT_SCHEDULE_MARGIN_MS: u64 = 100;
+const DEFAULT_SCHEDULE_FLOOR_MS: u64 = 100;
+const DEFAULT_SCHEDULE_CEILING_MS: u64 = 2000;
+
+/// Result of one NTP-style exchange.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockSample {
+    pub rtt_ms: i64,
+    /// Server clock minus client clock
+    pub offset_ms: i64,
+}
+
+/// Computes RTT and clock offset from the four exchange timestamps:
+/// client send (t0), server receive (t1), server send (t2), client receive (t3).
+/// Returns None for impossible or unusably slow exchanges.
+pub fn compute_sample(t0: u64, t1: u64, t2: u64, t3: u64) -> Option<ClockSample> {
+    let (t0, t1, t2, t3) = (t0 as i64, t1 as i64, t2 as i64, t3 as i64);
+    let rtt_ms = (t3 - t0) - (t2 - t1);
+    if t3 < t0 || t2 < t1 || !(0..=MAX_SAMPLE_RTT_MS).contains(&rtt_ms) {
+        return None;
+    }
+    let offset_ms = ((t1 - t0) + (t2 - t3)) / 2;
+    Some(ClockSample { rtt_ms, offset_ms })
+}
+
+/// A client's measured sync quality, as reported in `sync_quality`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ClockQuality {
+    pub rtt_ms: Option<u64>,
+    pub offset_ms: Option<i64>,
+    pub jitter_ms: Option<u64>,
+    pub samples: usize,
+}
+
+/// Rolling RTT and clock offset estimate for one client, fed by `time_sync`.
+///
+/// The server answers each `time_sync` with its receive/send timestamps and
+/// remembers them; the client's next `time_sync` reports when that reply
+/// arrived, completing the exchange. Server timestamps are never taken from
+/// the client, but its send and receive times are, so a client can still
+/// inflate its RTT. A sample is rejected if it claims a longer round trip
+/// than the server saw between sending the reply and hearing back, which
+/// bounds the lie by how long the client waits before its next exchange.
+#[derive(Debug, Clone, Default)]
+pub struct ClockSync {
+    samples: VecDeque<ClockSample>,
+    /// Outstanding exchange: (client send, server receive, server send)
+    pending: Option<(u64, u64, u64)>,
+}
+
+impl ClockSync {
+    /// Records the server side of an exchange awaiting the client's receive time.
+    pub fn start(&mut self, client_send_ts: u64, server_recv_ts: u64, server_send_ts: u64) {
+        self.pending = Some((client_send_ts, server_recv_ts, server_send_ts));
+    }
+
+    /// Completes the outstanding exchange if `client_send_ts` matches it.
+    /// `server_recv_ts` is when the message reporting `client_recv_ts` arrived.
+    pub fn complete(
+        &mut self,
+        client_send_ts: u64,
+        client_recv_ts: u64,
+        server_recv_ts: u64,
+    ) -> Option<ClockSample> {
+        let (t0, t1, t2) = self.pending.filter(|(t0, _, _)| *t0 == client_send_ts)?;
+        self.pending = None;
+        let sample = compute_sample(t0, t1, t2, client_recv_ts)?;
+        // The reply went out at t2 and the report came back at
+        // server_recv_ts; no real round trip takes longer than that
+        if sample.rtt_ms > server_recv_ts.saturating_sub(t2) as i64 {
+            return None;
+        }
+        if self.samples.len() >= MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+        Some(sample)
+    }
+
+    /// Lowest-RTT sample in the window (least affected by queuing delay).
+    fn best(&self) -> Option<&ClockSample> {
+        self.samples.iter().min_by_key(|s| s.rtt_ms)
+    }
+
+    pub fn rtt_ms(&self) -> Option<u64> {
+        self.best().map(|s| s.rtt_ms as u64)
+    }
+
+    pub fn offset_ms(&self) -> Option<i64> {
+        self.best().map(|s| s.offset_ms)
+    }
+
+    /// Spread between the fastest and slowest exchange in the window.
+    pub fn jitter_ms(&self) -> Option<u64> {
+        let min = self.samples.iter().map(|s| s.rtt_ms).min()?;
+        let max = self.samples.iter().map(|s| s.rtt_ms).max()?;
+        Some((max - min) as u64)
+    }
+
+    /// Summary sent to hosts in `sync_quality`.
+    pub fn quality(&self) -> ClockQuality {
+        ClockQuality {
+            rtt_ms: self.rtt_ms(),
+            offset_ms: self.offset_ms(),
+            jitter_ms: self.jitter_ms(),
+            samples: self.samples.len(),
+        }
+    }
+}
+
+/// Bounds for the lead time given to scheduled play/pause/seek commands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScheduleConfig {
+    pub margin_ms: u64,
+    pub floor_ms: u64,
+    pub ceiling_ms: u64,
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        Self {
+            margin_ms: DEFAULT_SCHEDULE_MARGIN_MS,
+            floor_ms: DEFAULT_SCHEDULE_FLOOR_MS,
+            ceiling_ms: DEFAULT_SCHEDULE_CEILING_MS,
+        }
+    }
+}
+
+impl ScheduleConfig {
+    /// Slowest RTT among participants, leaving out anyone above the ceiling.
+    /// Their commands arrive late whatever we pick, so one slow (or lying)
+    /// client shouldn't push everyone else's lead time to the maximum.
+    pub fn worst_rtt_ms(&self, rtts: impl IntoIterator<Item = u64>) -> Option<u64> {
+        rtts.into_iter().filter(|rtt| *rtt <= self.ceiling_ms).max()
+    }
+
+    /// Lead time for a scheduled command given the slowest participant's RTT.
+    ///
+    /// A full RTT is used rather than the one-way delay: delivery takes about
+    /// RTT/2 and the client's offset estimate can be off by up to RTT/2.
+    /// Falls back to `fallback_ms` while nobody has been measured yet.
+    pub fn delay_ms(&self, worst_rtt_ms: Option<u64>, fallback_ms: u64) -> u64 {
+        worst_rtt_ms
+            .map(|rtt| rtt + self.margin_ms)
+            .unwrap_or(fallback_ms)
+            .clamp(self.floor_ms, self.ceiling_ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compute_sample_symmetric_path() {
+        // Client clock 500ms behind server, 40ms each way, 2ms server processing
+        let sample = compute_sample(1_000, 1_540, 1_542, 1_082).unwrap();
+        assert_eq!(sample.rtt_ms, 80);
+        assert_eq!(sample.offset_ms, 500);
+    }
+
+    #[test]
+    fn test_compute_sample_rejects_invalid() {
+        assert!(compute_sample(1_000, 900, 800, 1_100).is_none()); // Server sent before receiving
+        assert!(compute_sample(1_000, 1_010, 1_020, 900).is_none()); // Reply before request
+        assert!(compute_sample(0, 10, 20, 60_000).is_none()); // Too slow to be useful
+    }
+
+    #[test]
+    fn test_schedule_delay_bounds() {
+        let config = ScheduleConfig {
+            margin_ms: 100,
+            floor_ms: 150,
+            ceiling_ms: 1_500,
+        };
+        assert_eq!(config.delay_ms(Some(10), 300), 150); // LAN: floor
+        assert_eq!(config.delay_ms(Some(400), 300), 500); // Remote: RTT + margin
+        assert_eq!(config.delay_ms(Some(5_000), 300), 1_500); // Capped at ceiling
+        assert_eq!(config.delay_ms(None, 1_000), 1_000); // Unmeasured: fallback
+        assert_eq!(config.delay_ms(None, 3_000), 1_500);
+    }
+
+    #[test]
+    fn test_worst_rtt_ignores_outliers() {
+        let config = ScheduleConfig::default();
+        assert_eq!(config.worst_rtt_ms([40, 250, 90]), Some(250));
+        // A participant claiming 9 seconds doesn't drag the room to the ceiling
+        assert_eq!(config.worst_rtt_ms([40, 9_000, 90]), Some(90));
+        assert_eq!(config.worst_rtt_ms([9_000]), None);
+        assert_eq!(config.worst_rtt_ms([]), None);
+    }
+
+    #[test]
+    fn test_clock_sync_requires_matching_exchange() {
+        let mut clock = ClockSync::default();
+        assert!(clock.complete(1_000, 1_100, 1_700).is_none());
+
+        clock.start(1_000, 1_540, 1_542);
+        assert!(clock.complete(999, 1_082, 1_700).is_none()); // Wrong exchange
+        assert!(clock.complete(1_000, 1_082, 1_700).is_some());
+        assert!(clock.complete(1_000, 1_082, 1_700).is_none()); // Already completed
+        assert_eq!(clock.rtt_ms(), Some(80));
+        assert_eq!(clock.offset_ms(), Some(500));
+    }
+
+    #[test]
+    fn test_clock_sync_rolling_window_prefers_fastest() {
+        let mut clock = ClockSync::default();
+        for (i, rtt) in [300u64, 60, 200, 120].iter().enumerate() {
+            let t0 = i as u64 * 10_000;
+            clock.start(t0, t0 + rtt / 2, t0 + rtt / 2);
+            clock.complete(t0, t0 + rtt, t0 + rtt + 1_000);
+        }
+        assert_eq!(clock.rtt_ms(), Some(60));
+        assert_eq!(clock.jitter_ms(), Some(240));
+
+        for i in 0..MAX_SAMPLES as u64 {
+            let t0 = 100_000 + i * 10_000;
+            clock.start(t0, t0 + 50, t0 + 50);
+            clock.complete(t0, t0 + 100, t0 + 1_100);
+        }
+        // Old samples rolled out of the window
+        assert_eq!(clock.rtt_ms(), Some(100));
+        assert_eq!(clock.jitter_ms(), Some(0));
+    }
+
+    #[test]
+    fn test_clock_sync_rejects_inflated_rtt() {
+        let mut clock = ClockSync::default();
+        // Real round trip ~80ms; the report arrives 300ms after our reply
+        // left, but the client claims its reply took 3 seconds to arrive
+        clock.start(1_000, 1_540, 1_542);
+        assert!(clock.complete(1_000, 4_082, 1_842).is_none());
+        assert_eq!(clock.rtt_ms(), None);
+
+        clock.start(2_000, 2_540, 2_542);
+        assert_eq!(
+            clock.complete(2_000, 2_082, 2_842).map(|s| s.rtt_ms),
+            Some(80)
+        );
+    }
+}
@@ -0,0 +1,352 @@
+use crate::auth::{Claims, TOKEN_LEEWAY_SECS};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// Bounds for the per-node token bookkeeping
+const MAX_USED_TOKENS: usize = 100_000;
+const MAX_REVOCATIONS: usize = 10_000;
+// How long a revoked `jti` is remembered when its expiry is unknown (the
+// plugin's longest token lifetime)
+const REVOKED_JTI_TTL_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// What an admin revocation applies to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Revocation {
+    /// One token
+    Jti(String),
+    /// Every token of a user (JWT `sub`), until the revocation is lifted
+    Sub(String),
+}
+
+/// A `jti` taken by a connection, remembered until the token stops verifying.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsedToken {
+    pub jti: String,
+    pub client_id: String,
+    pub expires_at: u64,
+}
+
+impl UsedToken {
+    /// None for tokens without a `jti`.
+    pub fn new(claims: &Claims, client_id: &str) -> Option<Self> {
+        Some(Self {
+            jti: claims.jti.clone()?,
+            client_id: client_id.to_string(),
+            // The validator still accepts the token for the leeway past `exp`
+            expires_at: (claims.exp as u64 + TOKEN_LEEWAY_SECS) * 1000,
+        })
+    }
+}
+
+/// Copy of a registry: saved with the room snapshot, and handed to nodes
+/// joining a cluster.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TokenSnapshot {
+    used: Vec<UsedToken>,
+    revoked: Vec<(Revocation, Option<u64>)>,
+}
+
+/// Token bookkeeping on top of signature checks: `jti`s are single-use (a
+/// token authenticates one connection) and admins can revoke tokens by
+/// `jti` or `sub`. Each node keeps its own copy; the cluster passes uses and
+/// revocations on to the other nodes (see `Cluster::token_used`).
+#[derive(Default)]
+pub struct TokenRegistry {
+    used: Mutex<HashMap<String, (String, u64)>>, // jti -> client ID and token expiry (ms)
+    revoked: Mutex<HashMap<Revocation, Option<u64>>>, // Expiry (ms); None lasts until lifted
+}
+
+impl TokenRegistry {
+    /// Accepts `claims` for `client_id`: the token must not be revoked, and
+    /// its `jti` must not have been used by another connection. Tokens
+    /// without a `jti` can't be tracked and are only checked by `sub`.
+    pub fn admit(&self, claims: &Claims, client_id: &str, now: u64) -> Result<(), String> {
+        let revoked = self
+            .revoked
+            .lock()
+            .map_err(|_| "Token registry unavailable".to_string())?;
+        let is_revoked = |key: &Revocation| {
+            revoked
+                .get(key)
+                .is_some_and(|expires| expires.is_none_or(|at| at > now))
+        };
+        if is_revoked(&Revocation::Sub(claims.sub.clone())) {
+            return Err(format!("Tokens of {} are revoked", claims.sub));
+        }
+        let Some(token) = UsedToken::new(claims, client_id) else {
+            return Ok(());
+        };
+        if is_revoked(&Revocation::Jti(token.jti.clone())) {
+            return Err(format!("Token {} is revoked", token.jti));
+        }
+        drop(revoked);
+
+        let mut used = self
+            .used
+            .lock()
+            .map_err(|_| "Token registry unavailable".to_string())?;
+        if let Some((owner, expires)) = used.get(&token.jti) {
+            if owner != client_id && *expires > now {
+                return Err(format!(
+                    "Token {} was already used by another connection",
+                    token.jti
+                ));
+            }
+        }
+        Self::insert_used(&mut used, token, now);
+        Ok(())
+    }
+
+    /// Records a use reported by another node. The first use seen wins.
+    pub fn record_used(&self, token: UsedToken, now: u64) {
+        if let Ok(mut used) = self.used.lock() {
+            let taken = used
+                .get(&token.jti)
+                .is_some_and(|(owner, expires)| *owner != token.client_id && *expires > now);
+            if !taken {
+                Self::insert_used(&mut used, token, now);
+            }
+        }
+    }
+
+    fn insert_used(used: &mut HashMap<String, (String, u64)>, token: UsedToken, now: u64) {
+        if used.len() >= MAX_USED_TOKENS {
+            used.retain(|_, (_, expires)| *expires > now);
+        }
+        if used.len() >= MAX_USED_TOKENS {
+            // Still full of live tokens: forget the one closest to expiring
+            if let Some(oldest) = used
+                .iter()
+                .min_by_key(|(_, (_, expires))| *expires)
+                .map(|(jti, _)| jti.clone())
+            {
+                used.remove(&oldest);
+            }
+        }
+        used.insert(token.jti, (token.client_id, token.expires_at));
+    }
+
+    /// Adds a revocation. A revoked `jti` is remembered until its token
+    /// expires (or a day, if it was never seen here). Returns that expiry,
+    /// for passing the revocation on with `apply_revocation`.
+    pub fn revoke(&self, revocation: Revocation, now: u64) -> Result<Option<u64>, String> {
+        let expires = match &revocation {
+            Revocation::Sub(_) => None,
+            Revocation::Jti(jti) => Some(
+                self.used
+                    .lock()
+                    .ok()
+                    .and_then(|used| used.get(jti).map(|(_, expires)| *expires))
+                    .unwrap_or(now + REVOKED_JTI_TTL_MS),
+            ),
+        };
+        self.apply_revocation(revocation, expires, now)?;
+        Ok(expires)
+    }
+
+    /// Adds a revocation lasting until `expires` (None: until lifted).
+    pub fn apply_revocation(
+        &self,
+        revocation: Revocation,
+        expires: Option<u64>,
+        now: u64,
+    ) -> Result<(), String> {
+        let mut revoked = self
+            .revoked
+            .lock()
+            .map_err(|_| "Token registry unavailable".to_string())?;
+        revoked.retain(|_, at| at.is_none_or(|at| at > now));
+        if revoked.len() >= MAX_REVOCATIONS && !revoked.contains_key(&revocation) {
+            return Err(format!(
+                "Revocation list is full ({} entries)",
+                MAX_REVOCATIONS
+            ));
+        }
+        revoked.insert(revocation, expires);
+        Ok(())
+    }
+
+    /// Lifts a revocation; returns false if there was none.
+    pub fn unrevoke(&self, revocation: &Revocation) -> bool {
+        self.revoked
+            .lock()
+            .is_ok_and(|mut revoked| revoked.remove(revocation).is_some())
+    }
+
+    /// Active revocations.
+    pub fn revocations(&self, now: u64) -> Vec<Revocation> {
+        self.revoked
+            .lock()
+            .map(|revoked| {
+                revoked
+                    .iter()
+                    .filter(|(_, at)| at.is_none_or(|at| at > now))
+                    .map(|(revocation, _)| revocation.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Live entries, for persisting or handing to another node.
+    pub fn snapshot(&self, now: u64) -> TokenSnapshot {
+        let used = self
+            .used
+            .lock()
+            .map(|used| {
+                used.iter()
+                    .filter(|(_, (_, expires))| *expires > now)
+                    .map(|(jti, (client_id, expires))| UsedToken {
+                        jti: jti.clone(),
+                        client_id: client_id.clone(),
+                        expires_at: *expires,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let revoked = self
+            .revoked
+            .lock()
+            .map(|revoked| {
+                revoked
+                    .iter()
+                    .filter(|(_, at)| at.is_none_or(|at| at > now))
+                    .map(|(revocation, at)| (revocation.clone(), *at))
+                    .collect()
+            })
+            .unwrap_or_default();
+        TokenSnapshot { used, revoked }
+    }
+
+    /// Merges a snapshot into the registry. Expired entries are dropped.
+    pub fn restore(&self, snapshot: TokenSnapshot, now: u64) {
+        for token in snapshot.used {
+            if token.expires_at > now {
+                self.record_used(token, now);
+            }
+        }
+        for (revocation, expires) in snapshot.revoked {
+            if expires.is_none_or(|at| at > now) {
+                let _ = self.apply_revocation(revocation, expires, now);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(sub: &str, jti: Option<&str>) -> Claims {
+        Claims {
+            sub: sub.to_string(),
+            name: "Alice".to_string(),
+            aud: "test".to_string(),
+            iss: "test".to_string(),
+            exp: 2_000,
+            iat: 0,
+            jti: jti.map(str::to_string),
+            roles: Vec::new(),
+            permissions: None,
+        }
+    }
+
+    #[test]
+    fn test_jti_is_single_use() {
+        let registry = TokenRegistry::default();
+        let token = claims("user-1", Some("t1"));
+        registry.admit(&token, "a", 1_000).unwrap();
+        // The same connection may present it again (e.g. `reauth`)
+        registry.admit(&token, "a", 1_000).unwrap();
+        assert!(registry.admit(&token, "b", 1_000).is_err());
+        // Forgotten once the token has expired anyway
+        registry.admit(&token, "b", 2_100_000).unwrap();
+        // Untracked without a jti
+        registry.admit(&claims("user-1", None), "c", 1_000).unwrap();
+    }
+
+    #[test]
+    fn test_jti_kept_through_leeway() {
+        let registry = TokenRegistry::default();
+        let token = claims("user-1", Some("t1"));
+        registry.admit(&token, "a", 1_000).unwrap();
+        // Past `exp` but inside the validator's leeway, the token still
+        // verifies, so it must still count as used
+        let exp_ms = token.exp as u64 * 1000;
+        assert!(registry.admit(&token, "b", exp_ms + 1_000).is_err());
+        assert!(registry
+            .admit(&token, "b", exp_ms + TOKEN_LEEWAY_SECS * 1000 - 1)
+            .is_err());
+        registry
+            .admit(&token, "b", exp_ms + TOKEN_LEEWAY_SECS * 1000)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let registry = TokenRegistry::default();
+        registry
+            .admit(&claims("user-1", Some("t1")), "a", 1_000)
+            .unwrap();
+        registry
+            .revoke(Revocation::Sub("user-2".to_string()), 1_000)
+            .unwrap();
+        let json = serde_json::to_string(&registry.snapshot(1_000)).unwrap();
+
+        let restored = TokenRegistry::default();
+        restored.restore(serde_json::from_str(&json).unwrap(), 1_000);
+        assert!(restored
+            .admit(&claims("user-1", Some("t1")), "b", 1_000)
+            .is_err());
+        assert!(restored
+            .admit(&claims("user-2", Some("t2")), "c", 1_000)
+            .is_err());
+        // Uses reported by other nodes don't displace a live local one
+        let token = UsedToken::new(&claims("user-1", Some("t1")), "z").unwrap();
+        restored.record_used(token, 1_000);
+        restored
+            .admit(&claims("user-1", Some("t1")), "a", 1_000)
+            .unwrap();
+        // Nothing expired is carried over
+        let late = TokenRegistry::default();
+        late.restore(registry.snapshot(1_000), 2_100_000);
+        assert!(late.snapshot(2_100_000).used.is_empty());
+    }
+
+    #[test]
+    fn test_revocations() {
+        let registry = TokenRegistry::default();
+        registry
+            .admit(&claims("user-1", Some("t1")), "a", 1_000)
+            .unwrap();
+
+        registry
+            .revoke(Revocation::Jti("t1".to_string()), 1_000)
+            .unwrap();
+        assert!(registry
+            .admit(&claims("user-1", Some("t1")), "a", 1_000)
+            .is_err());
+        registry
+            .admit(&claims("user-1", Some("t2")), "a", 1_000)
+            .unwrap();
+        // The jti entry lapses with the token
+        assert!(registry.revocations(2_100_000).is_empty());
+
+        let sub = Revocation::Sub("user-1".to_string());
+        registry.revoke(sub.clone(), 1_000).unwrap();
+        assert!(registry
+            .admit(&claims("user-1", Some("t3")), "a", 1_000)
+            .is_err());
+        assert!(registry
+            .admit(&claims("user-2", Some("t4")), "b", 1_000)
+            .is_ok());
+        assert_eq!(registry.revocations(2_100_000), vec![sub.clone()]);
+
+        assert!(registry.unrevoke(&sub));
+        assert!(!registry.unrevoke(&sub));
+        assert!(registry
+            .admit(&claims("user-1", Some("t3")), "a", 1_000)
+            .is_ok());
+    }
+}
@@ -1,10 +1,24 @@
+use crate::actor::RoomRegistry;
+use crate::auth::Permissions;
+use crate::chat::{ChatEntry, ChatHistory};
+use crate::connections::ConnectionSlot;
+use crate::error::ErrorCode;
+use crate::messaging::RoomListItem;
+use crate::protocol::{ProtocolInfo, LEGACY_PROTOCOL_VERSION};
+use crate::timesync::{ClockQuality, ClockSync};
+use crate::utils::now_ms;
+use dashmap::DashMap;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::borrow::Cow;
+use std::collections::HashSet;
 use std::sync::Arc;
-use tokio::sync::{mpsc, RwLock};
+use tokio::sync::mpsc;
 
-pub type Clients = Arc<RwLock<HashMap<String, Client>>>;
-pub type Rooms = Arc<RwLock<HashMap<String, Room>>>;
+// Never hold an entry across an `.await` or while taking another one
+pub type ClientMap = DashMap<String, Client>;
+pub type Clients = Arc<ClientMap>;
+pub type Rooms = Arc<RoomRegistry>;
 
 #[derive(Debug, Clone)]
 pub struct Client {
@@ -16,7 +30,50 @@ pub struct Client {
     pub authenticated: bool, // Whether client has authenticated via auth message
     pub message_count: u32,
     pub last_reset: u64,
-    pub last_seen: u64, // For zombie connection detection
+    pub last_seen: u64,                         // For zombie connection detection
+    pub resume_token: String, // Secret used to reclaim this session after a reconnect
+    pub disconnected_at: Option<u64>, // Set while the socket is gone but the session is held
+    pub clock: ClockSync,     // Rolling RTT/offset estimate from `time_sync`
+    pub owner_node: Option<String>, // Cluster node owning this client's room; frames are forwarded there
+    pub origin_node: Option<String>, // Cluster node holding this client's socket (relayed client)
+    pub protocol_version: u32,      // Negotiated during `auth` (legacy clients never declare one)
+    pub token_exp: Option<u64>,     // JWT expiry (ms); None when the identity never expires
+    pub token_warned: bool,         // `token_expiring` already sent for the current token
+    pub token_jti: Option<String>,  // ID of the current token, matched by revocations
+    pub permissions: Permissions,   // From the token's `roles`/`permissions` claims
+    pub user_slot: Option<Arc<ConnectionSlot>>, // Counts this socket against its user's connection cap
+}
+
+impl Client {
+    /// A client whose socket is held by `origin_node`, sending through
+    /// `sender`. Unauthenticated until the caller fills in its identity.
+    pub fn relayed(
+        sender: mpsc::Sender<std::result::Result<warp::ws::Message, warp::Error>>,
+        origin_node: String,
+    ) -> Self {
+        let now = now_ms();
+        Client {
+            sender,
+            room_id: None,
+            user_id: String::new(),
+            user_name: String::new(),
+            authenticated: false,
+            message_count: 0,
+            last_reset: now,
+            last_seen: now,
+            resume_token: String::new(), // Resumes happen on the origin node
+            disconnected_at: None,
+            clock: Default::default(),
+            owner_node: None,
+            origin_node: Some(origin_node),
+            protocol_version: LEGACY_PROTOCOL_VERSION,
+            token_exp: None, // Expiry is enforced by the origin node
+            token_warned: false,
+            token_jti: None,
+            permissions: Default::default(),
+            user_slot: None, // Counted on the origin node
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -25,17 +82,101 @@ pub struct Room {
     pub name: String,
     pub host_id: String,
     pub media_id: Option<String>,
+    pub queue: Vec<String>, // Upcoming media IDs, played in order after `media_id`
     pub clients: Vec<String>,
     pub ready_clients: HashSet<String>,
     pub pending_play: Option<PendingPlay>,
     pub state: PlaybackState,
+    pub host_policy: HostTransferPolicy,
+    pub successor_id: Option<String>,
+    pub control_mode: ControlMode,
+    pub controllers: HashSet<String>, // Co-controllers for ControlMode::AllowList
+    pub visibility: RoomVisibility,
+    #[serde(skip)]
+    pub password_hash: Option<String>, // Argon2 PHC string, never the plaintext
+    #[serde(skip)]
+    pub invite_codes: HashSet<String>, // Single-use codes for RoomVisibility::InviteOnly
+    #[serde(skip)]
+    pub banned_users: HashSet<String>, // Jellyfin user IDs (JWT `sub`) barred for the room's lifetime
+    #[serde(skip)]
+    pub muted: HashSet<String>, // Users (see `room::mute_key`) that may not chat, kept across rejoins
+    #[serde(skip)]
+    pub chat_history: ChatHistory, // Recent chat replayed to late joiners
+    #[serde(skip)]
+    pub position_ts: u64, // Server time (ms) at which `state.position` was accurate
     #[serde(skip)]
     pub last_state_ts: u64,
     #[serde(skip)]
     pub last_command_ts: u64,
+    #[serde(skip)]
+    pub last_command_by: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Host edit to a room's watch queue
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueueEdit {
+    /// Insert at `index` (appends when None or past the end)
+    Add {
+        media_id: String,
+        index: Option<usize>,
+    },
+    Remove(usize),
+    Move {
+        from: usize,
+        to: usize,
+    },
+}
+
+/// How a new host is picked when the current host leaves the room
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum HostTransferPolicy {
+    /// Participant who joined the room earliest
+    #[default]
+    OldestMember,
+    /// Earliest-joined participant that has reported ready
+    FirstReady,
+    /// Successor chosen by the host via `set_successor` (falls back to oldest member)
+    Designated,
+}
+
+/// Who may send playback commands (`player_event`) in a room
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ControlMode {
+    /// Only the host controls playback
+    #[default]
+    HostOnly,
+    /// Every participant controls playback (democratic mode)
+    Everyone,
+    /// Host plus the clients listed in `Room.controllers`
+    AllowList,
+}
+
+/// Whether a room is advertised in `room_list` and how it can be joined
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomVisibility {
+    /// Listed in `room_list`, joinable by anyone
+    #[default]
+    Public,
+    /// Hidden from `room_list`, joinable by room ID
+    Unlisted,
+    /// Hidden from `room_list`, joinable only with a host-issued invite code
+    InviteOnly,
+}
+
+/// Host moderation action applied to a participant
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ModerationAction {
+    Kick,
+    Ban,
+    Mute,
+    Unmute,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct PlaybackState {
     pub position: f64,
     pub play_state: String,
@@ -48,10 +189,11 @@ pub struct PendingPlay {
 }
 
 /// Incoming message types from clients (type-safe enum for dispatch)
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum ClientMessageType {
     Auth,
+    Reauth,
     ListRooms,
     CreateRoom,
     JoinRoom,
@@ -62,34 +204,213 @@ pub enum ClientMessageType {
     Ping,
     ClientLog,
     ChatMessage,
+    SetSuccessor,
+    Resume,
+    SetControlMode,
+    CreateInvite,
+    KickParticipant,
+    BanParticipant,
+    MuteParticipant,
+    TimeSync,
+    GetSyncQuality,
+    QueueAdd,
+    QueueRemove,
+    QueueMove,
+    QueueNext,
     #[serde(other)]
+    #[schemars(skip)]
     Unknown,
 }
 
-/// Outgoing message types from server (reserved for future use)
-#[allow(dead_code)]
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
-#[serde(rename_all = "snake_case")]
-pub enum ServerMessageType {
-    ClientHello,
-    AuthSuccess,
-    Error,
-    RoomList,
-    RoomState,
-    ParticipantsUpdate,
-    PlayerEvent,
-    StateUpdate,
-    Pong,
-    ClientLeft,
-    RoomClosed,
-    ChatMessage,
+/// Outgoing message: `type` plus the payload shape that goes with it.
+/// Serialized as the `type` and `payload` fields of `WsMessage`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", content = "payload", rename_all = "snake_case")]
+pub enum ServerMessage {
+    ClientHello(ClientHelloPayload),
+    SessionResumed {
+        client_id: String,
+        resume_token: String,
+    },
+    AuthSuccess {
+        user_name: String,
+        protocol_version: u32,
+        /// When the token expires (ms); a fresh one must be sent with `reauth`
+        #[serde(skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+        permissions: Permissions,
+    },
+    TokenExpiring {
+        expires_at: u64,
+        grace_ms: u64,
+    },
+    Ack(AckPayload),
+    Error(ErrorPayload),
+    RoomList(Vec<RoomListItem>),
+    RoomState(RoomStatePayload),
+    ParticipantsUpdate {
+        participant_count: usize,
+        state: PlaybackState,
+    },
+    PlayerEvent(PlayerEventPayload),
+    StateUpdate(StateUpdatePayload),
+    /// Echoes the `ping` payload
+    Pong(Option<serde_json::Value>),
+    ClientLeft {
+        participant_count: usize,
+    },
+    RoomClosed {
+        reason: String,
+    },
+    HostChanged {
+        host_id: String,
+        previous_host_id: String,
+        policy: HostTransferPolicy,
+    },
+    ControlModeChanged {
+        control_mode: ControlMode,
+        controllers: HashSet<String>,
+    },
+    InviteCreated {
+        invite_code: String,
+    },
+    Kicked(ModerationNotice),
+    Banned(ModerationNotice),
+    MuteStatus(ModerationNotice),
+    ParticipantModerated {
+        action: ModerationAction,
+        client_id: String,
+        user_name: String,
+    },
+    TimeSync(TimeSyncPayload),
+    SyncQuality {
+        participants: Vec<ParticipantSyncQuality>,
+    },
+    QueueUpdated {
+        queue: Vec<String>,
+    },
+    MediaChanged {
+        media_id: Option<String>,
+        queue: Vec<String>,
+        state: PlaybackState,
+    },
+    ChatMessage {
+        id: u64,
+        username: String,
+        text: String,
+    },
+    ChatHistory {
+        messages: Vec<ChatEntry>,
+    },
+    Announcement {
+        text: String,
+    },
+    Disconnected {
+        reason: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ClientHelloPayload {
+    pub client_id: String,
+    pub resume_token: String,
+    pub resume_grace_ms: u64,
+    pub protocol: ProtocolInfo,
+}
+
+/// Confirms a command that has no reply of its own.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct AckPayload {
+    pub request: ClientMessageType,
+    /// `player_event` only: false if the room dropped it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub applied: Option<bool>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ErrorPayload {
+    pub code: ErrorCode,
+    pub message: Cow<'static, str>,
+    /// Type of the failed message, when it could be parsed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request: Option<ClientMessageType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after_ms: Option<u64>,
+}
+
+/// Full room snapshot for a client entering a room.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct RoomStatePayload {
+    pub name: String,
+    pub host_id: String,
+    pub state: PlaybackState,
+    pub participant_count: usize,
+    pub media_id: Option<String>,
+    pub queue: Vec<String>,
+    pub control_mode: ControlMode,
+    pub controllers: HashSet<String>,
+    pub visibility: RoomVisibility,
+    pub has_password: bool,
+}
+
+/// Playback command, relayed from a controller or issued by the server
+/// (a pending play starting).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct PlayerEventPayload {
+    pub action: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub play_state: Option<String>,
+    /// Server time at which every client should apply the command
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_server_ts: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schedule_delay_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct StateUpdatePayload {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub play_state: Option<String>,
+}
+
+/// Sent to the moderated participant (`kicked`, `banned` or `mute_status`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ModerationNotice {
+    pub action: ModerationAction,
+    pub muted: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct TimeSyncPayload {
+    pub client_send_ts: u64,
+    pub server_recv_ts: u64,
+    pub server_send_ts: u64,
+    pub rtt_ms: Option<u64>,
+    pub offset_ms: Option<i64>,
+}
+
+/// One `sync_quality` entry.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ParticipantSyncQuality {
+    pub client_id: String,
+    pub user_name: String,
+    #[serde(flatten)]
+    pub quality: ClockQuality,
 }
 
 /// Incoming WebSocket message from client
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[schemars(rename = "ClientMessage")]
 pub struct IncomingMessage {
     #[serde(rename = "type")]
     pub msg_type: ClientMessageType,
+    /// Optional client-chosen request ID, echoed on the direct reply
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub room: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -101,16 +422,35 @@ pub struct IncomingMessage {
     pub server_ts: Option<u64>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// The request a reply answers: its type and the client's `id`, if any.
+#[derive(Debug, Clone)]
+pub struct ReplyTo {
+    pub msg_type: ClientMessageType,
+    pub id: Option<String>,
+}
+
+impl IncomingMessage {
+    pub fn reply_to(&self) -> ReplyTo {
+        ReplyTo {
+            msg_type: self.msg_type.clone(),
+            id: self.id.clone(),
+        }
+    }
+}
+
+/// Outgoing WebSocket message to clients
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, JsonSchema)]
+#[schemars(rename = "ServerMessage")]
 pub struct WsMessage {
-    #[serde(rename = "type")]
-    pub msg_type: String,
+    #[serde(flatten)]
+    pub body: ServerMessage,
+    /// `id` of the request this message directly answers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub room: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub client: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub payload: Option<serde_json::Value>,
     pub ts: u64,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub server_ts: Option<u64>,
@@ -178,6 +518,53 @@ mod tests {
         assert!(msg.payload.is_some());
     }
 
+    #[test]
+    fn test_host_transfer_policy_deserialize() {
+        let policy: HostTransferPolicy = serde_json::from_str(r#""first_ready""#).unwrap();
+        assert_eq!(policy, HostTransferPolicy::FirstReady);
+        let policy: HostTransferPolicy = serde_json::from_str(r#""designated""#).unwrap();
+        assert_eq!(policy, HostTransferPolicy::Designated);
+        assert_eq!(
+            HostTransferPolicy::default(),
+            HostTransferPolicy::OldestMember
+        );
+    }
+
+    #[test]
+    fn test_control_mode_serde() {
+        let mode: ControlMode = serde_json::from_str(r#""allow_list""#).unwrap();
+        assert_eq!(mode, ControlMode::AllowList);
+        let json = serde_json::to_string(&ControlMode::HostOnly).unwrap();
+        assert_eq!(json, r#""host_only""#);
+    }
+
+    #[test]
+    fn test_server_message_wire_format() {
+        let msg = WsMessage {
+            body: ServerMessage::ClientLeft {
+                participant_count: 2,
+            },
+            id: None,
+            room: Some("room-1".to_string()),
+            client: Some("c1".to_string()),
+            ts: 1,
+            server_ts: Some(2),
+        };
+        let json = serde_json::to_value(&msg).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "type": "client_left",
+                "room": "room-1",
+                "client": "c1",
+                "payload": { "participant_count": 2 },
+                "ts": 1,
+                "server_ts": 2
+            })
+        );
+        assert_eq!(serde_json::from_value::<WsMessage>(json).unwrap(), msg);
+    }
+
     #[test]
     fn test_playback_state() {
         let state = PlaybackState {
@@ -1,7 +1,22 @@
-use crate::auth::JwtConfig;
+use crate::auth::{hash_password, verify_password, JwtConfig, Permissions};
+use crate::cluster::Cluster;
+use crate::config::{Config, SessionConfig, SharedConfig};
+use crate::connections::{ConnectionLimiter, ConnectionSlot};
+use crate::error::{ErrorCode, ServerError};
 use crate::messaging::{broadcast_room_list, broadcast_to_room, send_room_list, send_to_client};
-use crate::room::{close_room, handle_leave};
-use crate::types::{ClientMessageType, Clients, IncomingMessage, PlaybackState, Room, WsMessage};
+use crate::metrics::METRICS;
+use crate::protocol::{self, ACK_PROTOCOL_VERSION, LEGACY_PROTOCOL_VERSION, MIN_PROTOCOL_VERSION};
+use crate::room::{
+    advance_queue, chat_history_message, close_room, edit_queue, expected_state, is_muted,
+    leave_room, may_control, moderate, room_state_payload,
+};
+use crate::timesync::ScheduleConfig;
+use crate::types::{
+    AckPayload, ClientHelloPayload, ClientMap, ClientMessageType, Clients, ControlMode,
+    HostTransferPolicy, IncomingMessage, ModerationAction, ParticipantSyncQuality, PlaybackState,
+    PlayerEventPayload, QueueEdit, ReplyTo, Room, RoomVisibility, Rooms, ServerMessage,
+    StateUpdatePayload, TimeSyncPayload, WsMessage,
+};
 use crate::utils::now_ms;
 use futures::StreamExt;
 use log::{debug, info, warn};
@@ -11,26 +26,14 @@ use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
 
 // Channel buffer size for client message queues (prevents OOM from slow clients)
-const CLIENT_CHANNEL_BUFFER: usize = 100;
+pub const CLIENT_CHANNEL_BUFFER: usize = 100;
 
-const PLAY_SCHEDULE_MS: u64 = 1000; // Reduced from 1500ms for better UX (UX-P1)
-const CONTROL_SCHEDULE_MS: u64 = 300;
-const MIN_STATE_UPDATE_INTERVAL_MS: u64 = 500;
-const POSITION_JITTER_THRESHOLD: f64 = 0.5;
-const COMMAND_COOLDOWN_MS: u64 = 2000;
-
-// Rate limiting constants
-const RATE_LIMIT_MESSAGES: u32 = 30; // Max messages per window
-const RATE_LIMIT_WINDOW_MS: u64 = 1000; // Window size in ms
-
-// Resource limits
-const MAX_CLIENTS_PER_ROOM: usize = 20; // Max clients in a room
+// Tunable limits and sync timings live in `Config` (config.rs)
 
 // Payload validation
 const MAX_POSITION_SECONDS: f64 = 86400.0; // 24 hours max
-const MAX_MESSAGE_SIZE: usize = 64 * 1024; // 64 KB max message size
 const MAX_NAME_LENGTH: usize = 100; // Max length for user/room names
-const MAX_CHAT_MESSAGE_LENGTH: usize = 500; // Max chat message length
+const MAX_PASSWORD_LENGTH: usize = 128; // Max room password length (bounds hashing cost)
 
 /// Validates a playback position value.
 /// Returns false for NaN, Infinity, negative values, or values exceeding 24 hours (fixes L12).
@@ -75,8 +78,11 @@ fn sanitize_name(name: &str) -> Option<String> {
 pub async fn client_connection(
     ws: warp::ws::WebSocket,
     clients: Clients,
-    rooms: crate::types::Rooms,
+    rooms: Rooms,
     jwt_config: Arc<JwtConfig>,
+    config: SharedConfig,
+    cluster: Arc<Cluster>,
+    limiter: Arc<ConnectionLimiter>,
 ) {
     let (client_ws_sender, mut client_ws_rcv) = ws.split();
     // Use bounded channel to prevent OOM from slow/malicious clients (P-RS03 fix)
@@ -88,6 +94,7 @@ pub async fn client_connection(
     });
 
     let temp_id = uuid::Uuid::new_v4().to_string();
+    let resume_token = uuid::Uuid::new_v4().to_string();
     let now = now_ms();
 
     // Start unauthenticated (or authenticated if auth is disabled)
@@ -102,10 +109,10 @@ pub async fn client_connection(
         "Client connected: {} (auth_required: {})",
         temp_id, jwt_config.enabled
     );
-    clients.write().await.insert(
+    clients.insert(
         temp_id.clone(),
         crate::types::Client {
-            sender: client_sender,
+            sender: client_sender.clone(),
             room_id: None,
             user_id,
             user_name,
@@ -113,183 +120,617 @@ pub async fn client_connection(
             message_count: 0,
             last_reset: now,
             last_seen: now,
+            resume_token: resume_token.clone(),
+            disconnected_at: None,
+            clock: Default::default(),
+            owner_node: None,
+            origin_node: None,
+            protocol_version: LEGACY_PROTOCOL_VERSION,
+            token_exp: None,
+            token_warned: false,
+            token_jti: None,
+            permissions: Default::default(),
+            user_slot: None,
         },
     );
 
-    {
-        let locked_clients = clients.read().await;
-        send_to_client(
-            &temp_id,
-            &locked_clients,
-            &WsMessage {
-                msg_type: "client_hello".to_string(),
-                room: None,
-                client: Some(temp_id.clone()),
-                payload: Some(serde_json::json!({ "client_id": temp_id.clone() })),
-                ts: now_ms(),
-                server_ts: Some(now_ms()),
-            },
-        );
-    }
+    send_to_client(
+        &temp_id,
+        &clients,
+        &WsMessage {
+            body: ServerMessage::ClientHello(ClientHelloPayload {
+                client_id: temp_id.clone(),
+                resume_token,
+                resume_grace_ms: config.get().session.resume_grace_ms,
+                protocol: protocol::hello_payload(&config.get()),
+            }),
+            id: None,
+            room: None,
+            client: Some(temp_id.clone()),
+            ts: now_ms(),
+            server_ts: Some(now_ms()),
+        },
+    );
 
-    send_room_list(&temp_id, &clients, &rooms).await;
+    send_room_list(&temp_id, &clients, &rooms, cluster.remote_rooms(), None);
 
+    // A successful `resume` switches this socket over to the reclaimed session ID
+    let mut client_id = temp_id;
     while let Some(result) = client_ws_rcv.next().await {
+        // A `resume` on another socket took this session over
+        let current = clients
+            .get(&client_id)
+            .is_some_and(|c| c.sender.same_channel(&client_sender));
+        if !current {
+            break;
+        }
         if let Ok(msg) = result {
-            client_msg(&temp_id, msg, &clients, &rooms, &jwt_config).await;
+            if let Some(resumed_id) = client_msg(
+                &client_id,
+                msg,
+                &clients,
+                &rooms,
+                &jwt_config,
+                &config.get(),
+                &cluster,
+                &limiter,
+            )
+            .await
+            {
+                client_id = resumed_id;
+                cluster.session_resumed(&client_id, &clients).await;
+            }
         }
     }
 
-    crate::room::handle_disconnect(&temp_id, &clients, &rooms).await;
+    cluster
+        .connection_lost(&client_id, &client_sender, &clients)
+        .await;
+    crate::room::handle_connection_lost(
+        &client_id,
+        &client_sender,
+        &clients,
+        &rooms,
+        cluster.remote_rooms(),
+        config.get().session.resume_grace_ms,
+    )
+    .await;
 }
 
-fn all_ready(room: &Room) -> bool {
-    room.ready_clients.len() >= room.clients.len()
+/// Extracts a bounded set of client IDs from a `controllers` payload array.
+fn parse_controllers(value: &serde_json::Value, max: usize) -> HashSet<String> {
+    value
+        .as_array()
+        .map(|ids| {
+            ids.iter()
+                .filter_map(|v| v.as_str())
+                .take(max)
+                .map(|id| id.to_string())
+                .collect()
+        })
+        .unwrap_or_default()
 }
 
-async fn broadcast_scheduled_play(
-    room: &mut Room,
-    clients: &Clients,
-    position: f64,
+/// Builds a `join_room` rejection for `room_id`.
+fn join_error(
+    client_id: &str,
+    room_id: &str,
+    request: &ReplyTo,
+    code: ErrorCode,
+    message: &'static str,
+) -> WsMessage {
+    ServerError::new(code, message).to_message(client_id, Some(room_id), Some(request))
+}
+
+/// Highest measured RTT among connected room participants, as counted by
+/// `ScheduleConfig::worst_rtt_ms`.
+fn worst_rtt_ms(
+    room: &Room,
+    clients: &ClientMap,
+    exclude: Option<&str>,
+    schedule: &ScheduleConfig,
+) -> Option<u64> {
+    schedule.worst_rtt_ms(
+        room.clients
+            .iter()
+            .filter(|id| Some(id.as_str()) != exclude)
+            .filter_map(|id| {
+                let client = clients.get(id)?;
+                client
+                    .disconnected_at
+                    .is_none()
+                    .then(|| client.clock.rtt_ms())?
+            }),
+    )
+}
+
+/// Warns about participants whose measured one-way latency means a command
+/// scheduled for `target_server_ts` will reach them after it should have run.
+fn warn_late_participants(
+    room: &Room,
+    clients: &ClientMap,
     target_server_ts: u64,
+    exclude: Option<&str>,
 ) {
+    let lead_ms = target_server_ts.saturating_sub(now_ms());
+    for id in room
+        .clients
+        .iter()
+        .filter(|id| Some(id.as_str()) != exclude)
+    {
+        if let Some(rtt) = clients.get(id).and_then(|c| c.clock.rtt_ms()) {
+            if rtt / 2 >= lead_ms {
+                warn!(
+                    "Client {} in room {} will miss scheduled command (one-way ~{} ms, lead {} ms)",
+                    id,
+                    room.room_id,
+                    rtt / 2,
+                    lead_ms
+                );
+            }
+        }
+    }
+}
+
+fn all_ready(room: &Room) -> bool {
+    room.ready_clients.len() >= room.clients.len()
+}
+
+fn broadcast_scheduled_play(room: &mut Room, clients: &ClientMap, position: f64, config: &Config) {
+    let schedule = config.sync.schedule();
+    let delay_ms = schedule.delay_ms(
+        worst_rtt_ms(room, clients, None, &schedule),
+        config.sync.play_schedule_ms,
+    );
+    let target_server_ts = now_ms() + delay_ms;
     room.state.position = position;
     room.state.play_state = "playing".to_string();
+    // Everyone starts at the target time, so that is when `position` holds
+    room.position_ts = target_server_ts;
     let msg = WsMessage {
-        msg_type: "player_event".to_string(),
+        body: ServerMessage::PlayerEvent(PlayerEventPayload {
+            action: "play".to_string(),
+            position: Some(position),
+            play_state: None,
+            target_server_ts: Some(target_server_ts),
+            schedule_delay_ms: Some(delay_ms),
+        }),
+        id: None,
         room: Some(room.room_id.clone()),
         client: None,
-        payload: Some(serde_json::json!({
-            "action": "play",
-            "position": position,
-            "target_server_ts": target_server_ts
-        })),
         ts: now_ms(),
         server_ts: Some(target_server_ts),
     };
-    let locked_clients = clients.read().await;
-    broadcast_to_room(room, &locked_clients, &msg, None);
+    warn_late_participants(room, clients, target_server_ts, None);
+    broadcast_to_room(room, clients, &msg, None);
 }
 
-/// Returns true if the client is rate limited (should drop the message)
-async fn check_rate_limit(client_id: &str, clients: &Clients) -> bool {
-    let mut locked_clients = clients.write().await;
-    if let Some(client) = locked_clients.get_mut(client_id) {
+/// Returns the time until the window resets if the client is rate limited
+/// (should drop the message)
+fn check_rate_limit(client_id: &str, clients: &ClientMap, config: &Config) -> Option<u64> {
+    if let Some(mut client) = clients.get_mut(client_id) {
         let now = now_ms();
         // Update last_seen for zombie detection
         client.last_seen = now;
         // Reset counter if window has passed
-        if now - client.last_reset > RATE_LIMIT_WINDOW_MS {
+        if now - client.last_reset > config.limits.rate_limit_window_ms {
             client.message_count = 0;
             client.last_reset = now;
         }
         client.message_count += 1;
-        if client.message_count > RATE_LIMIT_MESSAGES {
-            return true; // Rate limited
+        if client.message_count > config.limits.rate_limit_messages {
+            let window_end = client.last_reset + config.limits.rate_limit_window_ms;
+            return Some(window_end.saturating_sub(now)); // Rate limited
         }
     }
-    false
+    None
 }
 
-async fn send_error(client_id: &str, clients: &Clients, message: &str) {
-    let locked_clients = clients.read().await;
+/// Sends `error` to the client. `request` is the failed message.
+fn send_error(client_id: &str, clients: &ClientMap, request: Option<&ReplyTo>, error: ServerError) {
     send_to_client(
         client_id,
-        &locked_clients,
-        &WsMessage {
-            msg_type: "error".to_string(),
-            room: None,
-            client: Some(client_id.to_string()),
-            payload: Some(serde_json::json!({ "message": message })),
-            ts: now_ms(),
-            server_ts: Some(now_ms()),
-        },
+        clients,
+        &error.to_message(client_id, None, request),
     );
 }
 
+fn room_not_found() -> ServerError {
+    ServerError::new(ErrorCode::RoomNotFound, "Room not found")
+}
+
+/// What became of a `chat_message`, decided inside the room.
+enum ChatOutcome {
+    Sent,
+    Muted,
+    NotMember,
+}
+
+/// `ack` for a command that has no reply of its own. `applied` says whether a
+/// `player_event` took effect (the room drops contested or unauthorized ones).
+fn ack_message(client_id: &str, request: &ReplyTo, applied: Option<bool>) -> WsMessage {
+    WsMessage {
+        body: ServerMessage::Ack(AckPayload {
+            request: request.msg_type.clone(),
+            applied,
+        }),
+        id: request.id.clone(),
+        room: None,
+        client: Some(client_id.to_string()),
+        ts: now_ms(),
+        server_ts: Some(now_ms()),
+    }
+}
+
+fn send_ack(client_id: &str, clients: &ClientMap, request: &ReplyTo) {
+    if speaks_acks(client_id, clients) {
+        send_to_client(client_id, clients, &ack_message(client_id, request, None));
+    }
+}
+
+/// Clients on protocol versions before `ack` existed would choke on them.
+fn speaks_acks(client_id: &str, clients: &ClientMap) -> bool {
+    clients
+        .get(client_id)
+        .is_some_and(|c| c.protocol_version >= ACK_PROTOCOL_VERSION)
+}
+
+/// Acknowledges a command or reports why it failed.
+fn reply_to_command(
+    client_id: &str,
+    clients: &ClientMap,
+    request: &ReplyTo,
+    result: Result<(), ServerError>,
+) {
+    match result {
+        Ok(()) => send_ack(client_id, clients, request),
+        Err(e) => send_error(client_id, clients, Some(request), e),
+    }
+}
+
 /// Check if client is authenticated
-async fn is_authenticated(client_id: &str, clients: &Clients) -> bool {
-    let locked = clients.read().await;
-    locked
+fn is_authenticated(client_id: &str, clients: &ClientMap) -> bool {
+    clients
         .get(client_id)
         .map(|c| c.authenticated)
         .unwrap_or(false)
 }
 
-async fn client_msg(
+/// Whether the client's token grants `permission` (see `Permissions`).
+fn permitted(client_id: &str, clients: &ClientMap, permission: fn(&Permissions) -> bool) -> bool {
+    clients
+        .get(client_id)
+        .is_some_and(|c| permission(&c.permissions))
+}
+
+/// The slot counting `client_id` against `sub`'s connection cap: the one it
+/// already holds when re-authenticating as the same user, otherwise a new one.
+/// None when the user is at the cap.
+fn user_slot(
+    client_id: &str,
+    sub: &str,
+    clients: &ClientMap,
+    limiter: &Arc<ConnectionLimiter>,
+    max: usize,
+) -> Option<Arc<ConnectionSlot>> {
+    let held = clients
+        .get(client_id)
+        .and_then(|c| c.user_slot.clone())
+        .filter(|slot| slot.is_user(sub));
+    held.or_else(|| limiter.acquire_user(sub, max).map(Arc::new))
+}
+
+/// Sends a pre-serialized message to connected room members other than `exclude`.
+fn send_to_members(room: &Room, clients: &ClientMap, json: String, exclude: Option<&str>) {
+    let warp_msg = warp::ws::Message::text(json);
+    for id in room
+        .clients
+        .iter()
+        .filter(|id| Some(id.as_str()) != exclude)
+    {
+        let Some(client) = clients.get(id) else {
+            continue;
+        };
+        if client.disconnected_at.is_some() {
+            continue;
+        }
+        if let Err(e) = client.sender.try_send(Ok(warp_msg.clone())) {
+            METRICS.broadcast_failed();
+            log::warn!(
+                "Failed to send to client {} (buffer full or closed): {}",
+                id,
+                e
+            );
+        }
+    }
+}
+
+/// Warns clients whose JWT is about to expire and demotes those still on an
+/// expired one after the grace period: they leave their room and must
+/// `reauth` (or `auth`) before joining again. Only sockets on this node are
+/// checked; identity belongs to the node holding the socket.
+pub async fn expire_tokens(
+    clients: &Clients,
+    rooms: &Rooms,
+    cluster: &Cluster,
+    session: &SessionConfig,
+    now: u64,
+) {
+    let mut expiring = Vec::new();
+    let mut expired = Vec::new();
+    for mut client in clients.iter_mut() {
+        // Held sessions are checked again once they resume
+        if client.origin_node.is_some() || client.disconnected_at.is_some() {
+            continue;
+        }
+        let Some(exp) = client.token_exp else {
+            continue;
+        };
+        if now >= exp.saturating_add(session.token_grace_ms) {
+            client.authenticated = false;
+            client.token_exp = None;
+            expired.push(client.key().clone());
+        } else if !client.token_warned && now >= exp.saturating_sub(session.token_warning_ms) {
+            client.token_warned = true;
+            expiring.push((client.key().clone(), exp));
+        }
+    }
+
+    for (client_id, expires_at) in expiring {
+        send_to_client(
+            &client_id,
+            clients,
+            &WsMessage {
+                body: ServerMessage::TokenExpiring {
+                    expires_at,
+                    grace_ms: session.token_grace_ms,
+                },
+                id: None,
+                room: None,
+                client: Some(client_id.clone()),
+                ts: now,
+                server_ts: Some(now),
+            },
+        );
+    }
+
+    for client_id in expired {
+        info!("Token of client {} expired, leaving its room", client_id);
+        let (remote, in_room) = match clients.get(&client_id) {
+            Some(c) => (c.owner_node.is_some(), c.room_id.is_some()),
+            None => continue,
+        };
+        if remote {
+            cluster.leave_remote_room(&client_id, clients, rooms).await;
+        } else if in_room {
+            leave_room(&client_id, clients, rooms).await;
+            broadcast_room_list(clients, rooms, cluster.remote_rooms());
+        }
+        send_error(
+            &client_id,
+            clients,
+            None,
+            ServerError::new(
+                ErrorCode::TokenExpired,
+                "Token expired; authenticate again to rejoin",
+            ),
+        );
+    }
+}
+
+/// Handles one incoming message. Returns the reclaimed client ID when the
+/// message resumed a previous session, so the caller can adopt it. Messages
+/// for a room on another node are forwarded there through `cluster`.
+///
+/// Room state is only touched on the room's own task (`Rooms::call`), so
+/// traffic in one room never waits on another.
+#[allow(clippy::too_many_arguments)]
+pub async fn client_msg(
     client_id: &str,
     msg: warp::ws::Message,
     clients: &Clients,
-    rooms: &crate::types::Rooms,
+    rooms: &Rooms,
     jwt_config: &Arc<JwtConfig>,
-) {
+    config: &Arc<Config>,
+    cluster: &Cluster,
+    limiter: &Arc<ConnectionLimiter>,
+) -> Option<String> {
+    // Server receive timestamp for time_sync, taken before any other work
+    let received_at = now_ms();
+
     // Rate limiting check
-    if check_rate_limit(client_id, clients).await {
+    if let Some(retry_after_ms) = check_rate_limit(client_id, clients, config) {
+        METRICS.rate_limited();
         warn!("Rate limited client: {}", client_id);
-        send_error(client_id, clients, "Rate limit exceeded").await;
-        return;
+        send_error(
+            client_id,
+            clients,
+            None,
+            ServerError::new(ErrorCode::RateLimited, "Rate limit exceeded")
+                .retry_after(retry_after_ms),
+        );
+        return None;
     }
 
     // Message size limit check (prevent OOM attacks)
-    if msg.as_bytes().len() > MAX_MESSAGE_SIZE {
+    if msg.as_bytes().len() > config.limits.max_message_size {
         warn!(
             "Message too large from client {}: {} bytes",
             client_id,
             msg.as_bytes().len()
         );
-        send_error(client_id, clients, "Message too large").await;
-        return;
+        send_error(
+            client_id,
+            clients,
+            None,
+            ServerError::new(ErrorCode::MessageTooLarge, "Message too large"),
+        );
+        return None;
     }
 
-    let msg_str = if let Ok(s) = msg.to_str() { s } else { return };
+    let msg_str = if let Ok(s) = msg.to_str() {
+        s
+    } else {
+        return None;
+    };
 
-    let mut parsed: IncomingMessage = match serde_json::from_str(msg_str) {
+    let parsed: IncomingMessage = match serde_json::from_str(msg_str) {
         Ok(v) => v,
         Err(e) => {
             warn!("JSON parse error from {}: {}", client_id, e);
-            send_error(client_id, clients, "Invalid message format").await;
-            return;
+            send_error(
+                client_id,
+                clients,
+                None,
+                ServerError::new(ErrorCode::InvalidMessage, "Invalid message format"),
+            );
+            return None;
         }
     };
 
+    if cluster
+        .route(client_id, &parsed, msg_str, clients, rooms)
+        .await
+    {
+        return None;
+    }
+
     // Log message type only (not full payload for privacy)
     debug!("Message from {}: {:?}", client_id, parsed.msg_type);
+    METRICS.message_received(&parsed.msg_type);
+    let request = parsed.reply_to();
 
     match parsed.msg_type {
         ClientMessageType::Auth => {
             // Handle authentication via message (security: token not in URL)
             if let Some(payload) = &parsed.payload {
+                // Clients that don't declare a version speak the original protocol
+                let declared = |key: &str, default: u32| {
+                    payload
+                        .get(key)
+                        .and_then(|v| v.as_u64())
+                        .map_or(default, |v| v.min(u32::MAX as u64) as u32)
+                };
+                let client_max = declared("protocol_version", LEGACY_PROTOCOL_VERSION);
+                let client_min = declared("min_protocol_version", client_max);
+                let Some(version) = protocol::negotiate(client_max, client_min) else {
+                    warn!(
+                        "Rejecting {}: protocol {}..={} unsupported",
+                        client_id, client_min, client_max
+                    );
+                    send_error(
+                        client_id,
+                        clients,
+                        Some(&request),
+                        ServerError::new(
+                            ErrorCode::UnsupportedProtocol,
+                            format!(
+                                "Protocol version {} is not supported; this server speaks {} to {}",
+                                client_max,
+                                MIN_PROTOCOL_VERSION,
+                                protocol::PROTOCOL_VERSION
+                            ),
+                        ),
+                    );
+                    if let Some(client) = clients.get(client_id) {
+                        let _ = client.sender.try_send(Ok(warp::ws::Message::close()));
+                    }
+                    return None;
+                };
+                if let Some(mut client) = clients.get_mut(client_id) {
+                    client.protocol_version = version;
+                }
+
                 // Try JWT token first
                 if let Some(token) = payload.get("token").and_then(|v| v.as_str()) {
-                    match jwt_config.validate_token(token) {
+                    // Take the user's connection slot before the token is
+                    // admitted, so a refused connection doesn't use up its jti
+                    let claims = jwt_config.validate_token(token);
+                    let slot = match &claims {
+                        Ok(claims) => {
+                            let max = config.limits.max_connections_per_user;
+                            let Some(slot) =
+                                user_slot(client_id, &claims.sub, clients, limiter, max)
+                            else {
+                                warn!(
+                                    "Rejecting {}: {} already has {} connections",
+                                    client_id, claims.sub, max
+                                );
+                                METRICS.connection_rejected();
+                                send_error(
+                                    client_id,
+                                    clients,
+                                    Some(&request),
+                                    ServerError::new(
+                                        ErrorCode::TooManyConnections,
+                                        format!(
+                                            "Too many open connections for this user (limit {})",
+                                            max
+                                        ),
+                                    ),
+                                );
+                                if let Some(client) = clients.get(client_id) {
+                                    let _ = client.sender.try_send(Ok(warp::ws::Message::close()));
+                                }
+                                return None;
+                            };
+                            Some(slot)
+                        }
+                        Err(_) => None,
+                    };
+                    let admitted = claims.and_then(|claims| {
+                        jwt_config.admit(&claims, client_id)?;
+                        Ok(claims)
+                    });
+                    match admitted {
                         Ok(claims) => {
-                            let mut locked = clients.write().await;
-                            if let Some(client) = locked.get_mut(client_id) {
+                            let expires_at = jwt_config.expires_at(&claims);
+                            let used_token = jwt_config.used_token(&claims, client_id);
+                            let permissions = Permissions::from_claims(&claims);
+                            if let Some(mut client) = clients.get_mut(client_id) {
                                 client.authenticated = true;
                                 client.user_id = claims.sub;
                                 client.user_name = claims.name.clone();
+                                client.token_exp = expires_at;
+                                client.token_warned = false;
+                                client.token_jti = claims.jti;
+                                client.permissions = permissions;
+                                client.user_slot = slot;
                                 info!("Client {} authenticated as {}", client_id, claims.name);
                             }
-                            drop(locked);
-                            let locked_clients = clients.read().await;
                             send_to_client(
                                 client_id,
-                                &locked_clients,
+                                clients,
                                 &WsMessage {
-                                    msg_type: "auth_success".to_string(),
+                                    body: ServerMessage::AuthSuccess {
+                                        user_name: claims.name,
+                                        protocol_version: version,
+                                        expires_at,
+                                        permissions,
+                                    },
+                                    id: request.id.clone(),
                                     room: None,
                                     client: Some(client_id.to_string()),
-                                    payload: Some(serde_json::json!({ "user_name": claims.name })),
                                     ts: now_ms(),
                                     server_ts: Some(now_ms()),
                                 },
                             );
-                            return;
+                            cluster.identity_changed(client_id, clients).await;
+                            if let Some(token) = used_token {
+                                cluster.token_used(token).await;
+                            }
+                            return None;
                         }
                         Err(e) => {
+                            METRICS.auth_failed();
                             warn!("Auth failed for {}: {}", client_id, e);
-                            send_error(client_id, clients, "Authentication failed").await;
-                            return;
+                            send_error(
+                                client_id,
+                                clients,
+                                Some(&request),
+                                ServerError::new(ErrorCode::AuthFailed, "Authentication failed"),
+                            );
+                            return None;
                         }
                     }
                 }
@@ -302,42 +743,268 @@ async fn client_msg(
                         .and_then(sanitize_name);
                     let user_id = payload.get("user_id").and_then(|v| v.as_str());
                     if let Some(name) = user_name {
-                        let mut locked = clients.write().await;
-                        if let Some(client) = locked.get_mut(client_id) {
+                        if let Some(mut client) = clients.get_mut(client_id) {
                             client.user_name = name.clone();
                             if let Some(uid) = user_id {
                                 client.user_id = uid.to_string();
                             }
                             info!("Client {} identified as {}", client_id, name);
                         }
+                        cluster.identity_changed(client_id, clients).await;
+                    }
+                    send_ack(client_id, clients, &request);
+                } else {
+                    send_error(
+                        client_id,
+                        clients,
+                        Some(&request),
+                        ServerError::new(ErrorCode::AuthFailed, "Token required"),
+                    );
+                }
+            }
+        }
+        ClientMessageType::Reauth => {
+            // Swaps in a fresh token for the same user; room membership is kept
+            if !jwt_config.enabled {
+                send_ack(client_id, clients, &request);
+                return None;
+            }
+            let token = parsed
+                .payload
+                .as_ref()
+                .and_then(|p| p.get("token"))
+                .and_then(|v| v.as_str())
+                .unwrap_or("");
+            let (user_id, protocol_version) = clients
+                .get(client_id)
+                .map(|c| (c.user_id.clone(), c.protocol_version))?;
+            if user_id.is_empty() {
+                send_error(
+                    client_id,
+                    clients,
+                    Some(&request),
+                    ServerError::new(ErrorCode::AuthRequired, "Authentication required"),
+                );
+                return None;
+            }
+            // The user is checked before the token's jti is spent
+            let claims = match jwt_config.validate_token(token) {
+                Ok(claims) if claims.sub == user_id => claims,
+                Ok(claims) => {
+                    METRICS.auth_failed();
+                    warn!(
+                        "Reauth of {} rejected: token is for {}, not {}",
+                        client_id, claims.sub, user_id
+                    );
+                    send_error(
+                        client_id,
+                        clients,
+                        Some(&request),
+                        ServerError::new(ErrorCode::AuthFailed, "Token is for another user"),
+                    );
+                    return None;
+                }
+                Err(e) => {
+                    METRICS.auth_failed();
+                    warn!("Reauth failed for {}: {}", client_id, e);
+                    send_error(
+                        client_id,
+                        clients,
+                        Some(&request),
+                        ServerError::new(ErrorCode::AuthFailed, "Authentication failed"),
+                    );
+                    return None;
+                }
+            };
+            if let Err(e) = jwt_config.admit(&claims, client_id) {
+                METRICS.auth_failed();
+                warn!("Reauth failed for {}: {}", client_id, e);
+                send_error(
+                    client_id,
+                    clients,
+                    Some(&request),
+                    ServerError::new(ErrorCode::AuthFailed, "Authentication failed"),
+                );
+                return None;
+            }
+            let expires_at = jwt_config.expires_at(&claims);
+            let permissions = Permissions::from_claims(&claims);
+            if let Some(mut client) = clients.get_mut(client_id) {
+                client.authenticated = true;
+                client.user_name = claims.name.clone();
+                client.token_exp = expires_at;
+                client.token_warned = false;
+                client.token_jti = claims.jti.clone();
+                client.permissions = permissions;
+            }
+            debug!("Client {} refreshed its token", client_id);
+            let used_token = jwt_config.used_token(&claims, client_id);
+            send_to_client(
+                client_id,
+                clients,
+                &WsMessage {
+                    body: ServerMessage::AuthSuccess {
+                        user_name: claims.name,
+                        protocol_version,
+                        expires_at,
+                        permissions,
+                    },
+                    id: request.id.clone(),
+                    room: None,
+                    client: Some(client_id.to_string()),
+                    ts: now_ms(),
+                    server_ts: Some(now_ms()),
+                },
+            );
+            cluster.identity_changed(client_id, clients).await;
+            if let Some(token) = used_token {
+                cluster.token_used(token).await;
+            }
+        }
+        ClientMessageType::Resume => {
+            let token = parsed
+                .payload
+                .as_ref()
+                .and_then(|p| p.get("token"))
+                .and_then(|v| v.as_str())
+                .unwrap_or("");
+            let grace_ms = config.session.resume_grace_ms;
+            if crate::room::resumable(client_id, token, clients, grace_ms, now_ms()).is_some() {
+                // Drop a room this socket joined on another node before resuming
+                cluster.leave_remote_room(client_id, clients, rooms).await;
+            }
+            match crate::room::handle_resume(client_id, token, clients, rooms, grace_ms).await {
+                Some(resumed_id) => {
+                    // A held session gave up its slot; count it again, even
+                    // over the cap, since it was admitted before
+                    if let Some(mut client) = clients.get_mut(&resumed_id) {
+                        if client.user_slot.is_none() && client.token_exp.is_some() {
+                            client.user_slot =
+                                limiter.acquire_user(&client.user_id, 0).map(Arc::new);
+                        }
                     }
+                    return Some(resumed_id);
+                }
+                None => {
+                    send_error(
+                        client_id,
+                        clients,
+                        Some(&request),
+                        ServerError::new(
+                            ErrorCode::ResumeFailed,
+                            "Session expired or invalid resume token",
+                        ),
+                    );
                 }
             }
         }
         ClientMessageType::ListRooms => {
-            send_room_list(client_id, clients, rooms).await;
+            send_room_list(
+                client_id,
+                clients,
+                rooms,
+                cluster.remote_rooms(),
+                request.id.clone(),
+            );
         }
         ClientMessageType::CreateRoom => {
             // Require authentication for room operations
-            if !is_authenticated(client_id, clients).await {
-                send_error(client_id, clients, "Authentication required").await;
-                return;
+            if !is_authenticated(client_id, clients) {
+                send_error(
+                    client_id,
+                    clients,
+                    Some(&request),
+                    ServerError::new(ErrorCode::AuthRequired, "Authentication required"),
+                );
+                return None;
+            }
+            if !permitted(client_id, clients, |p| p.can_create_room) {
+                send_error(
+                    client_id,
+                    clients,
+                    Some(&request),
+                    ServerError::new(
+                        ErrorCode::PermissionDenied,
+                        "You are not allowed to create rooms",
+                    ),
+                );
+                return None;
             }
 
-            // Close any existing room by this user (one room per user)
-            let existing_room_id = {
-                let locked_rooms = rooms.read().await;
-                locked_rooms
-                    .values()
-                    .find(|r| r.host_id == client_id)
-                    .map(|r| r.room_id.clone())
+            // Hash the optional room password up front (slow, so off the async runtime)
+            let password = parsed
+                .payload
+                .as_ref()
+                .and_then(|p| p.get("password"))
+                .and_then(|v| v.as_str())
+                .filter(|p| !p.is_empty())
+                .map(|p| p.to_string());
+            let password_hash = match password {
+                Some(p) if p.len() > MAX_PASSWORD_LENGTH => {
+                    send_error(
+                        client_id,
+                        clients,
+                        Some(&request),
+                        ServerError::new(ErrorCode::PasswordTooLong, "Room password too long"),
+                    );
+                    return None;
+                }
+                Some(p) => match tokio::task::spawn_blocking(move || hash_password(&p)).await {
+                    Ok(Ok(hash)) => Some(hash),
+                    Ok(Err(e)) => {
+                        log::error!("{}", e);
+                        send_error(
+                            client_id,
+                            clients,
+                            Some(&request),
+                            ServerError::new(
+                                ErrorCode::InternalError,
+                                "Failed to set room password",
+                            ),
+                        );
+                        return None;
+                    }
+                    Err(e) => {
+                        log::error!("Password hashing task failed: {}", e);
+                        send_error(
+                            client_id,
+                            clients,
+                            Some(&request),
+                            ServerError::new(
+                                ErrorCode::InternalError,
+                                "Failed to set room password",
+                            ),
+                        );
+                        return None;
+                    }
+                },
+                None => None,
             };
-            if let Some(room_id) = existing_room_id {
-                close_room(&room_id, clients, rooms).await;
+            let visibility = parsed
+                .payload
+                .as_ref()
+                .and_then(|p| p.get("visibility"))
+                .and_then(|v| serde_json::from_value::<RoomVisibility>(v.clone()).ok())
+                .unwrap_or_default();
+
+            // Close any existing room by this user (one room per user)
+            if let Some(room_id) = rooms.hosted_by(client_id) {
+                close_room(
+                    &room_id,
+                    "Host started a new room",
+                    clients,
+                    rooms,
+                    cluster.remote_rooms(),
+                )
+                .await;
             }
 
-            // Debug: log the payload
-            info!("create_room payload: {:?}", parsed.payload);
+            // Debug: log the payload (never the room password)
+            let mut logged_payload = parsed.payload.clone();
+            if let Some(obj) = logged_payload.as_mut().and_then(|p| p.as_object_mut()) {
+                obj.remove("password");
+            }
+            info!("create_room payload: {:?}", logged_payload);
 
             // Get username from payload first, fall back to client state
             let payload_name = parsed
@@ -348,13 +1015,10 @@ async fn client_msg(
                 .and_then(sanitize_name);
             let host_name = match &payload_name {
                 Some(name) => name.clone(),
-                None => {
-                    let locked_clients = clients.read().await;
-                    locked_clients
-                        .get(client_id)
-                        .map(|c| c.user_name.clone())
-                        .unwrap_or_else(|| "Anonymous".to_string())
-                }
+                None => clients
+                    .get(client_id)
+                    .map(|c| c.user_name.clone())
+                    .unwrap_or_else(|| "Anonymous".to_string()),
             };
             let room_name = format!("Room de {}", host_name);
 
@@ -377,6 +1041,24 @@ async fn client_msg(
                 .and_then(|v| v.as_str())
                 .filter(|id| is_valid_media_id(id))
                 .map(|v| v.to_string());
+            let host_policy = parsed
+                .payload
+                .as_ref()
+                .and_then(|p| p.get("host_policy"))
+                .and_then(|v| serde_json::from_value::<HostTransferPolicy>(v.clone()).ok())
+                .unwrap_or_default();
+            let control_mode = parsed
+                .payload
+                .as_ref()
+                .and_then(|p| p.get("control_mode"))
+                .and_then(|v| serde_json::from_value::<ControlMode>(v.clone()).ok())
+                .unwrap_or_default();
+            let controllers = parsed
+                .payload
+                .as_ref()
+                .and_then(|p| p.get("controllers"))
+                .map(|list| parse_controllers(list, config.limits.max_clients_per_room))
+                .unwrap_or_default();
 
             info!(
                 "Creating room '{}' ({}) for {}",
@@ -388,6 +1070,7 @@ async fn client_msg(
                 name: room_name,
                 host_id: client_id.to_string(),
                 media_id,
+                queue: Vec::new(),
                 clients: vec![client_id.to_string()],
                 ready_clients: HashSet::from([client_id.to_string()]),
                 pending_play: None,
@@ -395,46 +1078,55 @@ async fn client_msg(
                     position: start_pos,
                     play_state: "paused".to_string(),
                 },
+                host_policy,
+                successor_id: None,
+                control_mode,
+                controllers,
+                visibility,
+                password_hash,
+                invite_codes: HashSet::new(),
+                banned_users: HashSet::new(),
+                muted: HashSet::new(),
+                chat_history: Default::default(),
+                position_ts: now_ms(),
                 last_state_ts: now_ms(),
                 last_command_ts: 0,
+                last_command_by: None,
             };
 
-            {
-                let mut locked_rooms = rooms.write().await;
-                let mut locked_clients = clients.write().await;
-
-                locked_rooms.insert(room_id.clone(), room.clone());
-                if let Some(client) = locked_clients.get_mut(client_id) {
-                    client.room_id = Some(room_id.clone());
-                    // Update username from payload if provided (for chat messages)
-                    if let Some(ref name) = payload_name {
-                        client.user_name = name.clone();
-                    }
+            if let Some(mut client) = clients.get_mut(client_id) {
+                client.room_id = Some(room_id.clone());
+                // Update username from payload if provided (for chat messages)
+                if let Some(ref name) = payload_name {
+                    client.user_name = name.clone();
                 }
-
-                send_to_client(
-                    client_id,
-                    &locked_clients,
-                    &WsMessage {
-                        msg_type: "room_state".to_string(),
-                        room: Some(room_id.clone()),
-                        client: Some(client_id.to_string()),
-                        payload: Some(
-                            serde_json::json!({ "name": room.name, "host_id": room.host_id, "state": room.state, "participant_count": 1, "media_id": room.media_id }),
-                        ),
-                        ts: now_ms(),
-                        server_ts: Some(now_ms()),
-                    },
-                );
             }
+            send_to_client(
+                client_id,
+                clients,
+                &WsMessage {
+                    body: ServerMessage::RoomState(room_state_payload(&room)),
+                    id: request.id.clone(),
+                    room: Some(room_id.clone()),
+                    client: Some(client_id.to_string()),
+                    ts: now_ms(),
+                    server_ts: Some(now_ms()),
+                },
+            );
+            rooms.spawn(room, clients);
 
-            broadcast_room_list(clients, rooms).await;
+            broadcast_room_list(clients, rooms, cluster.remote_rooms());
         }
         ClientMessageType::JoinRoom => {
             // Require authentication for room operations
-            if !is_authenticated(client_id, clients).await {
-                send_error(client_id, clients, "Authentication required").await;
-                return;
+            if !is_authenticated(client_id, clients) {
+                send_error(
+                    client_id,
+                    clients,
+                    Some(&request),
+                    ServerError::new(ErrorCode::AuthRequired, "Authentication required"),
+                );
+                return None;
             }
             if let Some(ref room_id) = parsed.room {
                 // Extract username from payload if provided
@@ -444,259 +1136,821 @@ async fn client_msg(
                     .and_then(|p| p.get("user_name"))
                     .and_then(|v| v.as_str())
                     .and_then(sanitize_name);
-
-                let mut locked_rooms = rooms.write().await;
-                let mut locked_clients = clients.write().await;
-
-                if let Some(room) = locked_rooms.get_mut(room_id) {
-                    // Check room capacity before joining
-                    if !room.clients.contains(&client_id.to_string())
-                        && room.clients.len() >= MAX_CLIENTS_PER_ROOM
-                    {
+                let payload_str = |key: &str| {
+                    parsed
+                        .payload
+                        .as_ref()
+                        .and_then(|p| p.get(key))
+                        .and_then(|v| v.as_str())
+                        .map(|v| v.to_string())
+                };
+                let password = payload_str("password").unwrap_or_default();
+                let invite_code = payload_str("invite_code");
+
+                // Check bans and the room password before joining (hashing is slow).
+                // Current members (e.g. the host re-joining) and server admins
+                // are not asked.
+                let (user_id, admin) = clients
+                    .get(client_id)
+                    .map(|c| (c.user_id.clone(), c.permissions.admin))
+                    .unwrap_or_default();
+                let (banned, password_hash) = {
+                    let client_id = client_id.to_string();
+                    rooms
+                        .call(room_id, move |room, _| {
+                            if admin || room.clients.contains(&client_id) {
+                                (false, None)
+                            } else {
+                                (
+                                    room.banned_users.contains(&user_id),
+                                    room.password_hash.clone(),
+                                )
+                            }
+                        })
+                        .await
+                        .unwrap_or((false, None))
+                };
+                if banned {
+                    send_to_client(
+                        client_id,
+                        clients,
+                        &join_error(
+                            client_id,
+                            room_id,
+                            &request,
+                            ErrorCode::Banned,
+                            "You are banned from this room",
+                        ),
+                    );
+                    return None;
+                }
+                if let Some(hash) = password_hash {
+                    let valid = !password.is_empty()
+                        && password.len() <= MAX_PASSWORD_LENGTH
+                        && tokio::task::spawn_blocking(move || verify_password(&password, &hash))
+                            .await
+                            .unwrap_or(false);
+                    if !valid {
+                        warn!(
+                            "Client {} gave a wrong password for room {}",
+                            client_id, room_id
+                        );
                         send_to_client(
                             client_id,
-                            &locked_clients,
+                            clients,
+                            &join_error(
+                                client_id,
+                                room_id,
+                                &request,
+                                ErrorCode::InvalidPassword,
+                                "Incorrect room password",
+                            ),
+                        );
+                        return None;
+                    }
+                }
+
+                let max_clients = config.limits.max_clients_per_room;
+                let joined = {
+                    let target = room_id.as_str();
+                    let client_id = client_id.to_string();
+                    let room_id = room_id.clone();
+                    let request = request.clone();
+                    rooms.call(target, move |room, clients| {
+                        // Check room capacity before joining
+                        if !room.clients.contains(&client_id) && room.clients.len() >= max_clients {
+                            send_to_client(
+                                &client_id,
+                                clients,
+                                &join_error(
+                                    &client_id,
+                                    &room_id,
+                                    &request,
+                                    ErrorCode::RoomFull,
+                                    "Room is full",
+                                ),
+                            );
+                            return;
+                        }
+
+                        // Invite-only rooms consume a single-use invite code on join
+                        if !admin
+                            && !room.clients.contains(&client_id)
+                            && room.visibility == RoomVisibility::InviteOnly
+                        {
+                            let rejection = match &invite_code {
+                                Some(code) if room.invite_codes.remove(code) => None,
+                                Some(_) => Some((
+                                    ErrorCode::InvalidInvite,
+                                    "Invalid or already used invite code",
+                                )),
+                                None => Some((
+                                    ErrorCode::InviteRequired,
+                                    "This room requires an invite code",
+                                )),
+                            };
+                            if let Some((code, message)) = rejection {
+                                send_to_client(
+                                    &client_id,
+                                    clients,
+                                    &join_error(&client_id, &room_id, &request, code, message),
+                                );
+                                return;
+                            }
+                        }
+
+                        info!("Client {} joining room {}", client_id, room_id);
+                        if !room.clients.contains(&client_id) {
+                            room.clients.push(client_id.clone());
+                        }
+                        room.ready_clients.remove(&client_id);
+                        if let Some(mut client) = clients.get_mut(&client_id) {
+                            client.room_id = Some(room_id.clone());
+                            // Update username from payload if provided (for chat messages)
+                            if let Some(name) = payload_name {
+                                client.user_name = name;
+                            }
+                        }
+
+                        send_to_client(
+                            &client_id,
+                            clients,
                             &WsMessage {
-                                msg_type: "error".to_string(),
+                                body: ServerMessage::RoomState(room_state_payload(room)),
+                                id: request.id.clone(),
                                 room: Some(room_id.clone()),
-                                client: Some(client_id.to_string()),
-                                payload: Some(serde_json::json!({ "message": "Room is full" })),
+                                client: Some(client_id.clone()),
                                 ts: now_ms(),
                                 server_ts: Some(now_ms()),
                             },
                         );
-                        return;
-                    }
-
-                    info!("Client {} joining room {}", client_id, room_id);
-                    if !room.clients.contains(&client_id.to_string()) {
-                        room.clients.push(client_id.to_string());
-                    }
-                    room.ready_clients.remove(client_id);
-                    if let Some(client) = locked_clients.get_mut(client_id) {
-                        client.room_id = Some(room_id.clone());
-                        // Update username from payload if provided (for chat messages)
-                        if let Some(ref name) = payload_name {
-                            client.user_name = name.clone();
-                        }
-                    }
+                        send_to_client(
+                            &client_id,
+                            clients,
+                            &chat_history_message(room, &client_id),
+                        );
 
+                        broadcast_to_room(
+                            room,
+                            clients,
+                            &WsMessage {
+                                body: ServerMessage::ParticipantsUpdate {
+                                    participant_count: room.clients.len(),
+                                    state: expected_state(room, now_ms()),
+                                },
+                                id: None,
+                                room: Some(room_id.clone()),
+                                client: None,
+                                ts: now_ms(),
+                                server_ts: Some(now_ms()),
+                            },
+                            Some(&client_id),
+                        );
+                    })
+                };
+                if joined.await.is_none() {
                     send_to_client(
                         client_id,
-                        &locked_clients,
-                        &WsMessage {
-                            msg_type: "room_state".to_string(),
-                            room: Some(room_id.clone()),
-                            client: Some(client_id.to_string()),
-                            payload: Some(
-                                serde_json::json!({ "name": room.name, "host_id": room.host_id, "state": room.state, "participant_count": room.clients.len(), "media_id": room.media_id }),
-                            ),
-                            ts: now_ms(),
-                            server_ts: Some(now_ms()),
-                        },
-                    );
-
-                    broadcast_to_room(
-                        room,
-                        &locked_clients,
-                        &WsMessage {
-                            msg_type: "participants_update".to_string(),
-                            room: Some(room_id.clone()),
-                            client: None,
-                            payload: Some(
-                                serde_json::json!({ "participant_count": room.clients.len() }),
-                            ),
-                            ts: now_ms(),
-                            server_ts: Some(now_ms()),
-                        },
-                        Some(client_id),
+                        clients,
+                        &join_error(
+                            client_id,
+                            room_id,
+                            &request,
+                            ErrorCode::RoomNotFound,
+                            "Room not found",
+                        ),
                     );
                 }
             }
         }
         ClientMessageType::Ready => {
             if let Some(ref room_id) = parsed.room {
-                let mut locked_rooms = rooms.write().await;
-                if let Some(room) = locked_rooms.get_mut(room_id) {
-                    room.ready_clients.insert(client_id.to_string());
-                    if room.pending_play.is_some() && all_ready(room) {
-                        let target_server_ts = now_ms() + PLAY_SCHEDULE_MS;
-                        let position = room
-                            .pending_play
-                            .as_ref()
-                            .map(|p| p.position)
-                            .unwrap_or(room.state.position);
-                        room.pending_play = None;
-                        broadcast_scheduled_play(room, clients, position, target_server_ts).await;
-                    }
-                }
+                let ready_id = client_id.to_string();
+                let config = config.clone();
+                let result = rooms
+                    .call(room_id, move |room, clients| {
+                        room.ready_clients.insert(ready_id);
+                        if room.pending_play.is_some() && all_ready(room) {
+                            let position = room
+                                .pending_play
+                                .as_ref()
+                                .map(|p| p.position)
+                                .unwrap_or(room.state.position);
+                            room.pending_play = None;
+                            broadcast_scheduled_play(room, clients, position, &config);
+                        }
+                    })
+                    .await;
+                reply_to_command(
+                    client_id,
+                    clients,
+                    &request,
+                    result.ok_or_else(room_not_found),
+                );
             }
         }
         ClientMessageType::LeaveRoom => {
             info!("Client {} leaving room", client_id);
-            {
-                let mut locked_clients = clients.write().await;
-                let mut locked_rooms = rooms.write().await;
-                handle_leave(client_id, &mut locked_clients, &mut locked_rooms);
-            }
-            broadcast_room_list(clients, rooms).await;
+            leave_room(client_id, clients, rooms).await;
+            broadcast_room_list(clients, rooms, cluster.remote_rooms());
+            send_ack(client_id, clients, &request);
         }
         ClientMessageType::PlayerEvent | ClientMessageType::StateUpdate => {
-            if let Some(ref room_id) = parsed.room {
-                // P-RS01 fix: Collect senders while holding lock, then send after releasing
-                // This reduces lock contention during broadcasts
-                let broadcast_data: Option<(Vec<mpsc::Sender<_>>, String)> = {
-                    let mut locked_rooms = rooms.write().await;
-                    let locked_clients = clients.read().await;
-
-                    if let Some(room) = locked_rooms.get_mut(room_id) {
-                        if room.host_id != client_id {
-                            None
+            if let Some(room_id) = parsed.room.clone() {
+                let sender_id = client_id.to_string();
+                let config = config.clone();
+                let applied = rooms
+                    .call(&room_id.clone(), move |room, clients| {
+                        let client_id = sender_id.as_str();
+                        let current_ts = now_ms();
+                        // Commands follow the room's control mode; state reports stay host-only
+                        // so several clients don't fight over the reference position.
+                        let allowed = if parsed.msg_type == ClientMessageType::PlayerEvent {
+                            clients
+                                .get(client_id)
+                                .is_some_and(|c| may_control(room, client_id, c.permissions))
                         } else {
-                            let current_ts = now_ms();
-
-                            // For state_update: filter out updates that are too frequent or have insignificant changes
-                            let should_process = if parsed.msg_type
-                                == ClientMessageType::StateUpdate
-                            {
-                                if let Some(payload) = &parsed.payload {
-                                    let new_pos = payload
-                                        .get("position")
-                                        .and_then(|v| v.as_f64())
-                                        .unwrap_or(room.state.position);
-                                    let new_play_state = payload
-                                        .get("play_state")
-                                        .and_then(|v| v.as_str())
-                                        .unwrap_or(&room.state.play_state);
-                                    let play_state_changed =
-                                        new_play_state != room.state.play_state;
-                                    let pos_diff = new_pos - room.state.position;
-
-                                    // Always allow state_update if play_state changed (critical for sync)
-                                    // Only apply cooldown/throttle for position-only updates
-                                    if play_state_changed {
-                                        true
-                                    } else {
-                                        // Check various throttle conditions
-                                        let in_command_cooldown = room.last_command_ts > 0
-                                            && current_ts - room.last_command_ts
-                                                < COMMAND_COOLDOWN_MS;
-                                        let too_frequent = current_ts - room.last_state_ts
-                                            < MIN_STATE_UPDATE_INTERVAL_MS;
-                                        let small_backward_jitter =
-                                            (-2.0..-POSITION_JITTER_THRESHOLD).contains(&pos_diff);
-                                        let small_forward_jitter =
-                                            (0.0..POSITION_JITTER_THRESHOLD).contains(&pos_diff);
-
-                                        !(in_command_cooldown
-                                            || too_frequent
-                                            || small_backward_jitter
-                                            || small_forward_jitter)
-                                    }
-                                } else {
+                            room.host_id == client_id
+                        };
+                        // With several controllers, a command from someone else during
+                        // the cooldown loses (prevents two simultaneous pauses ping-ponging)
+                        let contested = parsed.msg_type == ClientMessageType::PlayerEvent
+                            && room.control_mode != ControlMode::HostOnly
+                            && room.last_command_ts > 0
+                            && current_ts - room.last_command_ts < config.sync.command_cooldown_ms
+                            && room.last_command_by.as_deref() != Some(client_id);
+                        if contested {
+                            debug!(
+                                "Dropping contested command from {} in room {}",
+                                client_id, room_id
+                            );
+                        }
+                        if !allowed || contested {
+                            return false;
+                        }
+                        // For state_update: filter out updates that are too frequent or have insignificant changes
+                        let should_process = if parsed.msg_type == ClientMessageType::StateUpdate {
+                            if let Some(payload) = &parsed.payload {
+                                let new_pos = payload
+                                    .get("position")
+                                    .and_then(|v| v.as_f64())
+                                    .unwrap_or(room.state.position);
+                                let new_play_state = payload
+                                    .get("play_state")
+                                    .and_then(|v| v.as_str())
+                                    .unwrap_or(&room.state.play_state);
+                                let play_state_changed = new_play_state != room.state.play_state;
+                                let pos_diff = new_pos - room.state.position;
+
+                                // Always allow state_update if play_state changed (critical for sync)
+                                // Only apply cooldown/throttle for position-only updates
+                                if play_state_changed {
                                     true
+                                } else {
+                                    // Check various throttle conditions
+                                    let in_command_cooldown = room.last_command_ts > 0
+                                        && current_ts - room.last_command_ts
+                                            < config.sync.command_cooldown_ms;
+                                    let too_frequent = current_ts - room.last_state_ts
+                                        < config.sync.min_state_update_interval_ms;
+                                    let small_backward_jitter =
+                                        (-2.0..-config.sync.position_jitter_threshold)
+                                            .contains(&pos_diff);
+                                    let small_forward_jitter =
+                                        (0.0..config.sync.position_jitter_threshold)
+                                            .contains(&pos_diff);
+
+                                    !(in_command_cooldown
+                                        || too_frequent
+                                        || small_backward_jitter
+                                        || small_forward_jitter)
                                 }
                             } else {
                                 true
-                            };
+                            }
+                        } else {
+                            true
+                        };
+                        if !should_process {
+                            return false;
+                        }
 
-                            if !should_process {
-                                None
-                            } else {
-                                if let Some(payload) = &parsed.payload {
-                                    // Validate and update position
-                                    if let Some(pos) =
-                                        payload.get("position").and_then(|v| v.as_f64())
-                                    {
-                                        if is_valid_position(pos) {
-                                            room.state.position = pos;
-                                        }
-                                    }
-                                    // Validate and update play_state
-                                    if let Some(st) =
-                                        payload.get("play_state").and_then(|v| v.as_str())
-                                    {
-                                        if is_valid_play_state(st) {
-                                            room.state.play_state = st.to_string();
-                                        }
-                                    }
-                                    if parsed.msg_type == ClientMessageType::PlayerEvent {
-                                        if let Some(action) =
-                                            payload.get("action").and_then(|v| v.as_str())
-                                        {
-                                            if action == "play" {
-                                                room.state.play_state = "playing".to_string();
-                                            }
-                                            if action == "pause" {
-                                                room.state.play_state = "paused".to_string();
-                                            }
-                                        }
-                                    }
+                        // Carry the old state forward to now, so a payload without
+                        // a position (e.g. play_state only) doesn't rewind it
+                        room.state = expected_state(room, current_ts);
+                        room.position_ts = current_ts;
+                        if let Some(payload) = &parsed.payload {
+                            // Validate and update position
+                            if let Some(pos) = payload.get("position").and_then(|v| v.as_f64()) {
+                                if is_valid_position(pos) {
+                                    room.state.position = pos;
                                 }
-
-                                room.last_state_ts = current_ts;
-
-                                if parsed.msg_type == ClientMessageType::PlayerEvent {
-                                    room.last_command_ts = current_ts;
-                                    let target_server_ts = now_ms() + CONTROL_SCHEDULE_MS;
-                                    if let Some(payload) = parsed.payload.as_mut() {
-                                        payload["target_server_ts"] =
-                                            serde_json::json!(target_server_ts);
-                                    }
-                                    parsed.server_ts = Some(target_server_ts);
-                                } else {
-                                    parsed.server_ts = Some(now_ms());
+                            }
+                            // Validate and update play_state
+                            if let Some(st) = payload.get("play_state").and_then(|v| v.as_str()) {
+                                if is_valid_play_state(st) {
+                                    room.state.play_state = st.to_string();
                                 }
-
-                                // Collect senders for clients in the room (excluding sender)
-                                let senders: Vec<_> = room
-                                    .clients
-                                    .iter()
-                                    .filter(|id| *id != client_id)
-                                    .filter_map(|id| {
-                                        locked_clients.get(id).map(|c| c.sender.clone())
-                                    })
-                                    .collect();
-
-                                // Serialize message once
-                                match serde_json::to_string(&parsed) {
-                                    Ok(json) => Some((senders, json)),
-                                    Err(e) => {
-                                        log::error!("Failed to serialize message: {}", e);
-                                        None
+                            }
+                            if parsed.msg_type == ClientMessageType::PlayerEvent {
+                                if let Some(action) = payload.get("action").and_then(|v| v.as_str())
+                                {
+                                    if action == "play" {
+                                        room.state.play_state = "playing".to_string();
+                                    }
+                                    if action == "pause" {
+                                        room.state.play_state = "paused".to_string();
                                     }
                                 }
                             }
                         }
-                    } else {
-                        None
-                    }
-                }; // Locks released here
-
-                // Send messages without holding any locks (P-RS01 fix)
-                if let Some((senders, json)) = broadcast_data {
-                    let warp_msg = warp::ws::Message::text(json);
-                    for sender in senders {
-                        if let Err(e) = sender.try_send(Ok(warp_msg.clone())) {
-                            log::warn!(
-                                "Failed to send player event (buffer full or closed): {}",
-                                e
+
+                        room.last_state_ts = current_ts;
+
+                        let payload = parsed.payload.as_ref();
+                        let field = |key: &str| payload.and_then(|p| p.get(key));
+                        let position = field("position").and_then(|v| v.as_f64());
+                        let play_state = field("play_state")
+                            .and_then(|v| v.as_str())
+                            .map(str::to_string);
+                        let (body, server_ts) = if parsed.msg_type == ClientMessageType::PlayerEvent
+                        {
+                            room.last_command_ts = current_ts;
+                            room.last_command_by = Some(client_id.to_string());
+                            let schedule = config.sync.schedule();
+                            let delay_ms = schedule.delay_ms(
+                                worst_rtt_ms(room, clients, Some(client_id), &schedule),
+                                config.sync.control_schedule_ms,
+                            );
+                            let target_server_ts = now_ms() + delay_ms;
+                            // The command takes effect at the target time, not now
+                            room.position_ts = target_server_ts;
+                            warn_late_participants(
+                                room,
+                                clients,
+                                target_server_ts,
+                                Some(client_id),
                             );
+                            let event = PlayerEventPayload {
+                                action: field("action")
+                                    .and_then(|v| v.as_str())
+                                    .unwrap_or_default()
+                                    .to_string(),
+                                position,
+                                play_state,
+                                target_server_ts: Some(target_server_ts),
+                                schedule_delay_ms: Some(delay_ms),
+                            };
+                            (ServerMessage::PlayerEvent(event), target_server_ts)
+                        } else {
+                            let update = StateUpdatePayload {
+                                position,
+                                play_state,
+                            };
+                            (ServerMessage::StateUpdate(update), now_ms())
+                        };
+
+                        // Relay to everyone else, attributed to the actual sender; the
+                        // request ID only means something to the sender
+                        let msg = WsMessage {
+                            body,
+                            id: None,
+                            room: parsed.room.clone(),
+                            client: Some(client_id.to_string()),
+                            ts: parsed.ts,
+                            server_ts: Some(server_ts),
+                        };
+                        match serde_json::to_string(&msg) {
+                            Ok(json) => send_to_members(room, clients, json, Some(client_id)),
+                            Err(e) => log::error!("Failed to serialize message: {}", e),
+                        }
+                        true
+                    })
+                    .await;
+                // State reports are fire-and-forget; commands are acknowledged,
+                // including when the room dropped them
+                if request.msg_type == ClientMessageType::PlayerEvent {
+                    match applied {
+                        Some(_) if !speaks_acks(client_id, clients) => {}
+                        Some(applied) => send_to_client(
+                            client_id,
+                            clients,
+                            &ack_message(client_id, &request, Some(applied)),
+                        ),
+                        None => send_error(client_id, clients, Some(&request), room_not_found()),
+                    }
+                }
+            }
+        }
+        ClientMessageType::SetSuccessor => {
+            // Host designates who takes over if they leave (used by the `designated` policy)
+            if let Some(ref room_id) = parsed.room {
+                let successor = parsed
+                    .payload
+                    .as_ref()
+                    .and_then(|p| p.get("client_id"))
+                    .and_then(|v| v.as_str())
+                    .map(|v| v.to_string());
+                let host_id = client_id.to_string();
+                let result = rooms
+                    .call(room_id, move |room, _| {
+                        if room.host_id != host_id {
+                            return Err(ServerError::new(
+                                ErrorCode::NotHost,
+                                "Only the host can set a successor",
+                            ));
+                        }
+                        match successor {
+                            Some(id) if id != host_id && room.clients.contains(&id) => {
+                                info!("Host {} designated {} as successor", host_id, id);
+                                room.successor_id = Some(id);
+                            }
+                            None => room.successor_id = None,
+                            Some(_) => {
+                                return Err(ServerError::new(
+                                    ErrorCode::InvalidRequest,
+                                    "Successor must be a participant",
+                                ))
+                            }
+                        }
+                        Ok(())
+                    })
+                    .await
+                    .unwrap_or_else(|| Err(room_not_found()));
+                reply_to_command(client_id, clients, &request, result);
+            }
+        }
+        ClientMessageType::SetControlMode => {
+            // Host changes who may control playback
+            if let Some(ref room_id) = parsed.room {
+                let payload = parsed.payload.as_ref();
+                let mode = payload
+                    .and_then(|p| p.get("control_mode"))
+                    .and_then(|v| serde_json::from_value::<ControlMode>(v.clone()).ok());
+                let Some(mode) = mode else {
+                    send_error(
+                        client_id,
+                        clients,
+                        Some(&request),
+                        ServerError::new(ErrorCode::InvalidRequest, "Invalid control mode"),
+                    );
+                    return None;
+                };
+                let controllers = payload
+                    .and_then(|p| p.get("controllers"))
+                    .map(|list| parse_controllers(list, config.limits.max_clients_per_room));
+
+                let host_id = client_id.to_string();
+                let result = rooms
+                    .call(room_id, move |room, clients| {
+                        if room.host_id != host_id {
+                            return Err(ServerError::new(
+                                ErrorCode::NotHost,
+                                "Only the host can change control mode",
+                            ));
+                        }
+                        room.control_mode = mode;
+                        if let Some(controllers) = controllers {
+                            room.controllers = controllers;
+                        }
+                        info!("Room {} control mode set to {:?}", room.room_id, mode);
+                        broadcast_to_room(
+                            room,
+                            clients,
+                            &WsMessage {
+                                body: ServerMessage::ControlModeChanged {
+                                    control_mode: room.control_mode,
+                                    controllers: room.controllers.clone(),
+                                },
+                                id: None,
+                                room: Some(room.room_id.clone()),
+                                client: Some(host_id.clone()),
+                                ts: now_ms(),
+                                server_ts: Some(now_ms()),
+                            },
+                            None,
+                        );
+                        Ok(())
+                    })
+                    .await
+                    .unwrap_or_else(|| Err(room_not_found()));
+                reply_to_command(client_id, clients, &request, result);
+            }
+        }
+        ClientMessageType::QueueAdd
+        | ClientMessageType::QueueRemove
+        | ClientMessageType::QueueMove => {
+            // Host edits the upcoming-media queue
+            if let Some(ref room_id) = parsed.room {
+                let payload = parsed.payload.as_ref();
+                let get_index = |key: &str| {
+                    payload
+                        .and_then(|p| p.get(key))
+                        .and_then(|v| v.as_u64())
+                        .map(|v| v as usize)
+                };
+                let edit = match parsed.msg_type {
+                    ClientMessageType::QueueAdd => payload
+                        .and_then(|p| p.get("media_id"))
+                        .and_then(|v| v.as_str())
+                        .filter(|id| is_valid_media_id(id))
+                        .map(|id| QueueEdit::Add {
+                            media_id: id.to_string(),
+                            index: get_index("index"),
+                        }),
+                    ClientMessageType::QueueRemove => get_index("index").map(QueueEdit::Remove),
+                    _ => get_index("from")
+                        .zip(get_index("to"))
+                        .map(|(from, to)| QueueEdit::Move { from, to }),
+                };
+                let Some(edit) = edit else {
+                    send_error(
+                        client_id,
+                        clients,
+                        Some(&request),
+                        ServerError::new(ErrorCode::InvalidRequest, "Invalid queue edit"),
+                    );
+                    return None;
+                };
+
+                let host_id = client_id.to_string();
+                let max_queue_length = config.limits.max_queue_length;
+                let result = rooms
+                    .call(room_id, move |room, clients| {
+                        if room.host_id != host_id {
+                            return Err(ServerError::new(
+                                ErrorCode::NotHost,
+                                "Only the host can edit the queue",
+                            ));
+                        }
+                        if matches!(edit, QueueEdit::Add { .. })
+                            && room.queue.len() >= max_queue_length
+                        {
+                            return Err(ServerError::new(ErrorCode::QueueFull, "Queue is full"));
+                        }
+                        edit_queue(room, edit)?;
+                        broadcast_to_room(
+                            room,
+                            clients,
+                            &WsMessage {
+                                body: ServerMessage::QueueUpdated {
+                                    queue: room.queue.clone(),
+                                },
+                                id: None,
+                                room: Some(room.room_id.clone()),
+                                client: Some(host_id.clone()),
+                                ts: now_ms(),
+                                server_ts: Some(now_ms()),
+                            },
+                            None,
+                        );
+                        Ok(())
+                    })
+                    .await
+                    .unwrap_or_else(|| Err(room_not_found()));
+                if result.is_ok() {
+                    broadcast_room_list(clients, rooms, cluster.remote_rooms());
+                }
+                reply_to_command(client_id, clients, &request, result);
+            }
+        }
+        ClientMessageType::QueueNext => {
+            // Host advances the room to the next queued item
+            if let Some(ref room_id) = parsed.room {
+                let host_id = client_id.to_string();
+                let result = rooms
+                    .call(room_id, move |room, clients| {
+                        if room.host_id != host_id {
+                            return Err(ServerError::new(
+                                ErrorCode::NotHost,
+                                "Only the host can advance the queue",
+                            ));
+                        }
+                        let Some(media_id) = advance_queue(room, now_ms()) else {
+                            return Err(ServerError::new(ErrorCode::QueueEmpty, "Queue is empty"));
+                        };
+                        info!("Room {} advanced to media {}", room.room_id, media_id);
+                        broadcast_to_room(
+                            room,
+                            clients,
+                            &WsMessage {
+                                body: ServerMessage::MediaChanged {
+                                    media_id: room.media_id.clone(),
+                                    queue: room.queue.clone(),
+                                    state: room.state.clone(),
+                                },
+                                id: None,
+                                room: Some(room.room_id.clone()),
+                                client: Some(host_id.clone()),
+                                ts: now_ms(),
+                                server_ts: Some(now_ms()),
+                            },
+                            None,
+                        );
+                        Ok(())
+                    })
+                    .await
+                    .unwrap_or_else(|| Err(room_not_found()));
+                if result.is_ok() {
+                    broadcast_room_list(clients, rooms, cluster.remote_rooms());
+                }
+                reply_to_command(client_id, clients, &request, result);
+            }
+        }
+        ClientMessageType::CreateInvite => {
+            // Host issues a single-use invite code for the room
+            if let Some(ref room_id) = parsed.room {
+                let host_id = client_id.to_string();
+                let max_invites = config.limits.max_invites_per_room;
+                let reply_id = request.id.clone();
+                let result = rooms
+                    .call(room_id, move |room, clients| {
+                        if room.host_id != host_id {
+                            return Err(ServerError::new(
+                                ErrorCode::NotHost,
+                                "Only the host can create invites",
+                            ));
+                        }
+                        if room.invite_codes.len() >= max_invites {
+                            return Err(ServerError::new(
+                                ErrorCode::TooManyInvites,
+                                "Too many unused invites",
+                            ));
                         }
+                        let code = uuid::Uuid::new_v4().simple().to_string();
+                        room.invite_codes.insert(code.clone());
+                        info!(
+                            "Host {} created an invite for room {}",
+                            host_id, room.room_id
+                        );
+                        send_to_client(
+                            &host_id,
+                            clients,
+                            &WsMessage {
+                                body: ServerMessage::InviteCreated { invite_code: code },
+                                id: reply_id.clone(),
+                                room: Some(room.room_id.clone()),
+                                client: Some(host_id.clone()),
+                                ts: now_ms(),
+                                server_ts: Some(now_ms()),
+                            },
+                        );
+                        Ok(())
+                    })
+                    .await
+                    .unwrap_or_else(|| Err(room_not_found()));
+                if let Err(e) = result {
+                    send_error(client_id, clients, Some(&request), e);
+                }
+            }
+        }
+        ClientMessageType::KickParticipant
+        | ClientMessageType::BanParticipant
+        | ClientMessageType::MuteParticipant => {
+            let Some(ref room_id) = parsed.room else {
+                send_error(
+                    client_id,
+                    clients,
+                    Some(&request),
+                    ServerError::new(ErrorCode::RoomRequired, "Room ID required for moderation"),
+                );
+                return None;
+            };
+            let payload = parsed.payload.as_ref();
+            let target = payload
+                .and_then(|p| p.get("client_id"))
+                .and_then(|v| v.as_str())
+                .unwrap_or("")
+                .to_string();
+            let action = match parsed.msg_type {
+                ClientMessageType::KickParticipant => ModerationAction::Kick,
+                ClientMessageType::BanParticipant => ModerationAction::Ban,
+                _ => {
+                    // `muted: false` lifts a mute
+                    let mute = payload
+                        .and_then(|p| p.get("muted"))
+                        .and_then(|v| v.as_bool())
+                        .unwrap_or(true);
+                    if mute {
+                        ModerationAction::Mute
+                    } else {
+                        ModerationAction::Unmute
                     }
                 }
+            };
+            let moderator_id = client_id.to_string();
+            let result = rooms
+                .call(room_id, move |room, clients| {
+                    moderate(room, &moderator_id, &target, action, clients)
+                })
+                .await
+                .unwrap_or_else(|| Err(room_not_found()));
+            if result.is_ok() && matches!(action, ModerationAction::Kick | ModerationAction::Ban) {
+                broadcast_room_list(clients, rooms, cluster.remote_rooms());
+            }
+            reply_to_command(client_id, clients, &request, result);
+        }
+        ClientMessageType::TimeSync => {
+            // NTP-style exchange. The client reports when our previous reply
+            // arrived, which completes that exchange's RTT/offset sample.
+            let payload = parsed.payload.as_ref();
+            let get_ts = |key: &str| payload.and_then(|p| p.get(key)).and_then(|v| v.as_u64());
+            let Some(client_send_ts) = get_ts("client_send_ts") else {
+                send_error(
+                    client_id,
+                    clients,
+                    Some(&request),
+                    ServerError::new(
+                        ErrorCode::InvalidRequest,
+                        "client_send_ts required for time_sync",
+                    ),
+                );
+                return None;
+            };
+            let reply = clients.get_mut(client_id).map(|mut client| {
+                if let (Some(prev_send), Some(prev_recv)) =
+                    (get_ts("prev_client_send_ts"), get_ts("prev_client_recv_ts"))
+                {
+                    client.clock.complete(prev_send, prev_recv, received_at);
+                }
+                let server_send_ts = now_ms();
+                client
+                    .clock
+                    .start(client_send_ts, received_at, server_send_ts);
+                WsMessage {
+                    body: ServerMessage::TimeSync(TimeSyncPayload {
+                        client_send_ts,
+                        server_recv_ts: received_at,
+                        server_send_ts,
+                        rtt_ms: client.clock.rtt_ms(),
+                        offset_ms: client.clock.offset_ms(),
+                    }),
+                    id: request.id.clone(),
+                    room: None,
+                    client: Some(client_id.to_string()),
+                    ts: server_send_ts,
+                    server_ts: Some(server_send_ts),
+                }
+            });
+            if let Some(msg) = reply {
+                send_to_client(client_id, clients, &msg);
+            }
+        }
+        ClientMessageType::GetSyncQuality => {
+            // Host view of each participant's measured latency and clock offset
+            if let Some(ref room_id) = parsed.room {
+                let host_id = client_id.to_string();
+                let reply_id = request.id.clone();
+                let result = rooms
+                    .call(room_id, move |room, clients| {
+                        if room.host_id != host_id {
+                            return Err(ServerError::new(
+                                ErrorCode::NotHost,
+                                "Only the host can view sync quality",
+                            ));
+                        }
+                        let participants = room
+                            .clients
+                            .iter()
+                            .filter_map(|id| {
+                                let c = clients.get(id)?;
+                                Some(ParticipantSyncQuality {
+                                    client_id: id.clone(),
+                                    user_name: c.user_name.clone(),
+                                    quality: c.clock.quality(),
+                                })
+                            })
+                            .collect();
+                        send_to_client(
+                            &host_id,
+                            clients,
+                            &WsMessage {
+                                body: ServerMessage::SyncQuality { participants },
+                                id: reply_id.clone(),
+                                room: Some(room.room_id.clone()),
+                                client: Some(host_id.clone()),
+                                ts: now_ms(),
+                                server_ts: Some(now_ms()),
+                            },
+                        );
+                        Ok(())
+                    })
+                    .await
+                    .unwrap_or_else(|| Err(room_not_found()));
+                if let Err(e) = result {
+                    send_error(client_id, clients, Some(&request), e);
+                }
             }
         }
         ClientMessageType::Ping => {
-            let locked_clients = clients.read().await;
             send_to_client(
                 client_id,
-                &locked_clients,
+                clients,
                 &WsMessage {
-                    msg_type: "pong".to_string(),
+                    body: ServerMessage::Pong(parsed.payload),
+                    id: request.id.clone(),
                     room: parsed.room,
                     client: parsed.client,
-                    payload: parsed.payload,
                     ts: now_ms(),
                     server_ts: Some(now_ms()),
                 },
@@ -718,6 +1972,15 @@ async fn client_msg(
             }
         }
         ClientMessageType::ChatMessage => {
+            if !permitted(client_id, clients, |p| p.can_chat) {
+                send_error(
+                    client_id,
+                    clients,
+                    Some(&request),
+                    ServerError::new(ErrorCode::PermissionDenied, "You are not allowed to chat"),
+                );
+                return None;
+            }
             // Handle chat messages within a room
             if let Some(ref room_id) = parsed.room {
                 // Get chat text from payload
@@ -726,103 +1989,215 @@ async fn client_msg(
                     .as_ref()
                     .and_then(|p| p.get("text"))
                     .and_then(|v| v.as_str())
-                    .unwrap_or("");
+                    .unwrap_or("")
+                    .to_string();
 
                 // Validate message length
                 if chat_text.is_empty() {
-                    send_error(client_id, clients, "Chat message cannot be empty").await;
-                    return;
+                    send_error(
+                        client_id,
+                        clients,
+                        Some(&request),
+                        ServerError::new(ErrorCode::ChatEmpty, "Chat message cannot be empty"),
+                    );
+                    return None;
                 }
-                if chat_text.len() > MAX_CHAT_MESSAGE_LENGTH {
+                if chat_text.len() > config.limits.max_chat_message_length {
                     send_error(
                         client_id,
                         clients,
-                        &format!(
-                            "Chat message too long (max {} characters)",
-                            MAX_CHAT_MESSAGE_LENGTH
+                        Some(&request),
+                        ServerError::new(
+                            ErrorCode::ChatTooLong,
+                            format!(
+                                "Chat message too long (max {} characters)",
+                                config.limits.max_chat_message_length
+                            ),
                         ),
-                    )
-                    .await;
-                    return;
+                    );
+                    return None;
                 }
 
                 // Get username from client state
-                let username = {
-                    let locked_clients = clients.read().await;
-                    locked_clients
-                        .get(client_id)
-                        .map(|c| c.user_name.clone())
-                        .unwrap_or_else(|| "Anonymous".to_string())
-                };
-
-                // Broadcast chat message to all clients in the room
-                let broadcast_data: Option<(Vec<mpsc::Sender<_>>, String)> = {
-                    let locked_rooms = rooms.read().await;
-                    let locked_clients = clients.read().await;
-
-                    if let Some(room) = locked_rooms.get(room_id) {
+                let username = clients
+                    .get(client_id)
+                    .map(|c| c.user_name.clone())
+                    .unwrap_or_else(|| "Anonymous".to_string());
+
+                // Broadcast chat message to all clients in the room (including sender)
+                let sender_id = client_id.to_string();
+                let request_id = request.id.clone();
+                let outcome = rooms
+                    .call(room_id, move |room, clients| {
+                        if is_muted(room, &sender_id, clients) {
+                            return ChatOutcome::Muted;
+                        }
                         // Only allow chat if client is in the room
-                        if !room.clients.contains(&client_id.to_string()) {
-                            None
-                        } else {
-                            let msg = WsMessage {
-                                msg_type: "chat_message".to_string(),
-                                room: Some(room_id.clone()),
-                                client: Some(client_id.to_string()),
-                                payload: Some(serde_json::json!({
-                                    "username": username,
-                                    "text": chat_text
-                                })),
-                                ts: now_ms(),
-                                server_ts: Some(now_ms()),
-                            };
-
-                            // Collect senders for ALL clients in the room (including sender)
-                            let senders: Vec<_> = room
-                                .clients
-                                .iter()
-                                .filter_map(|id| locked_clients.get(id).map(|c| c.sender.clone()))
-                                .collect();
-
-                            match serde_json::to_string(&msg) {
-                                Ok(json) => Some((senders, json)),
-                                Err(e) => {
-                                    log::error!("Failed to serialize chat_message: {}", e);
-                                    None
-                                }
-                            }
+                        if !room.clients.contains(&sender_id) {
+                            return ChatOutcome::NotMember;
                         }
-                    } else {
-                        None
-                    }
-                };
-
-                // Send messages without holding any locks
-                if let Some((senders, json)) = broadcast_data {
-                    let warp_msg = warp::ws::Message::text(json);
-                    for sender in senders {
-                        if let Err(e) = sender.try_send(Ok(warp_msg.clone())) {
-                            log::warn!(
-                                "Failed to send chat_message (buffer full or closed): {}",
-                                e
-                            );
+                        // Keep it for late joiners; the assigned ID lets clients dedupe
+                        let entry =
+                            room.chat_history
+                                .push(&sender_id, &username, &chat_text, now_ms());
+                        let mut msg = WsMessage {
+                            body: ServerMessage::ChatMessage {
+                                id: entry.id,
+                                username: entry.username,
+                                text: entry.text,
+                            },
+                            id: None,
+                            room: Some(room.room_id.clone()),
+                            client: Some(sender_id.clone()),
+                            ts: entry.ts,
+                            server_ts: Some(entry.ts),
+                        };
+                        match serde_json::to_string(&msg) {
+                            Ok(json) => send_to_members(room, clients, json, Some(&sender_id)),
+                            Err(e) => log::error!("Failed to serialize chat_message: {}", e),
                         }
-                    }
+                        // The sender's copy answers its request
+                        msg.id = request_id;
+                        send_to_client(&sender_id, clients, &msg);
+                        ChatOutcome::Sent
+                    })
+                    .await;
+                match outcome {
+                    Some(ChatOutcome::Sent) => {}
+                    Some(ChatOutcome::Muted) => send_error(
+                        client_id,
+                        clients,
+                        Some(&request),
+                        ServerError::new(ErrorCode::Muted, "You are muted in this room"),
+                    ),
+                    Some(ChatOutcome::NotMember) => send_error(
+                        client_id,
+                        clients,
+                        Some(&request),
+                        ServerError::new(ErrorCode::NotInRoom, "You are not in this room"),
+                    ),
+                    None => send_error(client_id, clients, Some(&request), room_not_found()),
                 }
             } else {
-                send_error(client_id, clients, "Room ID required for chat").await;
+                send_error(
+                    client_id,
+                    clients,
+                    Some(&request),
+                    ServerError::new(ErrorCode::RoomRequired, "Room ID required for chat"),
+                );
             }
         }
         ClientMessageType::Unknown => {
             warn!("Unknown message type from client {}", client_id);
-            send_error(client_id, clients, "Unknown message type").await;
+            send_error(
+                client_id,
+                clients,
+                Some(&request),
+                ServerError::new(ErrorCode::UnknownMessageType, "Unknown message type"),
+            );
         }
     }
+    None
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
+    use crate::types::Client;
+
+    pub(crate) type Receiver = mpsc::Receiver<Result<warp::ws::Message, warp::Error>>;
+
+    /// Auth is disabled unless a signing secret is given.
+    pub(crate) fn test_jwt_config(secret: Option<&str>) -> Arc<JwtConfig> {
+        Arc::new(JwtConfig {
+            secret: secret.unwrap_or_default().to_string(),
+            audience: "test".to_string(),
+            issuer: "test".to_string(),
+            enabled: secret.is_some(),
+            public_keys: None,
+            tokens: Default::default(),
+        })
+    }
+
+    /// A freshly connected, authenticated client and the receiving end of
+    /// its socket. Tests override fields with struct update syntax.
+    pub(crate) fn test_client(user_id: &str) -> (Client, Receiver) {
+        let (sender, rx) = mpsc::channel(CLIENT_CHANNEL_BUFFER);
+        let client = Client {
+            sender,
+            room_id: None,
+            user_id: user_id.to_string(),
+            user_name: user_id.to_string(),
+            authenticated: true,
+            message_count: 0,
+            last_reset: now_ms(),
+            last_seen: now_ms(),
+            resume_token: String::new(),
+            disconnected_at: None,
+            clock: Default::default(),
+            owner_node: None,
+            origin_node: None,
+            protocol_version: LEGACY_PROTOCOL_VERSION,
+            token_exp: None,
+            token_warned: false,
+            token_jti: None,
+            permissions: Default::default(),
+            user_slot: None,
+        };
+        (client, rx)
+    }
+
+    /// Everything `client_msg` needs, on a single node.
+    pub(crate) struct TestServer {
+        pub clients: Clients,
+        pub rooms: Rooms,
+        pub jwt_config: Arc<JwtConfig>,
+        pub config: Arc<Config>,
+        pub cluster: Arc<Cluster>,
+        pub limiter: Arc<ConnectionLimiter>,
+    }
+
+    impl TestServer {
+        pub(crate) fn new(config: Config, jwt_secret: Option<&str>) -> Self {
+            Self {
+                clients: Default::default(),
+                rooms: Default::default(),
+                jwt_config: test_jwt_config(jwt_secret),
+                config: Arc::new(config),
+                cluster: Arc::new(Cluster::new(
+                    "test".to_string(),
+                    Arc::new(crate::backend::MemoryBackend::default()),
+                )),
+                limiter: Default::default(),
+            }
+        }
+
+        /// Adds `test_client(client_id)` after `customize` adjusted it.
+        pub(crate) fn connect(
+            &self,
+            client_id: &str,
+            customize: impl FnOnce(&mut Client),
+        ) -> Receiver {
+            let (mut client, rx) = test_client(client_id);
+            customize(&mut client);
+            self.clients.insert(client_id.to_string(), client);
+            rx
+        }
+
+        pub(crate) async fn send(&self, client_id: &str, msg: serde_json::Value) {
+            client_msg(
+                client_id,
+                warp::ws::Message::text(msg.to_string()),
+                &self.clients,
+                &self.rooms,
+                &self.jwt_config,
+                &self.config,
+                &self.cluster,
+                &self.limiter,
+            )
+            .await;
+        }
+    }
 
     // Position validation tests
     #[test]
@@ -916,4 +2291,518 @@ mod tests {
         assert!(result.is_some());
         assert_eq!(result.unwrap().len(), MAX_NAME_LENGTH);
     }
+
+    #[tokio::test]
+    async fn test_replies_echo_request_id() {
+        let server = TestServer::new(Config::default(), None);
+        let mut rx = server.connect("alice", |_| {});
+        let send = |msg| server.send("alice", msg);
+        // Null once nothing is queued
+        let mut next = move || match rx.try_recv() {
+            Ok(Ok(msg)) if msg.is_close() => serde_json::json!("close"),
+            Ok(Ok(msg)) => serde_json::from_str(msg.to_str().unwrap()).unwrap(),
+            _ => serde_json::Value::Null,
+        };
+
+        // Clients needing a newer protocol are turned away
+        send(serde_json::json!({
+            "type": "auth", "id": "0", "ts": 0,
+            "payload": { "protocol_version": 9, "min_protocol_version": 9 }
+        }))
+        .await;
+        let error = next();
+        assert_eq!(error["id"], "0");
+        assert_eq!(error["payload"]["code"], "unsupported_protocol");
+        assert_eq!(next(), "close");
+
+        // Legacy clients don't get acks
+        send(serde_json::json!({ "type": "leave_room", "ts": 0 })).await;
+        assert_eq!(next()["type"], "room_list");
+        assert_eq!(next(), serde_json::Value::Null);
+
+        send(serde_json::json!({
+            "type": "auth", "id": "0", "ts": 0,
+            "payload": { "protocol_version": protocol::PROTOCOL_VERSION, "user_name": "Alice" }
+        }))
+        .await;
+        assert_eq!(next()["type"], "ack");
+
+        send(serde_json::json!({ "type": "ping", "id": "1", "ts": 0 })).await;
+        let pong = next();
+        assert_eq!(
+            (pong["type"].as_str(), pong["id"].as_str()),
+            (Some("pong"), Some("1"))
+        );
+
+        send(serde_json::json!({ "type": "join_room", "id": "2", "room": "missing", "ts": 0 }))
+            .await;
+        let error = next();
+        assert_eq!(error["id"], "2");
+        assert_eq!(error["payload"]["code"], "room_not_found");
+        assert_eq!(error["payload"]["request"], "join_room");
+
+        send(serde_json::json!({ "type": "leave_room", "id": "3", "ts": 0 })).await;
+        let _room_list = next();
+        let ack = next();
+        assert_eq!(
+            (ack["type"].as_str(), ack["id"].as_str()),
+            (Some("ack"), Some("3"))
+        );
+        assert_eq!(ack["payload"]["request"], "leave_room");
+    }
+
+    #[tokio::test]
+    async fn test_chat_message_replies() {
+        let server = TestServer::new(Config::default(), None);
+        let mut host_rx = server.connect("host", |_| {});
+        let mut bob_rx = server.connect("bob", |_| {});
+        let next = |rx: &mut Receiver| match rx.try_recv() {
+            Ok(Ok(msg)) => {
+                serde_json::from_str::<serde_json::Value>(msg.to_str().unwrap()).unwrap()
+            }
+            _ => serde_json::Value::Null,
+        };
+        let chat = |client_id, room: &str| {
+            server.send(
+                client_id,
+                serde_json::json!({
+                    "type": "chat_message", "id": "1", "room": room, "ts": 0,
+                    "payload": { "text": "hi" }
+                }),
+            )
+        };
+
+        server
+            .send(
+                "host",
+                serde_json::json!({ "type": "create_room", "ts": 0 }),
+            )
+            .await;
+        let room_id = server.clients.get("host").unwrap().room_id.clone().unwrap();
+        while host_rx.try_recv().is_ok() {}
+        while bob_rx.try_recv().is_ok() {}
+
+        chat("bob", "missing").await;
+        assert_eq!(next(&mut bob_rx)["payload"]["code"], "room_not_found");
+
+        chat("bob", &room_id).await;
+        let error = next(&mut bob_rx);
+        assert_eq!(error["id"], "1");
+        assert_eq!(error["payload"]["code"], "not_in_room");
+        assert_eq!(next(&mut host_rx), serde_json::Value::Null);
+
+        // Only the sender's copy answers the request
+        server
+            .send(
+                "bob",
+                serde_json::json!({ "type": "join_room", "room": room_id, "ts": 0 }),
+            )
+            .await;
+        while bob_rx.try_recv().is_ok() {}
+        while host_rx.try_recv().is_ok() {}
+        chat("host", &room_id).await;
+        let own = next(&mut host_rx);
+        assert_eq!(
+            (own["type"].as_str(), own["id"].as_str()),
+            (Some("chat_message"), Some("1"))
+        );
+        let relayed = next(&mut bob_rx);
+        assert_eq!(relayed["payload"]["text"], "hi");
+        assert_eq!(relayed["id"], serde_json::Value::Null);
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_play_position() {
+        let server = TestServer::new(Config::default(), None);
+        let (rooms, config) = (&server.rooms, &server.config);
+        let mut rx = server.connect("host", |_| {});
+        let send = |msg| server.send("host", msg);
+
+        send(serde_json::json!({ "type": "create_room", "ts": 0 })).await;
+        let room_id = server.clients.get("host").unwrap().room_id.clone().unwrap();
+        while rx.try_recv().is_ok() {}
+
+        let sent_at = now_ms();
+        send(serde_json::json!({
+            "type": "player_event", "room": room_id, "ts": 0,
+            "payload": { "action": "play", "position": 10.0 }
+        }))
+        .await;
+        let (position_ts, now_state, later_state) = rooms
+            .call(&room_id, |room, _| {
+                (
+                    room.position_ts,
+                    expected_state(room, now_ms()),
+                    expected_state(room, room.position_ts + 1000),
+                )
+            })
+            .await
+            .unwrap();
+        // Playback starts at the scheduled time, so the room doesn't run ahead
+        assert!(position_ts >= sent_at + config.sync.schedule_floor_ms);
+        assert_eq!(now_state.position, 10.0);
+        assert!((later_state.position - 11.0).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn test_reauth_and_token_expiry() {
+        use jsonwebtoken::{encode, EncodingKey, Header};
+
+        let secret = "test-secret-with-at-least-32-characters-here";
+        let server = TestServer::new(Config::default(), Some(secret));
+        let (clients, rooms, cluster) = (&server.clients, &server.rooms, &server.cluster);
+        let session = server.config.session.clone();
+        // Not authenticated until the first `auth`
+        let mut rx = server.connect("alice", |client| {
+            client.user_id = String::new();
+            client.user_name = String::new();
+            client.authenticated = false;
+        });
+        let token = |sub: &str, exp_secs: u64| {
+            let claims = crate::auth::Claims {
+                sub: sub.to_string(),
+                name: "Alice".to_string(),
+                aud: "test".to_string(),
+                iss: "test".to_string(),
+                exp: exp_secs as usize,
+                iat: 0,
+                jti: Some(format!("{}-{}", sub, exp_secs)),
+                roles: Vec::new(),
+                permissions: None,
+            };
+            let key = EncodingKey::from_secret(secret.as_bytes());
+            encode(&Header::default(), &claims, &key).unwrap()
+        };
+        let send = |msg| server.send("alice", msg);
+        // Everything queued so far, by type
+        let mut drain = move || {
+            let mut messages = Vec::new();
+            while let Ok(Ok(msg)) = rx.try_recv() {
+                let msg: serde_json::Value = serde_json::from_str(msg.to_str().unwrap()).unwrap();
+                messages.push(msg);
+            }
+            messages
+        };
+        let find = |messages: &[serde_json::Value], msg_type: &str| {
+            messages
+                .iter()
+                .find(|m| m["type"] == msg_type)
+                .cloned()
+                .unwrap_or_else(|| panic!("no {} in {:?}", msg_type, messages))
+        };
+
+        let exp = now_ms() / 1000 + 600;
+        send(serde_json::json!({ "type": "auth", "ts": 0, "payload": { "token": token("user-1", exp) } }))
+            .await;
+        let success = find(&drain(), "auth_success");
+        assert_eq!(success["payload"]["expires_at"], exp * 1000);
+        assert_eq!(success["payload"]["permissions"]["can_chat"], true);
+        assert_eq!(success["payload"]["permissions"]["admin"], false);
+        send(serde_json::json!({ "type": "create_room", "ts": 0 })).await;
+        drain();
+
+        // A token for someone else cannot take over the session
+        send(serde_json::json!({ "type": "reauth", "ts": 0, "payload": { "token": token("user-2", exp) } }))
+            .await;
+        assert_eq!(find(&drain(), "error")["payload"]["code"], "auth_failed");
+        // ...and its jti is left for its owner
+        let mut bob_rx = server.connect("bob", |client| {
+            client.user_id = String::new();
+            client.authenticated = false;
+        });
+        server
+            .send(
+                "bob",
+                serde_json::json!({ "type": "auth", "ts": 0, "payload": { "token": token("user-2", exp) } }),
+            )
+            .await;
+        let reply = bob_rx.try_recv().unwrap().unwrap();
+        assert!(
+            reply.to_str().unwrap().contains("auth_success"),
+            "{:?}",
+            reply
+        );
+
+        let exp = exp + 600;
+        send(serde_json::json!({ "type": "reauth", "id": "r", "ts": 0, "payload": { "token": token("user-1", exp) } }))
+            .await;
+        let success = find(&drain(), "auth_success");
+        assert_eq!(success["id"], "r");
+        assert_eq!(success["payload"]["expires_at"], exp * 1000);
+        assert!(clients.get("alice").unwrap().room_id.is_some());
+
+        // Warned once inside the warning window
+        let exp_ms = exp * 1000;
+        let warn_at = exp_ms - session.token_warning_ms;
+        expire_tokens(clients, rooms, cluster, &session, warn_at - 1).await;
+        assert!(drain().is_empty());
+        expire_tokens(clients, rooms, cluster, &session, warn_at).await;
+        let warning = find(&drain(), "token_expiring");
+        assert_eq!(warning["payload"]["expires_at"], exp_ms);
+        expire_tokens(clients, rooms, cluster, &session, exp_ms).await;
+        assert!(drain().is_empty());
+
+        // Demoted and out of the room once the grace period is over
+        expire_tokens(
+            clients,
+            rooms,
+            cluster,
+            &session,
+            exp_ms + session.token_grace_ms,
+        )
+        .await;
+        let messages = drain();
+        assert_eq!(find(&messages, "error")["payload"]["code"], "token_expired");
+        let client = clients.get("alice").unwrap();
+        assert!(!client.authenticated);
+        assert_eq!(client.room_id, None);
+    }
+
+    #[tokio::test]
+    async fn test_connections_per_user() {
+        use jsonwebtoken::{encode, EncodingKey, Header};
+
+        let secret = "test-secret-with-at-least-32-characters-here";
+        let mut config = Config::default();
+        config.limits.max_connections_per_user = 2;
+        let server = TestServer::new(config, Some(secret));
+        let clients = &server.clients;
+        let mut receivers: Vec<_> = ["a", "b", "c"]
+            .into_iter()
+            .map(|id| {
+                server.connect(id, |client| {
+                    client.user_id = String::new();
+                    client.authenticated = false;
+                })
+            })
+            .collect();
+        let auth = |client_id: &'static str, jti: &'static str| {
+            let claims = crate::auth::Claims {
+                sub: "user-1".to_string(),
+                name: "Alice".to_string(),
+                aud: "test".to_string(),
+                iss: "test".to_string(),
+                exp: (now_ms() / 1000 + 600) as usize,
+                iat: 0,
+                jti: Some(jti.to_string()),
+                roles: Vec::new(),
+                permissions: None,
+            };
+            let token = encode(
+                &Header::default(),
+                &claims,
+                &EncodingKey::from_secret(secret.as_bytes()),
+            )
+            .unwrap();
+            server.send(
+                client_id,
+                serde_json::json!({ "type": "auth", "ts": 0, "payload": { "token": token } }),
+            )
+        };
+        let error_code = |rx: &mut Receiver| {
+            let mut code = None;
+            while let Ok(Ok(msg)) = rx.try_recv() {
+                let Ok(text) = msg.to_str() else {
+                    continue; // The close frame
+                };
+                let msg: serde_json::Value = serde_json::from_str(text).unwrap();
+                if msg["type"] == "error" {
+                    code = msg["payload"]["code"].as_str().map(str::to_string);
+                }
+            }
+            code
+        };
+
+        auth("a", "t-a").await;
+        auth("b", "t-b").await;
+        assert!(clients.get("b").unwrap().authenticated);
+
+        // A third socket for the same user is refused
+        auth("c", "t-c").await;
+        assert_eq!(
+            error_code(&mut receivers[2]).as_deref(),
+            Some("too_many_connections")
+        );
+        assert!(!clients.get("c").unwrap().authenticated);
+
+        // Re-authenticating as the same user keeps the socket's own slot
+        auth("a", "t-a2").await;
+        assert_eq!(error_code(&mut receivers[0]), None);
+
+        // Sessions waiting for a resume give their slot up, and the refused
+        // token wasn't used up by the first attempt
+        let sender = clients.get("b").unwrap().sender.clone();
+        crate::room::handle_connection_lost(
+            "b",
+            &sender,
+            clients,
+            &server.rooms,
+            server.cluster.remote_rooms(),
+            60_000,
+        )
+        .await;
+        auth("c", "t-c").await;
+        assert_eq!(error_code(&mut receivers[2]), None);
+        assert!(clients.get("c").unwrap().authenticated);
+    }
+}
+
+#[cfg(test)]
+mod bench {
+    use super::tests::TestServer;
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    const ROOMS: usize = 500;
+    const GUESTS_PER_ROOM: usize = 3;
+    const MESSAGES_PER_CLIENT: usize = 100;
+
+    fn server() -> Arc<TestServer> {
+        let mut config = Config::default();
+        config.limits.rate_limit_messages = u32::MAX;
+        config.limits.max_clients_per_room = usize::MAX;
+        Arc::new(TestServer::new(config, None))
+    }
+
+    /// Adds a client whose outgoing messages are drained in the background.
+    fn connect(server: &TestServer, client_id: &str) {
+        let mut rx = server.connect(client_id, |_| {});
+        tokio::spawn(async move { while rx.recv().await.is_some() {} });
+    }
+
+    fn room_of(server: &TestServer, client_id: &str) -> String {
+        server
+            .clients
+            .get(client_id)
+            .unwrap()
+            .room_id
+            .clone()
+            .unwrap()
+    }
+
+    fn percentile(sorted: &[Duration], p: f64) -> Duration {
+        sorted[((sorted.len() - 1) as f64 * p) as usize]
+    }
+
+    /// `cargo test --release bench_500_rooms -- --ignored --nocapture`
+    ///
+    /// Ignored by default: it is slow, asserts nothing, and prints its
+    /// results, which the test harness only shows with `--nocapture`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn bench_500_rooms() {
+        let server = server();
+        let mut rooms = Vec::new();
+        for r in 0..ROOMS {
+            let host = format!("host-{}", r);
+            connect(&server, &host);
+            server
+                .send(
+                    &host,
+                    serde_json::json!({ "type": "create_room", "payload": { "visibility": "unlisted" }, "ts": 0 }),
+                )
+                .await;
+            let room_id = room_of(&server, &host);
+            for g in 0..GUESTS_PER_ROOM {
+                let guest = format!("guest-{}-{}", r, g);
+                connect(&server, &guest);
+                server
+                    .send(
+                        &guest,
+                        serde_json::json!({ "type": "join_room", "room": room_id, "ts": 0 }),
+                    )
+                    .await;
+            }
+            rooms.push((host, room_id));
+        }
+        // A quiet room that keeps getting joined while the others are busy
+        connect(&server, "quiet-host");
+        server
+            .send(
+                "quiet-host",
+                serde_json::json!({ "type": "create_room", "payload": { "visibility": "unlisted" }, "ts": 0 }),
+            )
+            .await;
+        let quiet_room = room_of(&server, "quiet-host");
+
+        let start = Instant::now();
+        let mut tasks = Vec::new();
+        for (r, (host, room_id)) in rooms.iter().enumerate() {
+            let senders = std::iter::once(host.clone())
+                .chain((0..GUESTS_PER_ROOM).map(|g| format!("guest-{}-{}", r, g)));
+            for client_id in senders {
+                let server = server.clone();
+                let room_id = room_id.clone();
+                let is_host = client_id.starts_with("host");
+                tasks.push(tokio::spawn(async move {
+                    for i in 0..MESSAGES_PER_CLIENT {
+                        let msg = if is_host {
+                            serde_json::json!({
+                                "type": if i % 2 == 0 { "player_event" } else { "state_update" },
+                                "room": room_id,
+                                "payload": { "action": "seek", "position": i as f64 * 10.0, "play_state": "playing" },
+                                "ts": 0
+                            })
+                        } else {
+                            serde_json::json!({
+                                "type": "chat_message",
+                                "room": room_id,
+                                "payload": { "text": "hello" },
+                                "ts": 0
+                            })
+                        };
+                        server.send(&client_id, msg).await;
+                    }
+                }));
+            }
+        }
+        let joiner = {
+            let server = server.clone();
+            tokio::spawn(async move {
+                let mut latencies = Vec::new();
+                for i in 0.. {
+                    let id = format!("joiner-{}", i);
+                    connect(&server, &id);
+                    let started = Instant::now();
+                    server
+                        .send(
+                            &id,
+                            serde_json::json!({ "type": "join_room", "room": quiet_room, "ts": 0 }),
+                        )
+                        .await;
+                    latencies.push(started.elapsed());
+                    server
+                        .send(&id, serde_json::json!({ "type": "leave_room", "ts": 0 }))
+                        .await;
+                    if latencies.len() >= 200 {
+                        break;
+                    }
+                    tokio::time::sleep(Duration::from_millis(2)).await;
+                }
+                latencies
+            })
+        };
+        for task in tasks {
+            task.await.unwrap();
+        }
+        let elapsed = start.elapsed();
+        let mut latencies = joiner.await.unwrap();
+        latencies.sort();
+
+        let total = ROOMS * (GUESTS_PER_ROOM + 1) * MESSAGES_PER_CLIENT;
+        println!(
+            "{} rooms, {} clients: {} messages in {:.2?} ({:.0} msg/s)",
+            ROOMS,
+            ROOMS * (GUESTS_PER_ROOM + 1),
+            total,
+            elapsed,
+            total as f64 / elapsed.as_secs_f64()
+        );
+        println!(
+            "join latency in a quiet room: p50 {:.2?}, p99 {:.2?}, max {:.2?}",
+            percentile(&latencies, 0.5),
+            percentile(&latencies, 0.99),
+            latencies[latencies.len() - 1]
+        );
+    }
 }